@@ -0,0 +1,32 @@
+-- An example generator script. The game must be built with `cargo build --features lua`, then
+-- use it from a preset in presets.toml:
+--
+--   [[preset]]
+--   name = "terraces"
+--   terrain = "lua"
+--   caves = true
+--   ores = true
+--   decorations = true
+--
+--   [preset.lua]
+--   path = "scripts/terraces.lua"
+--   fallback = "noise"
+
+local STEP = 4
+
+function generate(chunk_x, chunk_z, seed)
+    for x = 0, CHUNK_SIDE_LENGTH - 1 do
+        for z = 0, CHUNK_SIDE_LENGTH - 1 do
+            local world_x = chunk_x * CHUNK_SIDE_LENGTH + x
+            local world_z = chunk_z * CHUNK_SIDE_LENGTH + z
+            local height = 48 + noise2(1, world_x / 200, world_z / 200, 4) * 24
+            height = math.floor(height / STEP) * STEP
+
+            set_block(x, 0, z, blocks.Bedrock)
+            for y = 1, height - 1 do
+                set_block(x, y, z, blocks.Stone)
+            end
+            set_block(x, height, z, blocks.Grass)
+        end
+    end
+end
@@ -0,0 +1,99 @@
+use block::BlockType;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Biome {
+    Plains,
+    Desert,
+    Forest,
+    Mountains,
+    Tundra,
+}
+
+static BIOME_NAMES: [&str; 5] = [
+    "Plains",
+    "Desert",
+    "Forest",
+    "Mountains",
+    "Tundra",
+];
+
+impl Biome {
+    /// Picks a biome from climate values, both roughly in the range `-1.0..1.0`.
+    pub fn from_climate(temperature: f64, humidity: f64) -> Biome {
+        if temperature < -0.35 {
+            Biome::Tundra
+        } else if temperature > 0.35 {
+            if humidity < 0.0 { Biome::Desert } else { Biome::Forest }
+        } else if humidity > 0.25 {
+            Biome::Forest
+        } else if humidity < -0.3 {
+            Biome::Mountains
+        } else {
+            Biome::Plains
+        }
+    }
+
+    /// The biome with this ID, if there is one.
+    pub fn from_id(id: u8) -> Option<Biome> {
+        if (id as usize) < BIOME_NAMES.len() {
+            Some(Biome::from(id))
+        } else {
+            None
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        BIOME_NAMES[self as usize]
+    }
+
+    /// The block placed at the top of each column.
+    pub fn surface_block(self) -> BlockType {
+        match self {
+            Biome::Plains | Biome::Forest => BlockType::Grass,
+            Biome::Desert => BlockType::Sand,
+            Biome::Mountains => BlockType::Gravel,
+            Biome::Tundra => BlockType::Dirt,
+        }
+    }
+
+    /// The block placed in the few layers between the surface and the stone.
+    pub fn filler_block(self) -> BlockType {
+        match self {
+            Biome::Plains | Biome::Forest => BlockType::Dirt,
+            Biome::Desert => BlockType::Sandstone,
+            Biome::Mountains => BlockType::Stone,
+            Biome::Tundra => BlockType::Gravel,
+        }
+    }
+
+    /// The base height of the terrain and how far the noise may move it up or down.
+    pub fn terrain_shape(self) -> (f64, f64) {
+        match self {
+            Biome::Plains => (40.0, 8.0),
+            Biome::Desert => (38.0, 6.0),
+            Biome::Forest => (42.0, 12.0),
+            Biome::Mountains => (56.0, 40.0),
+            Biome::Tundra => (40.0, 10.0),
+        }
+    }
+}
+
+impl Default for Biome {
+    fn default() -> Biome {
+        Biome::Plains
+    }
+}
+
+impl From<u8> for Biome {
+    fn from(b: u8) -> Biome {
+        use self::Biome::*;
+        match b {
+            0 => Plains,
+            1 => Desert,
+            2 => Forest,
+            3 => Mountains,
+            4 => Tundra,
+            _ => unreachable!(),
+        }
+    }
+}
@@ -1,9 +1,16 @@
-#[derive(Clone, Copy, PartialEq, Eq, Default)]
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
 pub struct Block {
     pub ty: BlockType,
+    /// Extra data whose meaning depends on the type, like which way a log lies, which way a
+    /// stair faces, how far a crop has grown or how full a fluid is. Always 0 for types that
+    /// don't use it, so blocks that look the same compare equal.
+    pub state: u8,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+/// The number of block types, every ID below this is valid.
+pub const BLOCK_TYPE_COUNT: usize = 17;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum BlockType {
     Air,
     Dirt,
@@ -21,12 +28,21 @@ pub enum BlockType {
     Leaf,
     Sponge,
     Sandstone,
+    Water,
 }
 
 impl Block {
     pub fn new(ty: BlockType) -> Block {
         Block {
-            ty
+            ty,
+            state: 0,
+        }
+    }
+
+    pub fn with_state(ty: BlockType, state: u8) -> Block {
+        Block {
+            ty,
+            state,
         }
     }
 }
@@ -45,15 +61,59 @@ impl From<u8> for BlockType {
             1 => Dirt,
             2 => Grass,
             3 => Stone,
+            4 => Cobblestone,
+            5 => Wood,
+            6 => Log,
+            7 => Bedrock,
+            8 => Sand,
+            9 => Gravel,
+            10 => GoldOre,
+            11 => IronOre,
+            12 => CoalOre,
+            13 => Leaf,
+            14 => Sponge,
+            15 => Sandstone,
+            16 => Water,
             _ => unreachable!(),
         }
     }
 }
 
+impl BlockType {
+    /// The type with this ID, if there is one.
+    pub fn from_id(id: u8) -> Option<BlockType> {
+        if (id as usize) < BLOCK_TYPE_COUNT {
+            Some(BlockType::from(id))
+        } else {
+            None
+        }
+    }
+
+    /// Whether the block completely hides whatever is behind it.
+    pub fn is_opaque(self) -> bool {
+        match self {
+            BlockType::Air | BlockType::Water => false,
+            _ => true,
+        }
+    }
+
+    pub fn is_fluid(self) -> bool {
+        self == BlockType::Water
+    }
+}
+
 impl Block {
     pub fn is_air(self) -> bool {
         self.ty == BlockType::Air
     }
+
+    pub fn is_opaque(self) -> bool {
+        self.ty.is_opaque()
+    }
+
+    pub fn is_fluid(self) -> bool {
+        self.ty.is_fluid()
+    }
     /*pub fn has_solid_top_surface(&self) -> bool {
 
     }*/
@@ -62,7 +122,8 @@ impl Block {
 impl From<u8> for Block {
     fn from(b: u8) -> Block {
         Block {
-            ty: BlockType::from(b)
+            ty: BlockType::from(b),
+            state: 0,
         }
     }
 }
@@ -0,0 +1,219 @@
+use block::{ Block, BlockType };
+use chunk::{ Chunk, CHUNK_SIDE_LENGTH, WORLD_HEIGHT };
+use math::*;
+use player_edits::PlayerEdits;
+
+/// Evens out the seam between a chunk from the current generator at `new_coord` and an
+/// outdated one next to it at `old_coord`. The ground along each line of columns crossing the
+/// seam is reshaped into a slope between the columns `width` blocks either side of it, and
+/// whatever stands on the ground moves up or down with it. Columns holding a block the player
+/// has edited are left as they are. `ends` comes from `seam_ends`, and the lines within `width`
+/// of an end that meets another seam are left for that seam. Returns whether each chunk changed.
+pub fn blend_seam(
+    new: &mut Chunk,
+    new_coord: ChunkCoord,
+    old: &mut Chunk,
+    old_coord: ChunkCoord,
+    width: i32,
+    ends: (bool, bool),
+    player_edits: &PlayerEdits,
+) -> (bool, bool) {
+    let (dx, dz) = (old_coord.x - new_coord.x, old_coord.z - new_coord.z);
+    assert!(dx.abs() + dz.abs() == 1, "chunks {} and {} aren't next to each other", new_coord, old_coord);
+    let width = width.max(1).min(CHUNK_SIDE_LENGTH as i32 - 1);
+    let last = CHUNK_SIDE_LENGTH as i32 - 1;
+
+    // The column `distance` blocks back from the seam, along the line `i`.
+    let column = |i: i32, distance: i32, towards_seam: i32| -> (i32, i32) {
+        let along = if towards_seam > 0 { last - distance } else { distance };
+        if dx != 0 { (along, i) } else { (i, along) }
+    };
+    let new_direction = dx + dz;
+    let old_direction = -new_direction;
+
+    let mut changed = (false, false);
+    for i in 0..CHUNK_SIDE_LENGTH as i32 {
+        // Otherwise each seam would reshape the other's anchors, and blending again would keep
+        // moving the corner.
+        if (ends.0 && i <= width) || (ends.1 && i >= last - width) {
+            continue;
+        }
+        let (nx, nz) = column(i, width, new_direction);
+        let (ox, oz) = column(i, width, old_direction);
+        let (new_anchor, old_anchor) = match (ground_height(new, nx, nz), ground_height(old, ox, oz)) {
+            (Some(new_anchor), Some(old_anchor)) => (new_anchor as f64, old_anchor as f64),
+            _ => continue,
+        };
+
+        let span = (2 * width + 1) as f64;
+        for distance in 0..width {
+            // Positions along the line, from the new anchor at 0 to the old anchor at `span`.
+            let new_t = (width - distance) as f64 / span;
+            let old_t = (width + 1 + distance) as f64 / span;
+
+            let (x, z) = column(i, distance, new_direction);
+            let target = (new_anchor + (old_anchor - new_anchor) * new_t).round() as i32;
+            if !has_player_edit(player_edits, new_coord, x, z) && reshape_column(new, x, z, target) {
+                changed.0 = true;
+            }
+
+            let (x, z) = column(i, distance, old_direction);
+            let target = (new_anchor + (old_anchor - new_anchor) * old_t).round() as i32;
+            if !has_player_edit(player_edits, old_coord, x, z) && reshape_column(old, x, z, target) {
+                changed.1 = true;
+            }
+        }
+    }
+    changed
+}
+
+/// Whether the first and last lines of columns across the seam between `new_coord` and
+/// `old_coord` meet another seam, going by `is_seam`, which tells whether the seam between two
+/// chunks is blended.
+pub fn seam_ends<F: Fn(ChunkCoord, ChunkCoord) -> bool>(new_coord: ChunkCoord, old_coord: ChunkCoord, is_seam: F) -> (bool, bool) {
+    // Along the seam, towards the last line.
+    let (ax, az) = if old_coord.x != new_coord.x { (0, 1) } else { (1, 0) };
+    let meets = |sign: i32| {
+        let beside = |coord: ChunkCoord| ChunkCoord::new(coord.x + sign * ax, coord.z + sign * az);
+        is_seam(new_coord, beside(new_coord)) || is_seam(old_coord, beside(old_coord))
+    };
+    (meets(-1), meets(1))
+}
+
+fn has_player_edit(player_edits: &PlayerEdits, coord: ChunkCoord, x: i32, z: i32) -> bool {
+    player_edits.get(coord).iter().any(|edit| edit.pos.x == x && edit.pos.z == z)
+}
+
+/// Whether a block is part of the ground rather than something built or grown on it.
+fn is_ground(ty: BlockType) -> bool {
+    use block::BlockType::*;
+    match ty {
+        Dirt | Grass | Stone | Bedrock | Sand | Gravel | GoldOre | IronOre | CoalOre | Sandstone => true,
+        Air | Cobblestone | Wood | Log | Leaf | Sponge | Water => false,
+    }
+}
+
+/// The y coordinate of the highest ground block in the column.
+fn ground_height(chunk: &Chunk, x: i32, z: i32) -> Option<i32> {
+    (0..WORLD_HEIGHT as i32).rev().find(|&y| is_ground(chunk.get(Coord::new(x, y, z)).ty))
+}
+
+/// Moves the ground of a column to `target`, shifting everything above it by the same amount.
+/// Water stays where it was, so seas and lakes keep their level.
+fn reshape_column(chunk: &mut Chunk, x: i32, z: i32, target: i32) -> bool {
+    let height = match ground_height(chunk, x, z) {
+        Some(height) => height,
+        None => return false,
+    };
+    let target = target.max(1).min(WORLD_HEIGHT as i32 - 1);
+    if target == height {
+        return false;
+    }
+
+    let old: Vec<Block> = (0..WORLD_HEIGHT as i32).map(|y| chunk.get(Coord::new(x, y, z))).collect();
+    let surface = old[height as usize];
+    let filler = if height > 0 && is_ground(old[height as usize - 1].ty) { old[height as usize - 1] } else { surface };
+    let shift = target - height;
+
+    for y in height.min(target)..WORLD_HEIGHT as i32 {
+        let block = if y == target {
+            surface
+        } else if y < target {
+            filler
+        } else {
+            let from = y - shift;
+            let shifted = if from < WORLD_HEIGHT as i32 { old[from as usize] } else { Block::new(BlockType::Air) };
+            let original = old[y as usize];
+            if original.ty.is_fluid() && (shifted.ty == BlockType::Air || shifted.ty.is_fluid()) {
+                original
+            } else if shifted.ty.is_fluid() {
+                Block::new(BlockType::Air)
+            } else {
+                shifted
+            }
+        };
+        chunk.set(Coord::new(x, y, z), block);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use decoration::BlockEdit;
+
+    fn ground(height: i32) -> Chunk {
+        let mut chunk = Chunk::new();
+        for x in 0..CHUNK_SIDE_LENGTH as i32 {
+            for z in 0..CHUNK_SIDE_LENGTH as i32 {
+                for y in 0..height {
+                    chunk.set(Coord::new(x, y, z), Block::new(BlockType::Dirt));
+                }
+                chunk.set(Coord::new(x, height, z), Block::new(BlockType::Grass));
+            }
+        }
+        chunk
+    }
+
+    #[test]
+    fn seams_become_slopes() {
+        let (mut new, mut old) = (ground(40), ground(60));
+        new.set(Coord::new(14, 41, 3), Block::new(BlockType::Log));
+        let changed = blend_seam(&mut new, ChunkCoord::new(0, 0), &mut old, ChunkCoord::new(1, 0), 4, (false, false), &PlayerEdits::new());
+        assert_eq!(changed, (true, true));
+
+        let heights: Vec<_> = (10..16).map(|x| ground_height(&new, x, 3).unwrap())
+            .chain((0..6).map(|x| ground_height(&old, x, 3).unwrap()))
+            .collect();
+        assert_eq!(heights[..2], [40, 40]);
+        assert_eq!(heights[10..], [60, 60]);
+        assert!(heights.windows(2).all(|pair| pair[0] <= pair[1] && pair[1] - pair[0] <= 3));
+
+        // The log moved up with the ground under it.
+        let height = ground_height(&new, 14, 3).unwrap();
+        assert_eq!(new.get(Coord::new(14, height, 3)).ty, BlockType::Grass);
+        assert_eq!(new.get(Coord::new(14, height + 1, 3)).ty, BlockType::Log);
+    }
+
+    #[test]
+    fn player_edited_columns_are_left_alone() {
+        let (mut new, mut old) = (ground(40), ground(60));
+        let mut player_edits = PlayerEdits::new();
+        let edit = BlockEdit { pos: Coord::new(5, 61, 0), block: Block::new(BlockType::Sponge) };
+        old.set(edit.pos, edit.block);
+        player_edits.record(ChunkCoord::new(0, 1), edit);
+
+        blend_seam(&mut new, ChunkCoord::new(0, 0), &mut old, ChunkCoord::new(0, 1), 6, (false, false), &player_edits);
+        assert_eq!(ground_height(&old, 5, 0), Some(60));
+        assert_eq!(old.get(edit.pos), edit.block);
+        assert!(ground_height(&old, 6, 0).unwrap() < 60);
+    }
+
+    #[test]
+    fn blending_twice_changes_nothing() {
+        let (mut new, mut old) = (ground(30), ground(45));
+        let (new_coord, old_coord) = (ChunkCoord::new(-1, 2), ChunkCoord::new(-2, 2));
+        blend_seam(&mut new, new_coord, &mut old, old_coord, 5, (false, false), &PlayerEdits::new());
+        let changed = blend_seam(&mut new, new_coord, &mut old, old_coord, 5, (false, false), &PlayerEdits::new());
+        assert_eq!(changed, (false, false));
+    }
+
+    #[test]
+    fn blending_two_seams_twice_changes_nothing() {
+        let old_coord = ChunkCoord::new(0, 0);
+        let new_coords = [ChunkCoord::new(1, 0), ChunkCoord::new(0, 1)];
+        let is_seam = |a: ChunkCoord, b: ChunkCoord| {
+            (a == old_coord && new_coords.contains(&b)) || (b == old_coord && new_coords.contains(&a))
+        };
+        let (mut old, mut news) = (ground(60), [ground(40), ground(20)]);
+
+        for pass in 0..2 {
+            for (new, &new_coord) in news.iter_mut().zip(new_coords.iter()) {
+                let ends = seam_ends(new_coord, old_coord, &is_seam);
+                assert_eq!(ends, (false, true));
+                let changed = blend_seam(new, new_coord, &mut old, old_coord, 5, ends, &PlayerEdits::new());
+                assert_eq!(changed, if pass == 0 { (true, true) } else { (false, false) });
+            }
+        }
+    }
+}
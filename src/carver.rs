@@ -0,0 +1,96 @@
+use block::{ Block, BlockType };
+use chunk::{ Chunk, CHUNK_SIDE_LENGTH, WORLD_HEIGHT };
+use math::*;
+use world_noise::OctaveNoise;
+
+const TUNNEL_A_SALT: u32 = 101;
+const TUNNEL_B_SALT: u32 = 102;
+const RAVINE_PATH_SALT: u32 = 103;
+const RAVINE_MASK_SALT: u32 = 104;
+const RAVINE_DEPTH_SALT: u32 = 105;
+
+/// How close to zero both tunnel fields must be for a block to be carved. Larger values give
+/// wider tunnels.
+const TUNNEL_RADIUS: f64 = 0.07;
+/// Tunnels are stretched horizontally by sampling the noise with a squashed y axis.
+const TUNNEL_Y_SCALE: f64 = 2.0;
+/// Caves stop this many blocks above the bedrock floor.
+const CAVE_FLOOR: i32 = 4;
+
+const RAVINE_WIDTH: f64 = 0.025;
+const RAVINE_MASK_THRESHOLD: f64 = 0.35;
+const RAVINE_MIN_FLOOR: f64 = 8.0;
+const RAVINE_FLOOR_VARIATION: f64 = 12.0;
+
+/// Cuts caves and ravines out of already generated terrain.
+///
+/// Worm caves follow the lines where two independent 3D noise fields are both close to zero,
+/// and ravines follow the zero line of a 2D field where a mask field allows them. Both only
+/// depend on the seed and the world position of each block, so a tunnel continues seamlessly
+/// into the neighbouring chunk regardless of which chunk was generated first.
+pub struct Carver {
+    tunnel_a: OctaveNoise,
+    tunnel_b: OctaveNoise,
+    ravine_path: OctaveNoise,
+    ravine_mask: OctaveNoise,
+    ravine_depth: OctaveNoise,
+}
+
+impl Carver {
+    pub fn new(seed: u32) -> Carver {
+        Carver {
+            tunnel_a: OctaveNoise::new(seed, TUNNEL_A_SALT, 2, 1.0 / 64.0, 0.5),
+            tunnel_b: OctaveNoise::new(seed, TUNNEL_B_SALT, 2, 1.0 / 64.0, 0.5),
+            ravine_path: OctaveNoise::new(seed, RAVINE_PATH_SALT, 3, 1.0 / 384.0, 0.5),
+            ravine_mask: OctaveNoise::new(seed, RAVINE_MASK_SALT, 1, 1.0 / 512.0, 0.5),
+            ravine_depth: OctaveNoise::new(seed, RAVINE_DEPTH_SALT, 1, 1.0 / 48.0, 0.5),
+        }
+    }
+
+    pub fn carve(&self, coord: ChunkCoord, chunk: &mut Chunk) {
+        for x in 0..CHUNK_SIDE_LENGTH as i32 {
+            for z in 0..CHUNK_SIDE_LENGTH as i32 {
+                let world_x = coord.x * CHUNK_SIDE_LENGTH as i32 + x;
+                let world_z = coord.z * CHUNK_SIDE_LENGTH as i32 + z;
+                let ravine_floor = self.ravine_floor(world_x, world_z);
+
+                for y in 1..WORLD_HEIGHT as i32 {
+                    let local = Coord { x, y, z };
+                    let block = chunk.get(local);
+                    if block.is_air() || block.ty == BlockType::Bedrock {
+                        continue;
+                    }
+
+                    let in_ravine = ravine_floor.map_or(false, |floor| y >= floor);
+                    if in_ravine || (y >= CAVE_FLOOR && self.is_tunnel(world_x, y, world_z)) {
+                        chunk.set(local, Block::new(BlockType::Air));
+                    }
+                }
+            }
+        }
+    }
+
+    fn is_tunnel(&self, x: i32, y: i32, z: i32) -> bool {
+        let (x, y, z) = (x as f64, y as f64 * TUNNEL_Y_SCALE, z as f64);
+        self.tunnel_a.get3(x, y, z).abs() < TUNNEL_RADIUS &&
+            self.tunnel_b.get3(x, y, z).abs() < TUNNEL_RADIUS
+    }
+
+    /// The lowest y coordinate carved out by a ravine in this column, if one passes through it.
+    fn ravine_floor(&self, x: i32, z: i32) -> Option<i32> {
+        let (x, z) = (x as f64, z as f64);
+        if self.ravine_mask.get2(x, z) < RAVINE_MASK_THRESHOLD {
+            return None;
+        }
+
+        let distance = self.ravine_path.get2(x, z).abs();
+        if distance >= RAVINE_WIDTH {
+            return None;
+        }
+
+        // Deepest in the middle of the ravine, sloping up towards the walls.
+        let depth = (self.ravine_depth.get2(x, z) + 1.0) * 0.5 * RAVINE_FLOOR_VARIATION;
+        let slope = distance / RAVINE_WIDTH;
+        Some((RAVINE_MIN_FLOOR + depth + slope * slope * 24.0) as i32)
+    }
+}
@@ -1,23 +1,216 @@
+use biome::Biome;
 use block::{ Block, BlockType };
 use math::*;
+use world_generator::GeneratorStamp;
 
 pub static EMPTY_CHUNK: Chunk = Chunk {
-    blocks: [Block { ty: BlockType::Air }; CHUNK_BLOCK_COUNT],
+    sections: [None, None, None, None, None, None, None, None],
+    biomes: [Biome::Plains; CHUNK_COLUMN_COUNT],
+    generator: None,
 };
 
-// #[derive(Clone)]
+/// A column of blocks, split into `CHUNK_SECTIONS_HIGH` cubic sections. Sections that are all
+/// air aren't stored at all.
 pub struct Chunk {
-    blocks: [Block; CHUNK_BLOCK_COUNT],
+    sections: [Option<Box<Section>>; CHUNK_SECTIONS_HIGH],
+    biomes: [Biome; CHUNK_COLUMN_COUNT],
+    /// `None` for chunks saved before generators were recorded.
+    generator: Option<GeneratorStamp>,
 }
 
 impl Clone for Chunk {
     fn clone(&self) -> Chunk {
+        let mut sections: [Option<Box<Section>>; CHUNK_SECTIONS_HIGH] = Default::default();
+        for (section, other) in sections.iter_mut().zip(self.sections.iter()) {
+            *section = other.clone();
+        }
         Chunk {
-            blocks: self.blocks
+            sections,
+            biomes: self.biomes,
+            generator: self.generator.clone(),
         }
     }
 }
 
+/// A cube of `SECTION_BLOCK_COUNT` blocks, stored as a palette of the different blocks in it and
+/// a packed array of indices into the palette, using only as many bits for each index as the
+/// palette needs.
+#[derive(Clone)]
+struct Section {
+    palette: Vec<Block>,
+    /// How many blocks use each palette entry. Entries that drop to 0 are reused.
+    counts: Vec<u16>,
+    bits: usize,
+    data: Vec<u64>,
+}
+
+impl Section {
+    fn new() -> Section {
+        Section {
+            palette: vec![Block::new(BlockType::Air)],
+            counts: vec![SECTION_BLOCK_COUNT as u16],
+            bits: 1,
+            data: pack(&[0; SECTION_BLOCK_COUNT], 1),
+        }
+    }
+
+    fn index(&self, i: usize) -> usize {
+        let per_word = 64 / self.bits;
+        let word = self.data[i / per_word];
+        (word >> (i % per_word * self.bits) & ((1 << self.bits) - 1)) as usize
+    }
+
+    fn set_index(&mut self, i: usize, index: usize) {
+        let per_word = 64 / self.bits;
+        let shift = i % per_word * self.bits;
+        let mask = ((1u64 << self.bits) - 1) << shift;
+        let word = &mut self.data[i / per_word];
+        *word = (*word & !mask) | ((index as u64) << shift);
+    }
+
+    fn get(&self, i: usize) -> Block {
+        self.palette[self.index(i)]
+    }
+
+    fn set(&mut self, i: usize, block: Block) {
+        let old = self.index(i);
+        if self.palette[old] == block {
+            return;
+        }
+        self.counts[old] -= 1;
+        let new = self.palette_entry(block);
+        self.counts[new] += 1;
+        self.set_index(i, new);
+    }
+
+    /// The palette index of `block`, adding it if it isn't there.
+    fn palette_entry(&mut self, block: Block) -> usize {
+        if let Some(i) = self.palette.iter().position(|&b| b == block) {
+            return i;
+        }
+        if let Some(i) = self.counts.iter().position(|&count| count == 0) {
+            self.palette[i] = block;
+            return i;
+        }
+        if self.palette.len() == 1 << self.bits {
+            let indices: Vec<usize> = (0..SECTION_BLOCK_COUNT).map(|i| self.index(i)).collect();
+            self.bits += 1;
+            self.data = pack(&indices, self.bits);
+        }
+        self.palette.push(block);
+        self.counts.push(0);
+        self.palette.len() - 1
+    }
+
+    fn is_empty(&self) -> bool {
+        self.palette.iter().zip(self.counts.iter()).all(|(block, &count)| count == 0 || block.is_air())
+    }
+
+    /// Writes the section with its palette in the order the blocks first appear, leaving out
+    /// unused entries, so the same blocks always give the same bytes.
+    fn write(&self, bytes: &mut Vec<u8>) {
+        let mut palette: Vec<Block> = Vec::new();
+        let mut indices = Vec::with_capacity(SECTION_BLOCK_COUNT);
+        for i in 0..SECTION_BLOCK_COUNT {
+            let block = self.get(i);
+            let index = match palette.iter().position(|&b| b == block) {
+                Some(index) => index,
+                None => {
+                    palette.push(block);
+                    palette.len() - 1
+                }
+            };
+            indices.push(index);
+        }
+
+        let bits = bits_for(palette.len());
+        bytes.push(palette.len() as u8);
+        bytes.push((palette.len() >> 8) as u8);
+        for block in &palette {
+            bytes.push(block.ty as u8);
+            bytes.push(block.state);
+        }
+        bytes.push(bits as u8);
+        for word in pack(&indices, bits) {
+            for byte in 0..8 {
+                bytes.push((word >> (byte * 8)) as u8);
+            }
+        }
+    }
+
+    /// Reads a section written by `write` from the start of `bytes`, returning it and the number
+    /// of bytes it took up. Without `states`, the palette is only block types, as sections were
+    /// written before blocks had states.
+    fn read(bytes: &[u8], states: bool) -> Result<(Section, usize), String> {
+        if bytes.len() < 2 {
+            return Err("section cut short".to_string());
+        }
+        let palette_len = bytes[0] as usize | (bytes[1] as usize) << 8;
+        let entry_len = if states { 2 } else { 1 };
+        let start = 3 + palette_len * entry_len;
+        if palette_len == 0 || bytes.len() < start {
+            return Err("section palette cut short".to_string());
+        }
+        let mut palette = Vec::with_capacity(palette_len);
+        for b in bytes[2..start - 1].chunks(entry_len) {
+            match BlockType::from_id(b[0]) {
+                Some(ty) => palette.push(Block::with_state(ty, if states { b[1] } else { 0 })),
+                None => return Err(format!("unknown block type {}", b[0])),
+            }
+        }
+        let bits = bytes[start - 1] as usize;
+        if bits < bits_for(palette_len) || bits > 16 {
+            return Err(format!("bad section index size {}", bits));
+        }
+
+        let words = word_count(bits);
+        if bytes.len() < start + words * 8 {
+            return Err("section indices cut short".to_string());
+        }
+        let data = (0..words)
+            .map(|w| (0..8).fold(0, |word, byte| word | (bytes[start + w * 8 + byte] as u64) << (byte * 8)))
+            .collect();
+        let mut section = Section {
+            counts: vec![0; palette.len()],
+            palette,
+            bits,
+            data,
+        };
+        for i in 0..SECTION_BLOCK_COUNT {
+            let index = section.index(i);
+            if index >= palette_len {
+                return Err(format!("palette index {} out of range", index));
+            }
+            section.counts[index] += 1;
+        }
+        Ok((section, start + words * 8))
+    }
+}
+
+/// The fewest bits, at least one, that can index a palette of `len` entries.
+fn bits_for(len: usize) -> usize {
+    let mut bits = 1;
+    while 1 << bits < len {
+        bits += 1;
+    }
+    bits
+}
+
+/// Indices don't cross word boundaries, so some bits at the top of each word may go unused.
+fn word_count(bits: usize) -> usize {
+    let per_word = 64 / bits;
+    (SECTION_BLOCK_COUNT + per_word - 1) / per_word
+}
+
+fn pack(indices: &[usize], bits: usize) -> Vec<u64> {
+    let per_word = 64 / bits;
+    let mut data = vec![0u64; word_count(bits)];
+    for (i, &index) in indices.iter().enumerate() {
+        data[i / per_word] |= (index as u64) << (i % per_word * bits);
+    }
+    data
+}
+
 fn index_to_coord(i: usize) -> Coord {
     Coord {
         y: (i & 0x7F) as i32,
@@ -36,6 +229,7 @@ pub const CHUNK_HEIGHT_BITS: usize = 7;
 pub const CHUNK_HEIGHT: usize = 1 << CHUNK_HEIGHT_BITS;
 pub const CHUNK_HEIGHT_MASK: usize = CHUNK_HEIGHT - 1;
 pub const CHUNK_BLOCK_COUNT: usize = CHUNK_SIDE_LENGTH * CHUNK_SIDE_LENGTH * CHUNK_HEIGHT;
+pub const CHUNK_COLUMN_COUNT: usize = CHUNK_SIDE_LENGTH * CHUNK_SIDE_LENGTH;
 
 pub const WORLD_HEIGHT: usize = CHUNK_HEIGHT;
 
@@ -44,22 +238,33 @@ pub const SECTION_SIZE: usize = CHUNK_SIDE_LENGTH;
 pub const SECTION_SIZE_MASK: usize = CHUNK_SIDE_LENGTH_MASK;
 pub const SECTION_BLOCK_COUNT: usize = SECTION_SIZE * SECTION_SIZE * SECTION_SIZE;
 
+/// The version of the format written by `Chunk::to_bytes`. Bump it when the format changes and
+/// keep reading the old versions in `Chunk::from_bytes`.
+pub const BLOCK_FORMAT_VERSION: u8 = 1;
+
 //bit_consts! {}
 
-fn coord_to_index(coord: Coord) -> usize {
-    let mut x = coord.x as usize;
-    let mut y = coord.y as usize;
-    let mut z = coord.z as usize;
+/// The section holding `coord`, and the index of the block within it. Like in the chunk, y
+/// changes fastest, then z, then x.
+fn section_index(coord: Coord) -> (usize, usize) {
+    debug_assert!(coord.y >= 0 && (coord.y as usize) < WORLD_HEIGHT);
+    debug_assert!(coord.x >= 0 && (coord.x as usize) < CHUNK_SIDE_LENGTH);
+    debug_assert!(coord.z >= 0 && (coord.z as usize) < CHUNK_SIDE_LENGTH);
 
-    debug_assert!(y < WORLD_HEIGHT);
-    debug_assert!(x < CHUNK_SIDE_LENGTH);
-    debug_assert!(z < CHUNK_SIDE_LENGTH);
+    let x = coord.x as usize & SECTION_SIZE_MASK;
+    let y = coord.y as usize;
+    let z = coord.z as usize & SECTION_SIZE_MASK;
 
-    x &= CHUNK_SIDE_LENGTH - 1;
-    y &= WORLD_HEIGHT - 1;
-    z &= CHUNK_SIDE_LENGTH - 1;
+    let section = y >> SECTION_SIZE_BITS & CHUNK_SECTIONS_HIGH_MASK;
+    let y = y & SECTION_SIZE_MASK;
+    (section, y + (z << SECTION_SIZE_BITS) + (x << (2 * SECTION_SIZE_BITS)))
+}
+
+fn column_index(x: i32, z: i32) -> usize {
+    debug_assert!(x >= 0 && (x as usize) < CHUNK_SIDE_LENGTH);
+    debug_assert!(z >= 0 && (z as usize) < CHUNK_SIDE_LENGTH);
 
-    y + (z * WORLD_HEIGHT) + (x * WORLD_HEIGHT * CHUNK_SIDE_LENGTH)
+    (x as usize & CHUNK_SIDE_LENGTH_MASK) + (z as usize & CHUNK_SIDE_LENGTH_MASK) * CHUNK_SIDE_LENGTH
 }
 
 impl Chunk {
@@ -72,9 +277,13 @@ impl Chunk {
     /// when it is outside the range `0..WORLD_HEIGHT`.
     pub fn get(&self, coord: Coord) -> Block {
         if Self::is_valid_coord(coord) {
-            self.blocks[coord_to_index(coord)]
+            let (section, i) = section_index(coord);
+            match self.sections[section] {
+                Some(ref section) => section.get(i),
+                None => Block::new(BlockType::Air),
+            }
         } else {
-            Block { ty: BlockType::Air }
+            Block::new(BlockType::Air)
         }
     }
 
@@ -82,40 +291,281 @@ impl Chunk {
     /// debug and wrapped in release. `coord.y` may take any value, but assignments are ignored
     /// when it is outside the range `0..WORLD_HEIGHT`.
     pub fn set(&mut self, coord: Coord, block: Block) {
-        if Self::is_valid_coord(coord) {
-            self.blocks[coord_to_index(coord)] = block;
+        if !Self::is_valid_coord(coord) {
+            return;
         }
+        let (section, i) = section_index(coord);
+        if self.sections[section].is_none() {
+            if block.is_air() {
+                return;
+            }
+            self.sections[section] = Some(Box::new(Section::new()));
+        }
+
+        let now_empty = {
+            let section = self.sections[section].as_mut().unwrap();
+            section.set(i, block);
+            block.is_air() && section.is_empty()
+        };
+        if now_empty {
+            self.sections[section] = None;
+        }
+    }
+
+    /// `x` and `z` must be in the range `0..CHUNK_SIDE_LENGTH`.
+    pub fn get_biome(&self, x: i32, z: i32) -> Biome {
+        self.biomes[column_index(x, z)]
+    }
+
+    /// `x` and `z` must be in the range `0..CHUNK_SIDE_LENGTH`.
+    pub fn set_biome(&mut self, x: i32, z: i32, biome: Biome) {
+        self.biomes[column_index(x, z)] = biome;
+    }
+
+    /// The generator that produced this chunk.
+    pub fn generator(&self) -> Option<&GeneratorStamp> {
+        self.generator.as_ref()
+    }
+
+    pub fn set_generator(&mut self, generator: Option<GeneratorStamp>) {
+        self.generator = generator;
     }
 
     pub fn is_valid_coord(coord: Coord) -> bool {
         coord.y >= 0 && coord.y < WORLD_HEIGHT as i32
     }
 
+    /// The format version, then a byte with a bit set for each section that isn't empty,
+    /// followed by those sections from the bottom up. Each is its palette size, the palette as
+    /// type and state pairs, the index size and the packed indices.
     pub fn to_bytes(&self) -> Vec<u8> {
-        self.blocks.iter().map(|&b| b.ty as u8).collect()
+        let mut bytes = vec![BLOCK_FORMAT_VERSION, 0];
+        for (i, section) in self.sections.iter().enumerate() {
+            if let Some(ref section) = *section {
+                bytes[1] |= 1 << i;
+                section.write(&mut bytes);
+            }
+        }
+        bytes
+    }
+
+    /// Reads blocks written by `to_bytes`, whatever their length. Fails if the bytes aren't a
+    /// chunk.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Box<Chunk>, String> {
+        if bytes.len() < 2 {
+            return Err(format!("only {} bytes of blocks", bytes.len()));
+        }
+        if bytes[0] != BLOCK_FORMAT_VERSION {
+            return Err(format!("unknown chunk format version {}", bytes[0]));
+        }
+        Chunk::read_sections(&bytes[1..], true).or_else(|e| {
+            // Blocks that came to exactly `CHUNK_BLOCK_COUNT` bytes used to get a zero byte on
+            // the end. Sections say how long they are, so only one of these can read.
+            if bytes.len() == CHUNK_BLOCK_COUNT + 1 && bytes[CHUNK_BLOCK_COUNT] == 0 {
+                Chunk::read_sections(&bytes[1..CHUNK_BLOCK_COUNT], true).map_err(|_| e)
+            } else {
+                Err(e)
+            }
+        })
+    }
+
+    /// Reads blocks saved before chunk blobs had a format header, which have nothing saying
+    /// what format they're in. They can be in the current format, in the first sectioned
+    /// format, which had no version and no block states and started straight away with the
+    /// section mask, or in the flat format from before sections, a type byte for every block.
+    /// The sectioned formats are tried first, since they only read when their sections take up
+    /// exactly the bytes there are, while any `CHUNK_BLOCK_COUNT` bytes of known types are a
+    /// flat chunk.
+    pub fn from_headerless_bytes(bytes: &[u8]) -> Result<Box<Chunk>, String> {
+        Chunk::from_bytes(bytes)
+            .or_else(|e| Chunk::read_sections(bytes, false).map_err(|_| e))
+            .or_else(|e| if bytes.len() == CHUNK_BLOCK_COUNT { Chunk::read_flat(bytes) } else { Err(e) })
+    }
+
+    /// Reads a type byte for every block, in the order of `index_to_coord`.
+    fn read_flat(bytes: &[u8]) -> Result<Box<Chunk>, String> {
+        let mut chunk = Chunk::new();
+        for (i, &byte) in bytes.iter().enumerate() {
+            match BlockType::from_id(byte) {
+                Some(ty) => chunk.set(index_to_coord(i), Block::new(ty)),
+                None => return Err(format!("unknown block type {}", byte)),
+            }
+        }
+        Ok(chunk)
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Box<Chunk> {
-        assert!(bytes.len() == CHUNK_BLOCK_COUNT);
+    /// Reads a section mask, with a bit set for each section that isn't empty, followed by
+    /// those sections from the bottom up.
+    fn read_sections(bytes: &[u8], states: bool) -> Result<Box<Chunk>, String> {
         let mut chunk = Chunk::new();
-        for (i, block) in chunk.blocks.iter_mut().enumerate() {
-            *block = Block::from(bytes[i]);
+        let mask = match bytes.first() {
+            Some(&mask) => mask,
+            None => return Err("no section mask".to_string()),
+        };
+        let mut pos = 1;
+        for i in 0..CHUNK_SECTIONS_HIGH {
+            if mask & 1 << i != 0 {
+                let (section, len) = Section::read(&bytes[pos..], states)?;
+                pos += len;
+                if !section.is_empty() {
+                    chunk.sections[i] = Some(Box::new(section));
+                }
+            }
+        }
+        if pos != bytes.len() {
+            return Err(format!("{} bytes left over after the chunk's sections", bytes.len() - pos));
+        }
+        Ok(chunk)
+    }
+
+    pub fn biomes_to_bytes(&self) -> Vec<u8> {
+        self.biomes.iter().map(|&b| b as u8).collect()
+    }
+
+    pub fn set_biomes_from_bytes(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if bytes.len() != CHUNK_COLUMN_COUNT {
+            return Err(format!("{} bytes of biomes", bytes.len()));
         }
-        chunk
+        for (i, biome) in self.biomes.iter_mut().enumerate() {
+            *biome = Biome::from_id(bytes[i]).ok_or_else(|| format!("unknown biome {}", bytes[i]))?;
+        }
+        Ok(())
     }
 
     pub fn iter<'a>(&'a self) -> impl Iterator<Item=(Coord, Block)> + 'a {
-        self.blocks
-            .iter()
-            .cloned()
-            .enumerate()
-            .map(|(i, b)| (index_to_coord(i), b))
-    }
-
-    pub fn iter_mut<'a>(&'a mut self) -> impl Iterator<Item=(Coord, &mut Block)> + 'a {
-        self.blocks
-            .iter_mut()
-            .enumerate()
-            .map(|(i, b)| (index_to_coord(i), b))
+        (0..CHUNK_BLOCK_COUNT).map(move |i| {
+            let coord = index_to_coord(i);
+            (coord, self.get(coord))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn section_count(chunk: &Chunk) -> usize {
+        chunk.sections.iter().filter(|section| section.is_some()).count()
+    }
+
+    #[test]
+    fn blocks_are_kept_across_sections() {
+        let mut chunk = Chunk::new();
+        let types = [BlockType::Stone, BlockType::Dirt, BlockType::Grass, BlockType::Sand, BlockType::Water];
+        for (i, (coord, _)) in EMPTY_CHUNK.iter().enumerate().filter(|&(i, _)| i % 7 == 0) {
+            chunk.set(coord, Block::new(types[i % types.len()]));
+        }
+        for (i, (coord, block)) in chunk.iter().enumerate() {
+            let expected = if i % 7 == 0 { types[i % types.len()] } else { BlockType::Air };
+            assert_eq!(block.ty, expected);
+            assert_eq!(chunk.get(coord), block);
+        }
+        assert_eq!(section_count(&chunk), CHUNK_SECTIONS_HIGH);
+    }
+
+    #[test]
+    fn air_sections_are_dropped() {
+        let mut chunk = Chunk::new();
+        chunk.set(Coord::new(3, 70, 9), Block::new(BlockType::Log));
+        chunk.set(Coord::new(3, 5, 9), Block::new(BlockType::Stone));
+        assert_eq!(section_count(&chunk), 2);
+        chunk.set(Coord::new(3, 70, 9), Block::new(BlockType::Air));
+        assert_eq!(section_count(&chunk), 1);
+        assert!(chunk.get(Coord::new(3, 70, 9)).is_air());
+        assert_eq!(chunk.get(Coord::new(3, 5, 9)).ty, BlockType::Stone);
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let mut chunk = Chunk::new();
+        for x in 0..CHUNK_SIDE_LENGTH as i32 {
+            for z in 0..CHUNK_SIDE_LENGTH as i32 {
+                for y in 0..40 + x {
+                    let ty = BlockType::from(((x + y * z) % 17) as u8);
+                    chunk.set(Coord::new(x, y, z), Block::with_state(ty, (y % 3) as u8));
+                }
+            }
+        }
+        let bytes = chunk.to_bytes();
+        assert!(bytes.len() < CHUNK_BLOCK_COUNT);
+        let loaded = Chunk::from_bytes(&bytes).unwrap();
+        assert_eq!(section_count(&loaded), 4);
+        assert!(loaded.to_bytes() == bytes);
+        assert!(loaded.iter().zip(chunk.iter()).all(|(a, b)| a == b));
+    }
+
+    /// Fills a section with `palette_len` different blocks, none of them air.
+    fn fill_section(chunk: &mut Chunk, section: usize, palette_len: usize) {
+        for i in 0..SECTION_BLOCK_COUNT {
+            let coord = Coord::new((i >> 8) as i32, (section * SECTION_SIZE + (i & 0xF)) as i32, (i >> 4 & 0xF) as i32);
+            let j = i % palette_len;
+            chunk.set(coord, Block::with_state(BlockType::from(1 + (j / 256) as u8), (j % 256) as u8));
+        }
+    }
+
+    #[test]
+    fn big_palettes_round_trip_at_any_length() {
+        // A full section is 3 + 2 * palette + 8 * words bytes, with 512 words for palettes up
+        // to 256 and 586 up to 512. A section with one stone block is 3 + 2 * 2 + 8 * 64.
+        let mut flat_length = Chunk::new();
+        for (section, &palette_len) in [253, 254, 254, 254, 254, 254, 254].iter().enumerate() {
+            fill_section(&mut flat_length, section, palette_len);
+        }
+        flat_length.set(Coord::new(5, WORLD_HEIGHT as i32 - 1, 5), Block::new(BlockType::Stone));
+        let mut one_longer = Chunk::new();
+        for (section, &palette_len) in [249, 247, 247, 247, 247, 247, 257].iter().enumerate() {
+            fill_section(&mut one_longer, section, palette_len);
+        }
+
+        for &(chunk, len) in [(&flat_length, CHUNK_BLOCK_COUNT), (&one_longer, CHUNK_BLOCK_COUNT + 1)].iter() {
+            let bytes = chunk.to_bytes();
+            assert_eq!(bytes.len(), len);
+            for loaded in vec![Chunk::from_bytes(&bytes).unwrap(), Chunk::from_headerless_bytes(&bytes).unwrap()] {
+                assert!(loaded.to_bytes() == bytes);
+                assert!(loaded.iter().zip(chunk.iter()).all(|(a, b)| a == b));
+            }
+        }
+
+        // Blocks this long used to be written with a zero byte on the end.
+        let mut padded = flat_length.to_bytes();
+        padded.push(0);
+        assert!(Chunk::from_bytes(&padded).unwrap().to_bytes() == flat_length.to_bytes());
+    }
+
+    #[test]
+    fn flat_blobs_still_load() {
+        let mut bytes = vec![0; CHUNK_BLOCK_COUNT];
+        // A byte per block, y changing fastest, then z, then x.
+        bytes[2 + 3 * WORLD_HEIGHT + WORLD_HEIGHT * CHUNK_SIDE_LENGTH] = BlockType::Sponge as u8;
+        let chunk = Chunk::from_headerless_bytes(&bytes).unwrap();
+        assert_eq!(chunk.get(Coord::new(1, 2, 3)).ty, BlockType::Sponge);
+        assert_eq!(section_count(&chunk), 1);
+        assert!(Chunk::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn stateless_sectioned_blobs_still_load() {
+        let sponge = Coord::new(1, 2, 3);
+        // Any mask can start these, including one equal to the current format version.
+        for &section in [0, 1, 4].iter() {
+            let coord = Coord { y: sponge.y + section as i32 * SECTION_SIZE as i32, ..sponge };
+            let mut indices = vec![0; SECTION_BLOCK_COUNT];
+            indices[section_index(coord).1] = 1;
+            let mut bytes = vec![1 << section, 2, 0, BlockType::Air as u8, BlockType::Sponge as u8, 1];
+            for word in pack(&indices, 1) {
+                for byte in 0..8 {
+                    bytes.push((word >> (byte * 8)) as u8);
+                }
+            }
+
+            let chunk = Chunk::from_headerless_bytes(&bytes).unwrap();
+            assert_eq!(chunk.get(coord), Block::new(BlockType::Sponge));
+            assert_eq!(section_count(&chunk), 1);
+            assert!(Chunk::from_bytes(&bytes).is_err());
+        }
+
+        let mut chunk = Chunk::new();
+        chunk.set(sponge, Block::with_state(BlockType::Log, 2));
+        assert_eq!(Chunk::from_headerless_bytes(&chunk.to_bytes()).unwrap().get(sponge), chunk.get(sponge));
     }
 }
@@ -2,54 +2,223 @@ use std::sync::mpsc;
 use std::thread;
 
 use block::{ Block, BlockType };
+use carver::Carver;
 use chunk::{ Chunk, CHUNK_SIDE_LENGTH };
+use decoration::{ BlockEdit, Decorator };
 use math::*;
+use ores::{ OreConfig, OrePlacer };
+use preset::WorldPreset;
+use regenerate::regenerate_chunk;
+use structures::{ Structure, StructurePlacer, StructureStart };
+use world_generator::{ GeneratorConfig, GeneratorRegistry, GeneratorStamp, WorldGenerator };
+
+const FALLBACK_GENERATOR: &str = "noise";
 
 pub struct ChunkGenerator {
-    thread_handle: thread::JoinHandle<()>,
-    tx_req: mpsc::Sender<ChunkCoord>,
+    // Options so that `finish` can close the request channel, which stops the thread once it's
+    // generated everything asked of it, and then join it.
+    thread_handle: Option<thread::JoinHandle<()>>,
+    tx_req: Option<mpsc::Sender<Request>>,
     rx_resp: mpsc::Receiver<Response>,
+    rx_regen: mpsc::Receiver<Regenerated>,
+}
+
+enum Request {
+    Generate(ChunkCoord),
+    /// See `regenerate_chunk`.
+    Regenerate(ChunkCoord, Option<Vec<BlockEdit>>),
+    /// Like `Regenerate` with the player's edits, but answered like `Generate`.
+    Recover(ChunkCoord, Vec<BlockEdit>),
+}
+
+/// Runs the world generator picked by the preset, then the ore, cave, structure and decoration
+/// passes that the preset has turned on.
+pub struct TerrainGenerator {
+    seed: u32,
+    generator: Box<WorldGenerator>,
+    ores: Option<OrePlacer>,
+    carver: Option<Carver>,
+    decorator: Option<Decorator>,
+    structures: Option<StructurePlacer>,
+    sea_level: Option<i32>,
+}
+
+impl TerrainGenerator {
+    pub fn new(seed: u32, preset: WorldPreset, ore_config: OreConfig, structures: Vec<Structure>) -> TerrainGenerator {
+        let registry = GeneratorRegistry::new();
+        let config = GeneratorConfig { preset: &preset };
+        let generator = registry.create(&preset.terrain, &config).unwrap_or_else(|| {
+            warn!("No world generator called {:?}, using {:?}.", preset.terrain, FALLBACK_GENERATOR);
+            registry.create(FALLBACK_GENERATOR, &config).unwrap()
+        });
+
+        TerrainGenerator {
+            seed,
+            generator,
+            ores: if preset.ores { Some(OrePlacer::new(seed, ore_config)) } else { None },
+            carver: if preset.caves { Some(Carver::new(seed)) } else { None },
+            decorator: if preset.decorations { Some(Decorator::new(seed)) } else { None },
+            structures: if preset.structures { Some(StructurePlacer::new(seed, structures)) } else { None },
+            sea_level: preset.sea_level,
+        }
+    }
+
+    /// The terrain of a world made with the default preset, for tests.
+    #[cfg(test)]
+    pub fn with_defaults(seed: u32) -> TerrainGenerator {
+        TerrainGenerator::new(seed, WorldPreset::default(), OreConfig::default(), Vec::new())
+    }
+
+    /// Generates a chunk without decorations.
+    pub fn generate(&self, coord: ChunkCoord) -> Box<Chunk> {
+        // A generator may stamp the chunk itself, when it had to make it some other way.
+        let mut chunk = self.generator.generate(coord, self.seed);
+        if chunk.generator().is_none() {
+            chunk.set_generator(Some(GeneratorStamp::of(&*self.generator)));
+        }
+
+        if let Some(ref ores) = self.ores {
+            ores.place(coord, &mut chunk);
+        }
+        if let Some(ref carver) = self.carver {
+            carver.carve(coord, &mut chunk);
+        }
+        if let Some(ref structures) = self.structures {
+            structures.place(&*self.generator, self.sea_level, coord, &mut chunk);
+        }
+        if let Some(sea_level) = self.sea_level {
+            fill_water(&mut chunk, sea_level);
+        }
+
+        chunk
+    }
+
+    /// Generates a chunk and decorates it, returning the decoration edits that spilled over
+    /// into neighbouring chunks.
+    pub fn generate_decorated(&self, coord: ChunkCoord) -> (Box<Chunk>, Vec<(ChunkCoord, BlockEdit)>) {
+        let mut chunk = self.generate(coord);
+        let foreign_edits = match self.decorator {
+            Some(ref decorator) => decorator.decorate(coord, &mut chunk),
+            None => Vec::new(),
+        };
+        (chunk, foreign_edits)
+    }
+
+    /// The structures placed with their origin in the chunk at `coord`.
+    pub fn structures_in(&self, coord: ChunkCoord) -> Vec<StructureStart> {
+        match self.structures {
+            Some(ref structures) => structures.starts_in(&*self.generator, self.sea_level, coord),
+            None => Vec::new(),
+        }
+    }
 }
 
-fn fill_layer(chunk: &mut Chunk, y: i32, ty: BlockType) {
+/// Fills every column with water from sea level down to the first solid block.
+fn fill_water(chunk: &mut Chunk, sea_level: i32) {
     for x in 0..CHUNK_SIDE_LENGTH as i32 {
         for z in 0..CHUNK_SIDE_LENGTH as i32 {
-            chunk.set(Coord {x, y, z}, Block { ty } )
+            for y in (1..sea_level + 1).rev() {
+                let coord = Coord { x, y, z };
+                if !chunk.get(coord).is_air() {
+                    break;
+                }
+                chunk.set(coord, Block::new(BlockType::Water));
+            }
         }
     }
 }
 
 impl ChunkGenerator {
-    pub fn new() -> ChunkGenerator {
+    pub fn new(seed: u32, preset: WorldPreset, ore_config: OreConfig, structures: Vec<Structure>) -> ChunkGenerator {
         let (tx_req, rx_req) = mpsc::channel();
         let (tx_resp, rx_resp) = mpsc::channel();
+        let (tx_regen, rx_regen) = mpsc::channel();
         let thread_handle = thread::spawn(move || {
-            for coord in rx_req {
-                let mut chunk = Chunk::new();
-
-                for y in 0..16 { fill_layer(&mut chunk, y, BlockType::Stone) }
-                for y in 16..40 { fill_layer(&mut chunk, y, BlockType::Stone) }
-                fill_layer(&mut chunk, 40, BlockType::Grass);
-
-                tx_resp.send((coord, chunk));
+            let terrain = TerrainGenerator::new(seed, preset, ore_config, structures);
+            for request in rx_req {
+                match request {
+                    Request::Generate(coord) => {
+                        let (chunk, foreign_edits) = terrain.generate_decorated(coord);
+                        tx_resp.send((coord, chunk, foreign_edits, terrain.structures_in(coord)));
+                    }
+                    Request::Regenerate(coord, player_edits) => {
+                        let (chunk, foreign_edits) = regenerate_chunk(&terrain, coord, player_edits.as_ref().map(|edits| &edits[..]));
+                        tx_regen.send((coord, chunk, foreign_edits));
+                    }
+                    Request::Recover(coord, player_edits) => {
+                        let (chunk, foreign_edits) = regenerate_chunk(&terrain, coord, Some(&player_edits));
+                        tx_resp.send((coord, chunk, foreign_edits, Vec::new()));
+                    }
+                }
             }
         });
 
         ChunkGenerator {
-            tx_req, rx_resp, thread_handle
+            tx_req: Some(tx_req),
+            rx_resp,
+            rx_regen,
+            thread_handle: Some(thread_handle),
         }
     }
 
     pub fn start_generate(&mut self, coord: ChunkCoord) {
-        self.tx_req.send(coord).unwrap();
+        self.send(Request::Generate(coord));
+    }
+
+    /// Generates a chunk again as if the world were new, see `regenerate_chunk`. It comes back
+    /// through `iter_regenerated`, not with the chunks being generated for the first time.
+    pub fn start_regenerate(&mut self, coord: ChunkCoord, player_edits: Option<Vec<BlockEdit>>) {
+        self.send(Request::Regenerate(coord, player_edits));
+    }
+
+    /// Generates a chunk again in place of one that couldn't be loaded, keeping `player_edits`,
+    /// see `regenerate_chunk`. It comes back through `iter_generated` like any other chunk.
+    pub fn start_recover(&mut self, coord: ChunkCoord, player_edits: Vec<BlockEdit>) {
+        self.send(Request::Recover(coord, player_edits));
+    }
+
+    pub fn iter_regenerated(&mut self) -> mpsc::TryIter<Regenerated> {
+        self.rx_regen.try_iter()
+    }
+
+    fn send(&self, request: Request) {
+        self.tx_req.as_ref().expect("the generator has been finished").send(request).unwrap();
     }
 
     pub fn iter_generated(&mut self) -> ResponseIter {
         ResponseIter(self.rx_resp.try_iter())
     }
+
+    /// Blocks until the next chunk is generated.
+    pub fn wait_generated(&mut self) -> Response {
+        self.rx_resp.recv().unwrap()
+    }
+
+    /// Stops taking requests and waits for every chunk already asked for. Returns the ones that
+    /// haven't been taken yet.
+    pub fn finish(&mut self) -> Vec<Response> {
+        self.tx_req.take();
+        if let Some(thread_handle) = self.thread_handle.take() {
+            if thread_handle.join().is_err() {
+                error!("The world generator thread panicked");
+            }
+        }
+        self.rx_resp.try_iter().collect()
+    }
+}
+
+impl Drop for ChunkGenerator {
+    fn drop(&mut self) {
+        self.finish();
+    }
 }
 
-type Response = (ChunkCoord, Box<Chunk>);
+/// A generated chunk, along with the decoration edits that spilled over into its neighbours and
+/// the structures that start in it.
+type Response = (ChunkCoord, Box<Chunk>, Vec<(ChunkCoord, BlockEdit)>, Vec<StructureStart>);
+
+/// A regenerated chunk, along with the decoration edits it spills into its neighbours.
+pub type Regenerated = (ChunkCoord, Box<Chunk>, Vec<(ChunkCoord, BlockEdit)>);
 
 pub struct ResponseIter<'a>(mpsc::TryIter<'a, Response>);
 
@@ -59,3 +228,75 @@ impl<'a> Iterator for ResponseIter<'a> {
         self.0.next()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static SAMPLE_COORDS: [ChunkCoord; 4] = [
+        ChunkCoord { x: 0, z: 0 },
+        ChunkCoord { x: -1, z: 3 },
+        ChunkCoord { x: 17, z: -42 },
+        ChunkCoord { x: -300, z: -300 },
+    ];
+
+    #[test]
+    fn same_seed_generates_identical_chunks() {
+        let first = TerrainGenerator::with_defaults(0xC0FFEE);
+        let second = TerrainGenerator::with_defaults(0xC0FFEE);
+        for &coord in SAMPLE_COORDS.iter() {
+            assert!(first.generate(coord).to_bytes() == second.generate(coord).to_bytes(),
+                    "chunk {} differs between generators with the same seed", coord);
+        }
+    }
+
+    #[test]
+    fn generation_does_not_depend_on_order() {
+        let terrain = TerrainGenerator::with_defaults(99);
+        let forwards: Vec<_> = SAMPLE_COORDS.iter().map(|&c| terrain.generate(c).to_bytes()).collect();
+        let backwards: Vec<_> = SAMPLE_COORDS.iter().rev().map(|&c| terrain.generate(c).to_bytes()).collect();
+        for (a, b) in forwards.iter().zip(backwards.iter().rev()) {
+            assert!(a == b);
+        }
+    }
+
+    #[test]
+    fn carving_never_removes_bedrock() {
+        let terrain = TerrainGenerator::with_defaults(7);
+        for &coord in SAMPLE_COORDS.iter() {
+            let chunk = terrain.generate(coord);
+            for x in 0..CHUNK_SIDE_LENGTH as i32 {
+                for z in 0..CHUNK_SIDE_LENGTH as i32 {
+                    assert!(chunk.get(Coord { x, y: 0, z }).ty == BlockType::Bedrock);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn chunks_record_their_generator() {
+        let terrain = TerrainGenerator::with_defaults(3);
+        let stamp = terrain.generate(ChunkCoord::new(0, 0)).generator().cloned().unwrap();
+        assert_eq!(stamp.name, "noise");
+        assert!(!GeneratorRegistry::new().is_outdated(Some(&stamp)));
+    }
+
+    #[test]
+    fn water_round_trips_through_bytes() {
+        let terrain = TerrainGenerator::with_defaults(11);
+        let mut chunk = terrain.generate(ChunkCoord::new(2, 2));
+        chunk.set(Coord::new(0, 100, 0), Block::new(BlockType::Water));
+        let bytes = chunk.to_bytes();
+        assert!(Chunk::from_bytes(&bytes).unwrap().to_bytes() == bytes);
+        assert!(Chunk::from_bytes(&bytes).unwrap().get(Coord::new(0, 100, 0)).ty == BlockType::Water);
+    }
+
+    #[test]
+    fn different_seeds_generate_different_terrain() {
+        let first = TerrainGenerator::with_defaults(1);
+        let second = TerrainGenerator::with_defaults(2);
+        let differs = SAMPLE_COORDS.iter()
+            .any(|&coord| first.generate(coord).to_bytes() != second.generate(coord).to_bytes());
+        assert!(differs);
+    }
+}
@@ -1,35 +1,150 @@
 use inflate::inflate_bytes_zlib;
-use deflate::deflate_bytes_zlib;
-use fnv::FnvHashMap;
-use rusqlite::{ Connection, Row, DatabaseName, Error as SqliteError };
+use fnv::{ FnvHashMap, FnvHashSet };
+use rusqlite::{ Connection, Row, DatabaseName, Error as SqliteError, Result as SqliteResult };
 
-use std::path::PathBuf;
+use std::path::{ Path, PathBuf };
 use std::sync::mpsc;
 use std::thread;
+use std::time::Duration;
 
 use chunk::Chunk;
 use chunk_manager::{ ChunkState, ChunkStates };
+use chunk_store::{ ChunkLoadError, ChunkStore, ChunkWriteError, StoreKind, checksum, copy_chunks, encode_blocks, open_store };
+use decoration::{ PendingEdits, edits_from_bytes, edits_to_bytes };
+use inventory::Inventory;
+use player::{ Camera, Player };
+use player_edits::PlayerEdits;
+use preset::{ WorldPreset, DEFAULT_PRESET, find_preset };
+use structures::StructureStart;
+use world_generator::GeneratorRegistry;
 use math::*;
-use utils::SETTINGS_MUT;
+use random::random_seed;
+use utils::{ SETTINGS, SETTINGS_MUT };
+use world_meta::{ GameMode, WorldMeta };
 
 pub enum Request {
     Load(ChunkCoord),
     Save(ChunkCoord, Box<Chunk>),
+    /// Replaces every stored pending edit with this set.
+    SavePendingEdits(Vec<(ChunkCoord, Vec<u8>)>),
+    SaveStructures(Vec<StructureStart>),
+    /// Replaces every stored player edit with this set.
+    SavePlayerEdits(Vec<(ChunkCoord, Vec<u8>)>),
+    /// Loads a chunk and sends it back on the given channel, rather than the usual one.
+    LoadNow(ChunkCoord, mpsc::Sender<Result<Box<Chunk>, ChunkLoadError>>),
+    /// Moves a chunk that couldn't be loaded out of the way, see `ChunkStore::quarantine`.
+    Quarantine(ChunkCoord, String),
+    SaveWorldMeta(WorldMeta),
+    SavePlayer(Player),
     Close,
 }
 
-type Response = (ChunkCoord, Box<Chunk>);
+type Response = (ChunkCoord, Result<Box<Chunk>, ChunkLoadError>);
 
-const DATABASE_INITIALIZER: &str = r"
-    CREATE TABLE chunks (
-        x           INTEGER NOT NULL,
-        z           INTEGER NOT NULL,
-        block_data  BLOB NOT NULL,
-        PRIMARY KEY(x, z)
-    );
-";
+/// The steps that build the save database, in order. A save at schema version `n` has had the
+/// first `n` applied. Never change one that has been released, add a new one to the end.
+const MIGRATIONS: &[Migration] = &[
+    // 1: the chunks themselves.
+    Migration::Sql(r"
+        CREATE TABLE IF NOT EXISTS chunks (
+            x           INTEGER NOT NULL,
+            z           INTEGER NOT NULL,
+            block_data  BLOB NOT NULL,
+            PRIMARY KEY(x, z)
+        );
+    "),
+    // 2: the seed and preset.
+    Migration::Sql(r"
+        CREATE TABLE IF NOT EXISTS world_meta (
+            key         TEXT NOT NULL PRIMARY KEY,
+            value       TEXT NOT NULL
+        );
+    "),
+    // 3: biome maps.
+    Migration::Sql(r"
+        ALTER TABLE chunks ADD COLUMN biome_data BLOB;
+    "),
+    // 4: decorations waiting for their chunk to be generated.
+    Migration::Sql(r"
+        CREATE TABLE IF NOT EXISTS pending_edits (
+            x           INTEGER NOT NULL,
+            z           INTEGER NOT NULL,
+            edit_data   BLOB NOT NULL,
+            PRIMARY KEY(x, z)
+        );
+    "),
+    // 5: which generator made each chunk.
+    Migration::Sql(r"
+        ALTER TABLE chunks ADD COLUMN generator TEXT;
+        ALTER TABLE chunks ADD COLUMN generator_version INTEGER;
+    "),
+    // 6: placed structures.
+    Migration::Sql(r"
+        CREATE TABLE IF NOT EXISTS structures (
+            name        TEXT NOT NULL,
+            x           INTEGER NOT NULL,
+            y           INTEGER NOT NULL,
+            z           INTEGER NOT NULL,
+            rotation    INTEGER NOT NULL,
+            mirrored    INTEGER NOT NULL,
+            PRIMARY KEY(name, x, y, z)
+        );
+    "),
+    // 7: blocks the player placed or broke.
+    Migration::Sql(r"
+        CREATE TABLE IF NOT EXISTS player_edits (
+            x           INTEGER NOT NULL,
+            z           INTEGER NOT NULL,
+            edit_data   BLOB NOT NULL,
+            PRIMARY KEY(x, z)
+        );
+    "),
+    // 8: a format header on every chunk blob.
+    Migration::Code(add_chunk_blob_headers),
+    // 9: checksums, and somewhere to put chunks that fail them.
+    Migration::Sql(r"
+        ALTER TABLE chunks ADD COLUMN checksum INTEGER;
+        CREATE TABLE IF NOT EXISTS quarantine (
+            x                   INTEGER NOT NULL,
+            z                   INTEGER NOT NULL,
+            block_data          BLOB,
+            biome_data          BLOB,
+            generator           TEXT,
+            generator_version   INTEGER,
+            checksum            INTEGER,
+            reason              TEXT NOT NULL
+        );
+    "),
+    // 10: checksums for the chunks already saved.
+    Migration::Code(add_chunk_checksums),
+    // 11: where the player is and what they're carrying. There's only ever one row, with id 0.
+    Migration::Sql(r"
+        CREATE TABLE IF NOT EXISTS player (
+            id          INTEGER NOT NULL PRIMARY KEY,
+            x           REAL NOT NULL,
+            y           REAL NOT NULL,
+            z           REAL NOT NULL,
+            h_angle     REAL NOT NULL,
+            v_angle     REAL NOT NULL,
+            inventory   BLOB NOT NULL
+        );
+    "),
+];
+
+const SCHEMA_VERSION: usize = 11;
 
+enum Migration {
+    Sql(&'static str),
+    Code(fn(&Connection) -> SqliteResult<()>),
+}
 
+/// How long the database thread waits before trying a failed write again.
+const RETRY_DELAY_MS: u64 = 500;
+/// How many more times writes are tried once the game is closing, before they're given up on.
+const ATTEMPTS_AFTER_CLOSE: u32 = 3;
+/// How many times a write that keeps failing in a way that might go away is tried, before it's
+/// given up on so that the writes queued behind it can go through.
+const MAX_WRITE_ATTEMPTS: u32 = 20;
 
 pub struct ChunkLoader {
     // An option because we need to be able to move the thread handle when
@@ -38,123 +153,1074 @@ pub struct ChunkLoader {
 
     rx_resp: mpsc::Receiver<Response>,
     tx_req: mpsc::Sender<Request>,
+
+    world_meta: WorldMeta,
+    preset: WorldPreset,
+    /// The player as they were when the game last closed, if they've played before.
+    player: Option<Player>,
+    outdated_chunks: Vec<ChunkCoord>,
+    structures: Vec<StructureStart>,
 }
 
 impl ChunkLoader {
-    pub fn new(path: PathBuf) -> (ChunkLoader, ChunkStates) {
+    pub fn new(path: PathBuf) -> (ChunkLoader, ChunkStates, PendingEdits, PlayerEdits) {
         let (tx_req, rx_req) = mpsc::channel();
         let (tx_resp, rx_resp) = mpsc::channel();
 
-        let conn = Connection::open(&path).unwrap();
-        init_database(&conn);
-        let chunk_states = get_chunk_states(&conn);
+        let mut conn = Connection::open(&path).unwrap();
+        // Writes go to a log first, so a crash part way through an autosave leaves the save as
+        // it was before it.
+        conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;").unwrap();
+        init_database(&mut conn);
+        let preset = load_or_create_preset(&conn);
+        let world_meta = load_or_create_world_meta(&conn, &preset);
+        let player = load_player(&conn);
+        let mut store = open_store(world_meta.storage, &path)
+            .unwrap_or_else(|e| panic!("Failed to open the world's {} chunk store: {}", world_meta.storage.name(), e));
+        let (chunk_states, outdated_chunks) = get_saved_chunks(&mut *store, &GeneratorRegistry::new());
+        let pending_edits = get_pending_edits(&conn);
+        let player_edits = get_player_edits(&conn);
+        let structures = get_structures(&conn);
+        if !outdated_chunks.is_empty() {
+            println!("{} saved chunks were made by an older world generator", outdated_chunks.len());
+        }
         let thread_handle = thread::spawn(move || {
-            database_handler(conn, rx_req, tx_resp);
+            database_handler(conn, store, rx_req, tx_resp);
         });
 
         let chunk_loader = ChunkLoader {
             tx_req,
             rx_resp,
             thread_handle: Some(thread_handle),
+            world_meta,
+            preset,
+            player,
+            outdated_chunks,
+            structures,
         };
 
-        (chunk_loader, chunk_states)
+        (chunk_loader, chunk_states, pending_edits, player_edits)
     }
 
     pub fn enqueue_unload(&mut self, coord: ChunkCoord, chunk: Box<Chunk>) {
-        self.tx_req.send(Request::Save(coord, chunk)).unwrap();
+        self.send(Request::Save(coord, chunk));
     }
 
+    /// The chunk, or why it couldn't be loaded, comes back through `iter_loaded`.
     pub fn enqueue_load(&mut self, coord: ChunkCoord) {
-        self.tx_req.send(Request::Load(coord)).unwrap();
+        self.send(Request::Load(coord));
+    }
+
+    pub fn save_pending_edits(&mut self, pending_edits: &PendingEdits) {
+        let edits = pending_edits.iter()
+            .map(|(coord, edits)| (coord, edits_to_bytes(edits)))
+            .collect();
+        self.send(Request::SavePendingEdits(edits));
+    }
+
+    pub fn save_player_edits(&mut self, player_edits: &PlayerEdits) {
+        let edits = player_edits.iter()
+            .map(|(coord, edits)| (coord, edits_to_bytes(edits)))
+            .collect();
+        self.send(Request::SavePlayerEdits(edits));
+    }
+
+    /// Reads a chunk from the save, waiting for it rather than sending it through
+    /// `iter_loaded`. Anything queued before it is written first.
+    pub fn load_now(&mut self, coord: ChunkCoord) -> Result<Box<Chunk>, ChunkLoadError> {
+        self.load_later(coord).recv().unwrap_or_else(|_| Err(database_stopped()))
+    }
+
+    /// Reads a chunk from the save, like `load_now`, but leaves waiting for it to the caller.
+    /// The receiver hangs up without sending anything if the database thread has stopped.
+    pub fn load_later(&mut self, coord: ChunkCoord) -> mpsc::Receiver<Result<Box<Chunk>, ChunkLoadError>> {
+        let (tx, rx) = mpsc::channel();
+        self.send(Request::LoadNow(coord, tx));
+        rx
+    }
+
+    /// Moves a chunk out of the way, so it isn't loaded again. The SQLite store keeps it in
+    /// the quarantine table to be looked at later, the region store in a quarantine directory.
+    pub fn quarantine(&mut self, coord: ChunkCoord, reason: String) {
+        self.send(Request::Quarantine(coord, reason));
+    }
+
+    /// Forgets that a chunk is outdated, once it's been regenerated.
+    pub fn mark_regenerated(&mut self, coord: ChunkCoord) {
+        self.outdated_chunks.retain(|&c| c != coord);
+    }
+
+    pub fn save_structures(&mut self, structures: Vec<StructureStart>) {
+        self.send(Request::SaveStructures(structures));
     }
 
     pub fn iter_loaded(&mut self) -> ResponseIter {
         ResponseIter(self.rx_resp.try_iter())
     }
+
+    /// The seed this world was created with.
+    pub fn seed(&self) -> u32 {
+        self.world_meta.seed
+    }
+
+    pub fn world_meta(&self) -> &WorldMeta {
+        &self.world_meta
+    }
+
+    pub fn save_world_meta(&mut self, world_meta: WorldMeta) {
+        self.world_meta = world_meta.clone();
+        self.send(Request::SaveWorldMeta(world_meta));
+    }
+
+    /// The player as they were last saved, `None` in a new world.
+    pub fn saved_player(&self) -> Option<&Player> {
+        self.player.as_ref()
+    }
+
+    pub fn save_player(&mut self, player: &Player) {
+        self.player = Some(player.clone());
+        self.send(Request::SavePlayer(player.clone()));
+    }
+
+    /// The preset this world was created with.
+    pub fn preset(&self) -> &WorldPreset {
+        &self.preset
+    }
+
+    /// Chunks that were in the save at startup and were made by an older version of their
+    /// generator, or before generators were recorded.
+    pub fn outdated_chunks(&self) -> &[ChunkCoord] {
+        &self.outdated_chunks
+    }
+
+    /// Every structure placed in the world so far.
+    pub fn structures(&self) -> &[StructureStart] {
+        &self.structures
+    }
+
+    fn send(&self, request: Request) {
+        if self.tx_req.send(request).is_err() {
+            error!("The save database thread has stopped, a request to it was dropped");
+        }
+    }
+}
+
+/// What a chunk read from the save fails with once the database thread is gone.
+pub fn database_stopped() -> ChunkLoadError {
+    ChunkLoadError::Database("the save database thread has stopped".to_string())
 }
 
 impl Drop for ChunkLoader {
     fn drop(&mut self) {
-        self.tx_req.send(Request::Close).unwrap();
-        self.thread_handle.take().unwrap().join();
+        self.send(Request::Close);
+        if self.thread_handle.take().unwrap().join().is_err() {
+            error!("The save database thread panicked");
+        }
+    }
+}
+
+/// Moves the chunks of the save at `path` into a store of another kind, and records that the
+/// world is kept there from now on. The old store is only emptied once every chunk is in the
+/// new one, so a conversion that fails part way leaves the world where it was. Returns how many
+/// chunks were moved.
+pub fn convert_storage(path: &Path, target: StoreKind) -> Result<usize, String> {
+    let mut conn = Connection::open(path).map_err(|e| format!("failed to open {:?}: {}", path, e))?;
+    init_database(&mut conn);
+    let preset = load_or_create_preset(&conn);
+    let mut world_meta = load_or_create_world_meta(&conn, &preset);
+    if world_meta.storage == target {
+        return Err(format!("the world's chunks are already kept in {}", target.name()));
+    }
+    let mut from = open_store(world_meta.storage, path)?;
+    let mut to = open_store(target, path)?;
+
+    // Anything left behind by an earlier conversion that didn't finish would otherwise turn up
+    // in the world.
+    let leftovers = to.list()?;
+    to.begin()?;
+    for (coord, _) in leftovers {
+        to.delete(coord)?;
+    }
+    to.commit()?;
+
+    let moved = copy_chunks(&mut *from, &mut *to)?;
+    let coords: Vec<_> = from.list()?.into_iter().map(|(coord, _)| coord).collect();
+    let copied: FnvHashSet<_> = to.list()?.into_iter().map(|(coord, _)| coord).collect();
+    if let Some(coord) = coords.iter().find(|coord| !copied.contains(*coord)) {
+        return Err(format!("chunk {} is missing from the new store after copying", coord));
+    }
+
+    world_meta.storage = target;
+    write_world_meta(&conn, &world_meta).map_err(|e| format!("failed to record the new store: {}", e))?;
+
+    // The world is kept in the new store from here, so if this fails the old copies are only
+    // wasted space.
+    from.begin()?;
+    for coord in coords {
+        from.delete(coord)?;
+    }
+    from.commit()?;
+    Ok(moved)
+}
+
+/// Brings the save up to `SCHEMA_VERSION`, or creates it if it's new. Either every migration
+/// needed is applied or, if one fails, none of them are.
+pub fn init_database(conn: &mut Connection) {
+    assert!(MIGRATIONS.len() == SCHEMA_VERSION);
+    let trans = conn.transaction().unwrap();
+    let version = schema_version(&trans);
+    if version > SCHEMA_VERSION {
+        panic!("The save is from a newer version of the game, it has schema version {} but only {} is supported", version, SCHEMA_VERSION);
+    }
+    if version == 0 {
+        println!("Creating world tables");
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        if version != 0 {
+            println!("Migrating save to schema version {}", i + 1);
+        }
+        let result = match *migration {
+            Migration::Sql(sql) => trans.execute_batch(sql),
+            Migration::Code(apply) => apply(&trans),
+        };
+        if let Err(e) = result {
+            panic!("Failed to migrate the save to schema version {}: {}", i + 1, e);
+        }
+    }
+
+    trans.execute_batch("
+        CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL);
+        DELETE FROM schema_version;
+    ").unwrap();
+    trans.execute("INSERT INTO schema_version (version) VALUES (?)", &[&(SCHEMA_VERSION as i64)]).unwrap();
+    trans.commit().unwrap();
+}
+
+fn schema_version(conn: &Connection) -> usize {
+    if !table_exists(conn, "schema_version") {
+        return legacy_schema_version(conn);
+    }
+    let version: i64 = conn.query_row("SELECT version FROM schema_version", &[], |row| row.get(0)).unwrap();
+    version as usize
+}
+
+/// Works out the version of a save from before versions were recorded from the tables in it.
+/// Each step only ever added tables or columns, so the first one missing gives it away.
+fn legacy_schema_version(conn: &Connection) -> usize {
+    if !table_exists(conn, "chunks") {
+        0
+    } else if !table_exists(conn, "world_meta") {
+        1
+    } else if !column_exists(conn, "chunks", "biome_data") {
+        2
+    } else if !table_exists(conn, "pending_edits") {
+        3
+    } else if !column_exists(conn, "chunks", "generator") {
+        4
+    } else if !table_exists(conn, "structures") {
+        5
+    } else if !table_exists(conn, "player_edits") {
+        6
+    } else {
+        7
+    }
+}
+
+fn table_exists(conn: &Connection, table: &str) -> bool {
+    let count: i64 = conn.query_row(
+        "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = ?", &[&table], |row| row.get(0)
+    ).unwrap();
+    count > 0
+}
+
+fn column_exists(conn: &Connection, table: &str, column: &str) -> bool {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table)).unwrap();
+    let mut iter = stmt.query(&[]).unwrap();
+    while let Some(Ok(row)) = iter.next() {
+        let name: String = row.get(1);
+        if name == column {
+            return true;
+        }
+    }
+    false
+}
+
+/// Migration 8. Rewrites every chunk in the current block format with a header in front.
+fn add_chunk_blob_headers(conn: &Connection) -> SqliteResult<()> {
+    let mut coords = Vec::new();
+    {
+        let mut stmt = conn.prepare("SELECT x, z FROM chunks")?;
+        let mut iter = stmt.query(&[])?;
+        while let Some(row) = iter.next() {
+            let row = row?;
+            coords.push(ChunkCoord::new(row.get(0), row.get(1)));
+        }
+    }
+
+    let mut update = conn.prepare("UPDATE chunks SET block_data = :block_data WHERE x = :x AND z = :z")?;
+    for coord in coords {
+        let compressed: Vec<u8> = conn.query_row(
+            "SELECT block_data FROM chunks WHERE x = ? AND z = ?", &[&coord.x, &coord.z], |row| row.get(0)
+        )?;
+        let chunk = inflate_bytes_zlib(&compressed).and_then(|block_data| Chunk::from_headerless_bytes(&block_data));
+        match chunk {
+            Ok(chunk) => {
+                update.execute_named(&[
+                    (":block_data", &encode_blocks(&chunk)),
+                    (":x", &coord.x),
+                    (":z", &coord.z)
+                ])?;
+            }
+            Err(e) => warn!("Chunk {} in the save is corrupt and was left as it is: {}", coord, e),
+        }
+    }
+    Ok(())
+}
+
+/// Migration 10. Fills in the checksum of every chunk saved before there were checksums.
+fn add_chunk_checksums(conn: &Connection) -> SqliteResult<()> {
+    let mut rows = Vec::new();
+    {
+        let mut stmt = conn.prepare("SELECT x, z, block_data, biome_data FROM chunks")?;
+        let mut iter = stmt.query(&[])?;
+        while let Some(row) = iter.next() {
+            let row = row?;
+            let block_data: Vec<u8> = row.get(2);
+            let biome_data: Option<Vec<u8>> = row.get(3);
+            let checksum = checksum(&block_data, biome_data.as_ref().map_or(&[][..], |data| &data[..]));
+            rows.push((ChunkCoord::new(row.get(0), row.get(1)), checksum));
+        }
+    }
+
+    let mut update = conn.prepare("UPDATE chunks SET checksum = :checksum WHERE x = :x AND z = :z")?;
+    for (coord, checksum) in rows {
+        update.execute_named(&[(":checksum", &checksum), (":x", &coord.x), (":z", &coord.z)])?;
+    }
+    Ok(())
+}
+
+fn get_meta(conn: &Connection, key: &str) -> Option<String> {
+    match conn.query_row("SELECT value FROM world_meta WHERE key = ?", &[&key], |row| row.get(0)) {
+        Ok(value) => Some(value),
+        Err(SqliteError::QueryReturnedNoRows) => None,
+        Err(e) => panic!("Failed to read world metadata {:?}: {}", key, e),
+    }
+}
+
+fn set_meta(conn: &Connection, key: &str, value: &str) -> SqliteResult<()> {
+    conn.execute("INSERT OR REPLACE INTO world_meta (key, value) VALUES (?, ?)", &[&key, &value])?;
+    Ok(())
+}
+
+/// Returns the seed stored in the save, or picks one and stores it if this is a new world.
+fn load_or_create_seed(conn: &Connection) -> u32 {
+    if let Some(seed) = get_meta(conn, "seed") {
+        return seed.parse().expect("World seed is not a valid number");
+    }
+
+    let seed = SETTINGS.world_seed.unwrap_or_else(random_seed);
+    println!("Creating world with seed {}", seed);
+    set_meta(conn, "seed", &seed.to_string()).unwrap();
+    seed
+}
+
+/// Returns the preset stored in the save, or picks one from the settings and stores it if this
+/// is a new world. The whole definition is stored rather than just the name, so editing
+/// `presets.toml` doesn't change how existing worlds generate.
+fn load_or_create_preset(conn: &Connection) -> WorldPreset {
+    if let Some(definition) = get_meta(conn, "preset_definition") {
+        return WorldPreset::from_toml(&definition).expect("World preset is corrupt");
     }
+
+    let name = SETTINGS.world_preset.clone().unwrap_or_else(|| DEFAULT_PRESET.to_string());
+    let preset = find_preset(&name);
+    println!("Creating world with preset {:?}", preset.name);
+    set_meta(conn, "preset", &preset.name).unwrap();
+    set_meta(conn, "preset_definition", &preset.to_toml()).unwrap();
+    preset
+}
+
+/// Reads the world's metadata. Anything a save from before it was recorded is missing is filled
+/// in: the generator from the preset, the game mode from the settings, the time from zero, and
+/// the chunk store from the settings for a new world or as SQLite for an old one.
+/// The spawn point is left for the caller to choose.
+fn load_or_create_world_meta(conn: &Connection, preset: &WorldPreset) -> WorldMeta {
+    let saved_chunks: i64 = conn.query_row("SELECT count(*) FROM chunks", &[], |row| row.get(0)).unwrap();
+    let new_world = get_meta(conn, "seed").is_none() && saved_chunks == 0;
+    let storage = match get_meta(conn, "chunk_store") {
+        // Opening the wrong store would look like an empty world, and new chunks would be
+        // generated over the real ones.
+        Some(name) => StoreKind::from_name(&name)
+            .unwrap_or_else(|| panic!("The save keeps its chunks in an unknown store {:?}", name)),
+        None if !new_world => StoreKind::Sqlite,
+        None => {
+            let name = SETTINGS.chunk_store.clone().unwrap_or_else(|| "sqlite".to_string());
+            StoreKind::from_name(&name).unwrap_or_else(|| {
+                warn!("No chunk store called {:?}, using sqlite.", name);
+                StoreKind::Sqlite
+            })
+        }
+    };
+    let game_mode = match get_meta(conn, "game_mode") {
+        Some(name) => GameMode::from_name(&name).unwrap_or_else(|| {
+            warn!("The save has an unknown game mode {:?}, using creative.", name);
+            GameMode::Creative
+        }),
+        None => {
+            let name = SETTINGS.game_mode.clone().unwrap_or_else(|| "creative".to_string());
+            GameMode::from_name(&name).unwrap_or_else(|| {
+                warn!("No game mode called {:?}, using creative.", name);
+                GameMode::Creative
+            })
+        }
+    };
+    let world_meta = WorldMeta {
+        seed: load_or_create_seed(conn),
+        generator: get_meta(conn, "generator").unwrap_or_else(|| preset.terrain.clone()),
+        spawn: get_meta(conn, "spawn").and_then(|spawn| parse_coord(&spawn)),
+        time: get_meta(conn, "time").and_then(|time| time.parse().ok()).unwrap_or(0),
+        game_mode,
+        storage,
+    };
+    write_world_meta(conn, &world_meta).unwrap();
+    world_meta
 }
 
-fn init_database(conn: &Connection) {
-    let num_tables: i64 = conn.query_row("SELECT count(*) FROM SQLITE_MASTER", &[], |row| row.get(0)).unwrap();
-    if num_tables == 0 {
-        println!("Creating chunks table");
-        conn.execute_batch(DATABASE_INITIALIZER).unwrap();
+fn write_world_meta(conn: &Connection, world_meta: &WorldMeta) -> SqliteResult<()> {
+    set_meta(conn, "seed", &world_meta.seed.to_string())?;
+    set_meta(conn, "generator", &world_meta.generator)?;
+    if let Some(spawn) = world_meta.spawn {
+        set_meta(conn, "spawn", &format!("{},{},{}", spawn.x, spawn.y, spawn.z))?;
+    }
+    set_meta(conn, "time", &world_meta.time.to_string())?;
+    set_meta(conn, "game_mode", world_meta.game_mode.name())?;
+    set_meta(conn, "chunk_store", world_meta.storage.name())
+}
+
+/// Reads a coordinate written as `x,y,z`.
+fn parse_coord(string: &str) -> Option<Coord> {
+    let parts: Vec<i32> = string.split(',').filter_map(|part| part.trim().parse().ok()).collect();
+    if parts.len() == 3 {
+        Some(Coord::new(parts[0], parts[1], parts[2]))
     } else {
-        println!("Database had {} tables", num_tables);
+        warn!("The saved spawn point {:?} is corrupt and will be chosen again.", string);
+        None
     }
 }
 
-fn get_chunk_states(conn: &Connection) -> ChunkStates {
-    let mut stmt = conn.prepare("SELECT x, z FROM chunks").unwrap();
-    let mut result = ChunkStates::new();
+fn load_player(conn: &Connection) -> Option<Player> {
+    let result = conn.query_row(
+        "SELECT x, y, z, h_angle, v_angle, inventory FROM player WHERE id = 0", &[],
+        |row| -> (Vec<f64>, Vec<u8>) { ((0..5).map(|i| row.get(i)).collect(), row.get(5)) }
+    );
+    let (numbers, inventory) = match result {
+        Ok(row) => row,
+        Err(SqliteError::QueryReturnedNoRows) => return None,
+        Err(e) => panic!("Failed to load the player: {}", e),
+    };
+    let inventory = Inventory::from_bytes(&inventory).unwrap_or_else(|e| {
+        warn!("The player's inventory is corrupt and was emptied: {}", e);
+        Inventory::new()
+    });
+    Some(Player {
+        camera: Camera {
+            pos: Point3::new(numbers[0] as f32, numbers[1] as f32, numbers[2] as f32),
+            h_angle: Deg(numbers[3] as f32),
+            v_angle: Deg(numbers[4] as f32),
+        },
+        inventory,
+    })
+}
+
+fn write_player(conn: &Connection, player: &Player) -> SqliteResult<()> {
+    let camera = &player.camera;
+    conn.execute_named(
+        "INSERT OR REPLACE INTO player (id, x, y, z, h_angle, v_angle, inventory) VALUES (0, :x, :y, :z, :h_angle, :v_angle, :inventory)",
+        &[
+            (":x", &(camera.pos.x as f64)),
+            (":y", &(camera.pos.y as f64)),
+            (":z", &(camera.pos.z as f64)),
+            (":h_angle", &(camera.h_angle.0 as f64)),
+            (":v_angle", &(camera.v_angle.0 as f64)),
+            (":inventory", &player.inventory.to_bytes())
+        ]
+    )?;
+    Ok(())
+}
+
+/// Which chunks are in the store, and which of those an older generator made.
+fn get_saved_chunks(store: &mut ChunkStore, registry: &GeneratorRegistry) -> (ChunkStates, Vec<ChunkCoord>) {
+    let saved = store.list().unwrap_or_else(|e| panic!("Failed to list the saved chunks: {}", e));
+    let mut chunk_states = ChunkStates::new();
+    let mut outdated_chunks = Vec::new();
+    for (coord, stamp) in saved {
+        chunk_states.set(coord, ChunkState::Saved);
+        if registry.is_outdated(stamp.as_ref()) {
+            outdated_chunks.push(coord);
+        }
+    }
+    (chunk_states, outdated_chunks)
+}
+
+fn get_pending_edits(conn: &Connection) -> PendingEdits {
+    let mut stmt = conn.prepare("SELECT x, z, edit_data FROM pending_edits").unwrap();
+    let mut result = PendingEdits::new();
     let mut iter = stmt.query(&[]).unwrap();
     while let Some(Ok(row)) = iter.next() {
-        let x = row.get(0);
-        let z = row.get(1);
-        result.set(ChunkCoord::new(x, z), ChunkState::Saved);
+        let coord = ChunkCoord::new(row.get(0), row.get(1));
+        let edit_data: Vec<u8> = row.get(2);
+        match edits_from_bytes(&edit_data) {
+            Ok(edits) => {
+                for edit in edits {
+                    result.add(coord, edit);
+                }
+            }
+            Err(e) => warn!("The decorations waiting for chunk {} are corrupt and were skipped: {}", coord, e),
+        }
     }
     result
 }
 
-fn database_handler(mut conn: Connection, rx: mpsc::Receiver<Request>, tx: mpsc::Sender<Response>) {
+fn get_player_edits(conn: &Connection) -> PlayerEdits {
+    let mut stmt = conn.prepare("SELECT x, z, edit_data FROM player_edits").unwrap();
+    let mut result = PlayerEdits::new();
+    let mut iter = stmt.query(&[]).unwrap();
+    while let Some(Ok(row)) = iter.next() {
+        let coord = ChunkCoord::new(row.get(0), row.get(1));
+        let edit_data: Vec<u8> = row.get(2);
+        match edits_from_bytes(&edit_data) {
+            Ok(edits) => {
+                for edit in edits {
+                    result.record(coord, edit);
+                }
+            }
+            Err(e) => warn!("The player's edits to chunk {} are corrupt and were skipped: {}", coord, e),
+        }
+    }
+    result
+}
+
+fn get_structures(conn: &Connection) -> Vec<StructureStart> {
+    let mut stmt = conn.prepare("SELECT name, x, y, z, rotation, mirrored FROM structures").unwrap();
+    let mut result = Vec::new();
+    let mut iter = stmt.query(&[]).unwrap();
+    while let Some(Ok(row)) = iter.next() {
+        let rotation: i64 = row.get(4);
+        result.push(StructureStart {
+            name: row.get(0),
+            pos: Coord::new(row.get(1), row.get(2), row.get(3)),
+            rotation: rotation as u8,
+            mirrored: row.get(5),
+        });
+    }
+    result
+}
+
+/// A request waiting on the database thread, with how many times writing it has failed.
+struct Queued {
+    request: Request,
+    failures: u32,
+}
+
+fn database_handler(mut conn: Connection, mut store: Box<ChunkStore + Send>, rx: mpsc::Receiver<Request>, tx: mpsc::Sender<Response>) {
     // conn.blob_open(DatabaseName::Main, "chunks", "block_data", 0, false);
 
     let mut requests = Vec::new();
-    let mut done = false;
-    while !done {
+    let mut closing = false;
+    let mut attempts_after_close = 0;
+    loop {
         for request in rx.try_iter() {
             if let Request::Close = request {
-                done = true;
+                closing = true;
                 break;
             } else {
-                requests.push(request);
+                requests.push(Queued { request, failures: 0 });
             }
         }
 
-        let trans = conn.transaction().unwrap();
-        for req in requests.drain(..) {
-            match req {
-                Request::Load(coord) => {
-                    let mut load_stmt = trans.prepare_cached("SELECT block_data FROM chunks WHERE x = :x AND z = :z").unwrap();
-                    //conn.blob_open(DatabaseName::Main, "chunks", "block_data", row, true)
-                    let compressed_block_data: Vec<u8> = load_stmt.query_row(
-                        &[&coord.x, &coord.z],
-                        |row| row.get(0)
-                    ).unwrap();
-                    let block_data = inflate_bytes_zlib(&compressed_block_data).unwrap();
-                    let chunk = Chunk::from_bytes(&block_data);
-                    tx.send((coord, chunk)).unwrap();
+        // Anything that couldn't be written is left in `requests` to try again, so a locked
+        // database or a full disk holds the writes up rather than losing them.
+        if let Err(e) = handle_requests(&mut conn, &mut *store, &mut requests, &tx) {
+            if closing {
+                attempts_after_close += 1;
+                if attempts_after_close >= ATTEMPTS_AFTER_CLOSE {
+                    error!("Failed to write to the save, giving up on {} changes: {}", requests.len(), e);
+                    break;
                 }
-                Request::Save(coord, chunk) => {
-                    let mut store_stmt = trans.prepare_cached("INSERT OR REPLACE INTO chunks (x, z, block_data) VALUES (:x, :z, :block_data)").unwrap();
-
-                    let block_data = chunk.to_bytes();
-                    let compressed_block_data = deflate_bytes_zlib(&block_data);
-                    store_stmt.execute_named(&[
-                        (":x", &coord.x),
-                        (":z", &coord.z),
-                        (":block_data", &compressed_block_data)
-                    ]).unwrap();
+            }
+            error!("Failed to write to the save, trying again: {}", e);
+            thread::sleep(Duration::from_millis(RETRY_DELAY_MS));
+        }
+
+        if closing && requests.is_empty() {
+            break;
+        }
+    }
+}
+
+/// Handles a batch of requests. Loads are always answered. Chunk writes go to the store in one
+/// batch, then everything else goes to the save in one transaction, one after the other so the
+/// two never wait on each other's locks when the store is the save itself. If a write fails,
+/// none of its batch is kept and all of that batch is put back in `requests`, except the write
+/// that failed if it's failed for good. A failure to start or finish the batch counts against
+/// every write in it.
+fn handle_requests(conn: &mut Connection, store: &mut ChunkStore, requests: &mut Vec<Queued>, tx: &mpsc::Sender<Response>) -> Result<(), ChunkWriteError> {
+    let mut chunk_writes = Vec::new();
+    let mut writes = Vec::new();
+    let mut error = store.begin().err();
+    let mut failed = None;
+    for Queued { request, failures } in requests.drain(..) {
+        match request {
+            // Nobody is left to answer if the game has stopped listening.
+            Request::Load(coord) => {
+                let _ = tx.send((coord, store.load(coord)));
+            }
+            Request::LoadNow(coord, reply) => {
+                let _ = reply.send(store.load(coord));
+            }
+            Request::Close => unreachable!(),
+            write @ Request::Save(..) | write @ Request::Quarantine(..) => {
+                if error.is_none() {
+                    error = write_chunk_request(store, &write).err();
+                    if error.is_some() {
+                        failed = Some(chunk_writes.len());
+                    }
                 }
-                Request::Close => unreachable!(),
+                chunk_writes.push(Queued { request: write, failures });
+            }
+            write => writes.push(Queued { request: write, failures }),
+        }
+    }
+
+    let result = match error {
+        Some(e) => Err(e),
+        None => store.commit(),
+    };
+    if let Err(e) = result {
+        store.rollback();
+        count_failure(&mut chunk_writes, failed, &e);
+        chunk_writes.extend(writes);
+        *requests = chunk_writes;
+        return Err(e);
+    }
+
+    if let Err((failed, e)) = write_requests(conn, &writes) {
+        let e = ChunkWriteError::sqlite(e);
+        count_failure(&mut writes, failed, &e);
+        *requests = writes;
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Counts a failure against the write at `failed`, or against all of `writes` when it was the
+/// batch that failed rather than one write, and drops those that have failed for good or too
+/// many times.
+fn count_failure(writes: &mut Vec<Queued>, failed: Option<usize>, error: &ChunkWriteError) {
+    let (start, end) = match failed {
+        Some(i) => (i, i + 1),
+        None => (0, writes.len()),
+    };
+    for i in (start..end).rev() {
+        writes[i].failures += 1;
+        if !error.is_transient() || writes[i].failures >= MAX_WRITE_ATTEMPTS {
+            let write = writes.remove(i);
+            error!("Giving up on {} after {} attempts: {}", describe_write(&write.request), write.failures, error);
+        }
+    }
+}
+
+fn describe_write(request: &Request) -> String {
+    match *request {
+        Request::Save(coord, _) => format!("saving chunk {}", coord),
+        Request::Quarantine(coord, _) => format!("quarantining chunk {}", coord),
+        Request::SavePendingEdits(_) => "saving pending edits".to_string(),
+        Request::SaveStructures(_) => "saving structures".to_string(),
+        Request::SavePlayerEdits(_) => "saving player edits".to_string(),
+        Request::SaveWorldMeta(_) => "saving the world's metadata".to_string(),
+        Request::SavePlayer(_) => "saving the player".to_string(),
+        Request::Load(_) | Request::LoadNow(..) | Request::Close => unreachable!(),
+    }
+}
+
+fn write_chunk_request(store: &mut ChunkStore, request: &Request) -> Result<(), ChunkWriteError> {
+    match *request {
+        Request::Save(coord, ref chunk) => store.save(coord, chunk),
+        Request::Quarantine(coord, ref reason) => store.quarantine(coord, reason),
+        _ => unreachable!(),
+    }
+}
+
+/// Writes `requests` in one transaction. If one of them fails, its index comes back with the
+/// error.
+fn write_requests(conn: &mut Connection, requests: &[Queued]) -> Result<(), (Option<usize>, SqliteError)> {
+    if requests.is_empty() {
+        return Ok(());
+    }
+    let trans = conn.transaction().map_err(|e| (None, e))?;
+    for (i, queued) in requests.iter().enumerate() {
+        write_request(&trans, &queued.request).map_err(|e| (Some(i), e))?;
+    }
+    trans.commit().map_err(|e| (None, e))
+}
+
+fn write_request(conn: &Connection, request: &Request) -> SqliteResult<()> {
+    match *request {
+        Request::SavePendingEdits(ref edits) => replace_edits(conn, "pending_edits", edits),
+        Request::SavePlayerEdits(ref edits) => replace_edits(conn, "player_edits", edits),
+        Request::SaveStructures(ref structures) => {
+            let mut store_stmt = conn.prepare_cached("INSERT OR REPLACE INTO structures (name, x, y, z, rotation, mirrored) VALUES (:name, :x, :y, :z, :rotation, :mirrored)")?;
+            for structure in structures {
+                store_stmt.execute_named(&[
+                    (":name", &structure.name),
+                    (":x", &structure.pos.x),
+                    (":y", &structure.pos.y),
+                    (":z", &structure.pos.z),
+                    (":rotation", &(structure.rotation as i64)),
+                    (":mirrored", &structure.mirrored)
+                ])?;
             }
+            Ok(())
         }
-        trans.commit().unwrap();
+        Request::SaveWorldMeta(ref world_meta) => write_world_meta(conn, world_meta),
+        Request::SavePlayer(ref player) => write_player(conn, player),
+        Request::Save(..) | Request::Quarantine(..) |
+        Request::Load(_) | Request::LoadNow(..) | Request::Close => unreachable!(),
+    }
+}
+
+/// Replaces every row of `table`, one of the edit tables, with `edits`.
+fn replace_edits(conn: &Connection, table: &str, edits: &[(ChunkCoord, Vec<u8>)]) -> SqliteResult<()> {
+    conn.execute(&format!("DELETE FROM {}", table), &[])?;
+    let mut store_stmt = conn.prepare_cached(&format!("INSERT INTO {} (x, z, edit_data) VALUES (:x, :z, :edit_data)", table))?;
+    for &(coord, ref edit_data) in edits {
+        store_stmt.execute_named(&[
+            (":x", &coord.x),
+            (":z", &coord.z),
+            (":edit_data", edit_data)
+        ])?;
     }
+    Ok(())
 }
 
-pub struct ResponseIter<'a>(mpsc::TryIter<'a, (ChunkCoord, Box<Chunk>)>);
+pub struct ResponseIter<'a>(mpsc::TryIter<'a, Response>);
 
 impl<'a> Iterator for ResponseIter<'a> {
-    type Item = (ChunkCoord, Box<Chunk>);
+    type Item = Response;
     fn next(&mut self) -> Option<Self::Item> {
         self.0.next()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deflate::deflate_bytes_zlib;
+    use std::env;
+    use std::fs;
+    use std::io;
+    use block::{ Block, BlockType };
+    use chunk::CHUNK_BLOCK_COUNT;
+    use chunk_store::MemoryChunkStore;
+    use decoration::BlockEdit;
+    use inventory::Stack;
+    use sqlite_store::{ SqliteChunkStore, load_chunk, save_chunk };
+    use world_generator::GeneratorStamp;
+
+    /// The tables as each release before schema versions created them, oldest first.
+    const LEGACY_SCHEMAS: &[&str] = &[
+        // 1
+        "CREATE TABLE chunks (x INTEGER NOT NULL, z INTEGER NOT NULL, block_data BLOB NOT NULL, PRIMARY KEY(x, z));",
+        // 2
+        "CREATE TABLE chunks (x INTEGER NOT NULL, z INTEGER NOT NULL, block_data BLOB NOT NULL, PRIMARY KEY(x, z));
+         CREATE TABLE world_meta (key TEXT NOT NULL PRIMARY KEY, value TEXT NOT NULL);",
+        // 3
+        "CREATE TABLE chunks (x INTEGER NOT NULL, z INTEGER NOT NULL, block_data BLOB NOT NULL, biome_data BLOB, PRIMARY KEY(x, z));
+         CREATE TABLE world_meta (key TEXT NOT NULL PRIMARY KEY, value TEXT NOT NULL);",
+        // 4
+        "CREATE TABLE chunks (x INTEGER NOT NULL, z INTEGER NOT NULL, block_data BLOB NOT NULL, biome_data BLOB, PRIMARY KEY(x, z));
+         CREATE TABLE pending_edits (x INTEGER NOT NULL, z INTEGER NOT NULL, edit_data BLOB NOT NULL, PRIMARY KEY(x, z));
+         CREATE TABLE world_meta (key TEXT NOT NULL PRIMARY KEY, value TEXT NOT NULL);",
+        // 5
+        "CREATE TABLE chunks (x INTEGER NOT NULL, z INTEGER NOT NULL, block_data BLOB NOT NULL, biome_data BLOB,
+             generator TEXT, generator_version INTEGER, PRIMARY KEY(x, z));
+         CREATE TABLE pending_edits (x INTEGER NOT NULL, z INTEGER NOT NULL, edit_data BLOB NOT NULL, PRIMARY KEY(x, z));
+         CREATE TABLE world_meta (key TEXT NOT NULL PRIMARY KEY, value TEXT NOT NULL);",
+        // 6
+        "CREATE TABLE chunks (x INTEGER NOT NULL, z INTEGER NOT NULL, block_data BLOB NOT NULL, biome_data BLOB,
+             generator TEXT, generator_version INTEGER, PRIMARY KEY(x, z));
+         CREATE TABLE pending_edits (x INTEGER NOT NULL, z INTEGER NOT NULL, edit_data BLOB NOT NULL, PRIMARY KEY(x, z));
+         CREATE TABLE structures (name TEXT NOT NULL, x INTEGER NOT NULL, y INTEGER NOT NULL, z INTEGER NOT NULL,
+             rotation INTEGER NOT NULL, mirrored INTEGER NOT NULL, PRIMARY KEY(name, x, y, z));
+         CREATE TABLE world_meta (key TEXT NOT NULL PRIMARY KEY, value TEXT NOT NULL);",
+        // 7
+        "CREATE TABLE chunks (x INTEGER NOT NULL, z INTEGER NOT NULL, block_data BLOB NOT NULL, biome_data BLOB,
+             generator TEXT, generator_version INTEGER, PRIMARY KEY(x, z));
+         CREATE TABLE pending_edits (x INTEGER NOT NULL, z INTEGER NOT NULL, edit_data BLOB NOT NULL, PRIMARY KEY(x, z));
+         CREATE TABLE player_edits (x INTEGER NOT NULL, z INTEGER NOT NULL, edit_data BLOB NOT NULL, PRIMARY KEY(x, z));
+         CREATE TABLE structures (name TEXT NOT NULL, x INTEGER NOT NULL, y INTEGER NOT NULL, z INTEGER NOT NULL,
+             rotation INTEGER NOT NULL, mirrored INTEGER NOT NULL, PRIMARY KEY(name, x, y, z));
+         CREATE TABLE world_meta (key TEXT NOT NULL PRIMARY KEY, value TEXT NOT NULL);",
+    ];
+
+    /// A save from before schema versions, holding one chunk in the old one byte per block
+    /// format with a sponge at 1, 2, 3.
+    fn legacy_fixture(version: usize) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(LEGACY_SCHEMAS[version - 1]).unwrap();
+
+        let mut blocks = vec![0; CHUNK_BLOCK_COUNT];
+        blocks[2 + 3 * 128 + 128 * 16] = BlockType::Sponge as u8;
+        let block_data = deflate_bytes_zlib(&blocks);
+        conn.execute("INSERT INTO chunks (x, z, block_data) VALUES (4, -2, ?)", &[&block_data]).unwrap();
+        conn
+    }
+
+    #[test]
+    fn legacy_versions_are_recognised() {
+        assert_eq!(legacy_schema_version(&Connection::open_in_memory().unwrap()), 0);
+        for version in 1..LEGACY_SCHEMAS.len() + 1 {
+            assert_eq!(legacy_schema_version(&legacy_fixture(version)), version);
+        }
+    }
+
+    #[test]
+    fn every_legacy_version_migrates() {
+        for version in 1..LEGACY_SCHEMAS.len() + 1 {
+            let mut conn = legacy_fixture(version);
+            init_database(&mut conn);
+            assert_eq!(schema_version(&conn), SCHEMA_VERSION);
+
+            let chunk = load_chunk(&conn, ChunkCoord::new(4, -2)).unwrap();
+            assert_eq!(chunk.get(Coord::new(1, 2, 3)), Block::new(BlockType::Sponge));
+            assert!(chunk.generator().is_none());
+            let checksum: Option<i64> = conn.query_row("SELECT checksum FROM chunks", &[], |row| row.get(0)).unwrap();
+            assert!(checksum.is_some());
+
+            // Every table the game writes to is there.
+            for &table in ["chunks", "pending_edits", "player", "player_edits", "quarantine", "structures", "world_meta"].iter() {
+                assert!(table_exists(&conn, table), "no {} table after migrating from {}", table, version);
+            }
+            conn.execute("INSERT INTO chunks (x, z, block_data, biome_data, generator, generator_version) VALUES (0, 0, ?, NULL, 'noise', 1)", &[&encode_blocks(&chunk)]).unwrap();
+        }
+    }
+
+    #[test]
+    fn migrating_again_changes_nothing() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_database(&mut conn);
+        let mut chunk = Chunk::new();
+        chunk.set(Coord::new(0, 10, 0), Block::with_state(BlockType::Log, 1));
+        conn.execute("INSERT INTO chunks (x, z, block_data) VALUES (1, 1, ?)", &[&encode_blocks(&chunk)]).unwrap();
+
+        init_database(&mut conn);
+        assert_eq!(schema_version(&conn), SCHEMA_VERSION);
+        let loaded = load_chunk(&conn, ChunkCoord::new(1, 1)).unwrap();
+        assert!(loaded.to_bytes() == chunk.to_bytes());
+    }
+
+    #[test]
+    fn corrupt_chunks_dont_stop_the_database_thread() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_database(&mut conn);
+        let mut chunks_conn = Connection::open_in_memory().unwrap();
+        init_database(&mut chunks_conn);
+        save_chunk(&chunks_conn, ChunkCoord::new(0, 0), &Chunk::new()).unwrap();
+        chunks_conn.execute_batch("UPDATE chunks SET block_data = x'00'").unwrap();
+        let store = Box::new(SqliteChunkStore::new(chunks_conn));
+
+        let (tx_req, rx_req) = mpsc::channel();
+        let (tx_resp, rx_resp) = mpsc::channel();
+        let handle = thread::spawn(move || database_handler(conn, store, rx_req, tx_resp));
+
+        tx_req.send(Request::Load(ChunkCoord::new(0, 0))).unwrap();
+        let (coord, result) = rx_resp.recv().unwrap();
+        assert_eq!(coord, ChunkCoord::new(0, 0));
+        assert_eq!(result.err(), Some(ChunkLoadError::ChecksumMismatch));
+
+        let mut chunk = Chunk::new();
+        chunk.set(Coord::new(0, 0, 0), Block::new(BlockType::Bedrock));
+        tx_req.send(Request::Save(ChunkCoord::new(0, 0), chunk)).unwrap();
+        let (tx, rx) = mpsc::channel();
+        tx_req.send(Request::LoadNow(ChunkCoord::new(0, 0), tx)).unwrap();
+        assert_eq!(rx.recv().unwrap().unwrap().get(Coord::new(0, 0, 0)).ty, BlockType::Bedrock);
+
+        tx_req.send(Request::Close).unwrap();
+        handle.join().unwrap();
+    }
+
+    /// Keeps chunks in memory, but can never save the one at `broken`, or commit anything if
+    /// `failing_commits` is set.
+    struct BrokenStore {
+        chunks: MemoryChunkStore,
+        broken: ChunkCoord,
+        failing_commits: bool,
+    }
+
+    impl ChunkStore for BrokenStore {
+        fn load(&mut self, coord: ChunkCoord) -> Result<Box<Chunk>, ChunkLoadError> {
+            self.chunks.load(coord)
+        }
+
+        fn save(&mut self, coord: ChunkCoord, chunk: &Chunk) -> Result<(), ChunkWriteError> {
+            if coord == self.broken {
+                return Err(ChunkWriteError::Permanent("that chunk can't be written".to_string()));
+            }
+            self.chunks.save(coord, chunk)
+        }
+
+        fn list(&mut self) -> Result<Vec<(ChunkCoord, Option<GeneratorStamp>)>, String> {
+            self.chunks.list()
+        }
+
+        fn delete(&mut self, coord: ChunkCoord) -> Result<(), ChunkWriteError> {
+            self.chunks.delete(coord)
+        }
+
+        fn commit(&mut self) -> Result<(), ChunkWriteError> {
+            if self.failing_commits {
+                return Err(ChunkWriteError::Transient("the disk is busy".to_string()));
+            }
+            self.chunks.commit()
+        }
+    }
+
+    #[test]
+    fn writes_that_always_fail_are_given_up_on() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_database(&mut conn);
+        let broken = ChunkCoord::new(0, 0);
+        let store = Box::new(BrokenStore { chunks: MemoryChunkStore::new(), broken, failing_commits: false });
+
+        let (tx_req, rx_req) = mpsc::channel();
+        let (tx_resp, _rx_resp) = mpsc::channel();
+        let handle = thread::spawn(move || database_handler(conn, store, rx_req, tx_resp));
+
+        // Queued behind the write that fails, in the same batch.
+        let mut chunk = Chunk::new();
+        chunk.set(Coord::new(0, 0, 0), Block::new(BlockType::Sponge));
+        tx_req.send(Request::Save(broken, Chunk::new())).unwrap();
+        tx_req.send(Request::Save(ChunkCoord::new(1, 0), chunk)).unwrap();
+
+        // The first batch is put back when the broken chunk fails, and retried without it.
+        let mut loaded = None;
+        for _ in 0..20 {
+            let (tx, rx) = mpsc::channel();
+            tx_req.send(Request::LoadNow(ChunkCoord::new(1, 0), tx)).unwrap();
+            if let Ok(chunk) = rx.recv().unwrap() {
+                loaded = Some(chunk);
+                break;
+            }
+            thread::sleep(Duration::from_millis(RETRY_DELAY_MS / 2));
+        }
+        assert_eq!(loaded.unwrap().get(Coord::new(0, 0, 0)).ty, BlockType::Sponge);
+
+        let (tx, rx) = mpsc::channel();
+        tx_req.send(Request::LoadNow(broken, tx)).unwrap();
+        assert_eq!(rx.recv().unwrap().err(), Some(ChunkLoadError::Missing));
+
+        tx_req.send(Request::Close).unwrap();
+        handle.join().unwrap();
+
+        assert!(!ChunkWriteError::io(&io::Error::new(io::ErrorKind::InvalidData, "bad header"), String::new()).is_transient());
+        assert!(ChunkWriteError::io(&io::Error::new(io::ErrorKind::Other, "disk full"), String::new()).is_transient());
+    }
+
+    #[test]
+    fn batches_that_never_commit_are_given_up_on() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_database(&mut conn);
+        let mut store = BrokenStore { chunks: MemoryChunkStore::new(), broken: ChunkCoord::new(9, 9), failing_commits: true };
+        let (tx, _rx) = mpsc::channel();
+        let mut requests = vec![
+            Queued { request: Request::Save(ChunkCoord::new(0, 0), Chunk::new()), failures: 0 },
+            Queued { request: Request::Save(ChunkCoord::new(1, 0), Chunk::new()), failures: 0 },
+        ];
+        for _ in 1..MAX_WRITE_ATTEMPTS {
+            assert!(handle_requests(&mut conn, &mut store, &mut requests, &tx).is_err());
+            assert_eq!(requests.len(), 2);
+        }
+        assert!(handle_requests(&mut conn, &mut store, &mut requests, &tx).is_err());
+        assert!(requests.is_empty());
+    }
+
+    #[test]
+    fn damaged_edit_rows_are_skipped() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_database(&mut conn);
+        let edit = BlockEdit { pos: Coord::new(1, 2, 3), block: Block::new(BlockType::Leaf) };
+        for &table in ["pending_edits", "player_edits"].iter() {
+            let sql = format!("INSERT INTO {} (x, z, edit_data) VALUES (?, 0, ?)", table);
+            conn.execute(&sql, &[&0, &edits_to_bytes(&[edit])]).unwrap();
+            conn.execute(&sql, &[&1, &vec![1u8, 2, 3, 200]]).unwrap();
+        }
+
+        let pending: Vec<_> = get_pending_edits(&conn).iter().map(|(coord, edits)| (coord, edits.to_vec())).collect();
+        assert_eq!(pending, vec![(ChunkCoord::new(0, 0), vec![edit])]);
+        let player: Vec<_> = get_player_edits(&conn).iter().map(|(coord, edits)| (coord, edits.to_vec())).collect();
+        assert_eq!(player, vec![(ChunkCoord::new(0, 0), vec![edit])]);
+    }
+
+    #[test]
+    fn world_meta_and_player_round_trip() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_database(&mut conn);
+        let preset = WorldPreset::default();
+        let mut world_meta = load_or_create_world_meta(&conn, &preset);
+        assert_eq!(world_meta.generator, preset.terrain);
+        assert_eq!((world_meta.spawn, world_meta.time), (None, 0));
+        assert!(load_player(&conn).is_none());
+
+        world_meta.spawn = Some(Coord::new(-5, 40, 12));
+        world_meta.time = 12345;
+        world_meta.game_mode = GameMode::Survival;
+        world_meta.storage = StoreKind::Region;
+        write_world_meta(&conn, &world_meta).unwrap();
+        assert_eq!(load_or_create_world_meta(&conn, &preset), world_meta);
+
+        let mut player = Player::new(Coord::new(-5, 40, 12));
+        player.camera.rotate_by(10.0, 5.0);
+        player.inventory.selected = 3;
+        player.inventory.slots[3] = Some(Stack { block: BlockType::Grass, count: 63 });
+        write_player(&conn, &player).unwrap();
+        let loaded = load_player(&conn).unwrap();
+        assert_eq!(loaded.camera.pos, player.camera.pos);
+        assert_eq!((loaded.camera.h_angle, loaded.camera.v_angle), (player.camera.h_angle, player.camera.v_angle));
+        assert_eq!(loaded.inventory, player.inventory);
+    }
+
+    #[test]
+    fn worlds_convert_between_stores() {
+        let dir = env::temp_dir().join("craft-convert-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("save.sqlite");
+        {
+            let mut conn = Connection::open(&path).unwrap();
+            init_database(&mut conn);
+            let mut world_meta = load_or_create_world_meta(&conn, &WorldPreset::default());
+            world_meta.storage = StoreKind::Sqlite;
+            write_world_meta(&conn, &world_meta).unwrap();
+            for x in 0..3 {
+                let mut chunk = Chunk::new();
+                chunk.set(Coord::new(x, 30, 0), Block::new(BlockType::Sponge));
+                save_chunk(&conn, ChunkCoord::new(x * 40, -x), &chunk).unwrap();
+            }
+        }
+
+        assert_eq!(convert_storage(&path, StoreKind::Region), Ok(3));
+        assert!(convert_storage(&path, StoreKind::Region).is_err());
+        assert_eq!(get_meta(&Connection::open(&path).unwrap(), "chunk_store"), Some("region".to_string()));
+        assert!(open_store(StoreKind::Sqlite, &path).unwrap().list().unwrap().is_empty());
+        let mut regions = open_store(StoreKind::Region, &path).unwrap();
+        assert_eq!(regions.list().unwrap().len(), 3);
+        assert_eq!(regions.load(ChunkCoord::new(80, -2)).unwrap().get(Coord::new(2, 30, 0)).ty, BlockType::Sponge);
+
+        assert_eq!(convert_storage(&path, StoreKind::Sqlite), Ok(3));
+        assert!(regions.list().unwrap().is_empty());
+        let conn = Connection::open(&path).unwrap();
+        assert_eq!(load_chunk(&conn, ChunkCoord::new(40, -1)).unwrap().get(Coord::new(1, 30, 0)).ty, BlockType::Sponge);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
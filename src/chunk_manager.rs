@@ -4,6 +4,7 @@ use std::io::prelude::*;
 use std::iter::FromIterator;
 use std::ops::Index;
 use std::path::PathBuf;
+use std::sync::mpsc::{ Receiver, TryRecvError };
 
 use fnv::{ FnvHashMap, FnvHashSet };
 use glium::{ Display,  VertexBuffer, Frame, Surface, Program };
@@ -13,15 +14,31 @@ use glium::index::{ NoIndices, PrimitiveType };
 use glium::texture::{ RawImage2d, SrgbTexture2d };
 use image;
 
+use biome::Biome;
 use block::Block;
+use border_blend::{ blend_seam, seam_ends };
 use chunk::{ Chunk, EMPTY_CHUNK, CHUNK_SIDE_LENGTH_MASK };
-use chunk_loader::ChunkLoader;
-use chunk_generator::ChunkGenerator;
+use chunk_loader::{ ChunkLoader, database_stopped };
+use chunk_store::{ ChunkLoadError, checksum };
+use chunk_generator::{ ChunkGenerator, Regenerated, TerrainGenerator };
 use chunk_mesher::ChunkMesher;
+use decoration::{ BlockEdit, PendingEdits, apply_edit, apply_edits };
 use math::*;
 use chunk_mesher::ChunkVertex;
-use player::Camera;
+use ores::load_ore_config;
+use player::{ Camera, Player };
+use player_edits::PlayerEdits;
+use regenerate::BlockDiff;
+use structures::{ StructureStart, load_structures };
 use utils::{ SETTINGS, ui };
+use world_generator::GeneratorRegistry;
+use world_meta::{ WorldMeta, find_spawn };
+
+/// How many times loading a chunk is tried before it's given up on and generated again.
+const MAX_LOAD_ATTEMPTS: u32 = 3;
+/// The furthest the regenerate window reaches from its centre, in chunks. Previewing a chunk
+/// generates it and its eight neighbours.
+const MAX_REGENERATION_RADIUS: i32 = 8;
 
 pub struct ChunkManager {
     chunks: FnvHashMap<ChunkCoord, Box<Chunk>>,
@@ -30,10 +47,35 @@ pub struct ChunkManager {
     chunk_loader: ChunkLoader,
     chunk_generator: ChunkGenerator,
     chunk_states: ChunkStates,
+    pending_edits: PendingEdits,
+    /// Every structure placed so far, for `locate`.
+    structures: Vec<StructureStart>,
+    player_edits: PlayerEdits,
+    regeneration: RegenerationSettings,
+    /// Made the first time a spawn is chosen.
+    regenerator: Option<TerrainGenerator>,
+    /// How many times in a row loading each chunk has failed in a way that might go away.
+    load_attempts: FnvHashMap<ChunkCoord, u32>,
+    /// Chunks being generated again because they couldn't be loaded.
+    recovering: FnvHashSet<ChunkCoord>,
+    /// Chunks in memory that have changed since they were last saved, or have never been saved.
+    /// The rest are left out when chunks are saved.
+    dirty: FnvHashSet<ChunkCoord>,
+    save_stats: SaveStats,
+    /// Tells chunks from older generators apart, for border blending.
+    registry: GeneratorRegistry,
     texture: SrgbTexture2d,
     program: Program,
 }
 
+/// How many chunks have been written to the save, and how many weren't because they hadn't
+/// changed since they were loaded.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct SaveStats {
+    pub written: u64,
+    pub skipped: u64,
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum ChunkState {
     Saved,
@@ -74,6 +116,51 @@ impl ChunkState {
     }
 }
 
+/// What the regenerate window is set to, and the regeneration waiting to be applied.
+struct RegenerationSettings {
+    centre_x: i32,
+    centre_z: i32,
+    radius: i32,
+    keep_player_edits: bool,
+    preview: Option<RegenerationPreview>,
+}
+
+struct RegenerationPreview {
+    chunks: Vec<RegeneratedChunk>,
+    diff: BlockDiff,
+    /// Chunks that couldn't be regenerated because they've never been generated, are being
+    /// loaded or generated right now, or couldn't be read from the save.
+    skipped: usize,
+    /// Chunks still being read from the save or regenerated. The preview can't be applied
+    /// until this is empty.
+    waiting: FnvHashMap<ChunkCoord, PendingRegeneration>,
+    /// Chunks written to the save since the preview was started.
+    written: FnvHashSet<ChunkCoord>,
+}
+
+struct RegeneratedChunk {
+    coord: ChunkCoord,
+    chunk: Box<Chunk>,
+    foreign_edits: Vec<(ChunkCoord, BlockEdit)>,
+    /// The checksum of the chunk it replaces, as it was when the preview was started.
+    old_checksum: i64,
+}
+
+struct PendingRegeneration {
+    old: OldChunk,
+    new: Option<Regenerated>,
+}
+
+/// What a chunk in a preview is compared against.
+enum OldChunk {
+    Loading(Receiver<Result<Box<Chunk>, ChunkLoadError>>),
+    Loaded(Box<Chunk>),
+}
+
+fn chunk_checksum(chunk: &Chunk) -> i64 {
+    checksum(&chunk.to_bytes(), &chunk.biomes_to_bytes())
+}
+
 pub struct ChunkStates {
     states: FnvHashMap<ChunkCoord, ChunkState>,
 }
@@ -113,20 +200,90 @@ impl ChunkManager {
             },
         ).unwrap();
 
-        let (chunk_loader, chunk_states) = ChunkLoader::new(save_path);
+        let (chunk_loader, chunk_states, pending_edits, player_edits) = ChunkLoader::new(save_path);
 
-        ChunkManager {
+        let mut chunk_manager = ChunkManager {
             chunks: FnvHashMap::default(),
             chunk_vbufs: FnvHashMap::default(),
             chunk_mesher: ChunkMesher::new(),
-            chunk_generator: ChunkGenerator::new(),
+            chunk_generator: ChunkGenerator::new(
+                chunk_loader.seed(), chunk_loader.preset().clone(), load_ore_config(), load_structures()
+            ),
+            structures: chunk_loader.structures().to_vec(),
+            player_edits,
+            regeneration: RegenerationSettings {
+                centre_x: 0,
+                centre_z: 0,
+                radius: 0,
+                keep_player_edits: true,
+                preview: None,
+            },
+            regenerator: None,
+            load_attempts: FnvHashMap::default(),
+            recovering: FnvHashSet::default(),
+            dirty: FnvHashSet::default(),
+            save_stats: SaveStats::default(),
+            registry: GeneratorRegistry::new(),
             chunk_loader,
             chunk_states,
+            pending_edits,
             texture,
             program,
+        };
+        if chunk_manager.world_meta().spawn.is_none() {
+            chunk_manager.choose_spawn();
+        }
+        chunk_manager
+    }
+
+    /// Picks where new players appear, the first time the world is played.
+    fn choose_spawn(&mut self) {
+        self.ensure_regenerator();
+        let spawn = find_spawn(self.regenerator.as_ref().unwrap());
+        info!("Spawning players at {}, {}, {}", spawn.x, spawn.y, spawn.z);
+        let mut world_meta = self.world_meta().clone();
+        world_meta.spawn = Some(spawn);
+        self.chunk_loader.save_world_meta(world_meta);
+    }
+
+    pub fn world_meta(&self) -> &WorldMeta {
+        self.chunk_loader.world_meta()
+    }
+
+    /// The player as they were when the world was last saved, or a new one at the spawn point.
+    pub fn load_player(&self) -> Player {
+        match self.chunk_loader.saved_player() {
+            Some(player) => player.clone(),
+            None => Player::new(self.world_meta().spawn.unwrap()),
         }
     }
 
+    /// Saves every chunk that has changed since it was last saved, then everything
+    /// `save_world` does. The game only waits for the chunks to be copied, the writing happens
+    /// on the database thread.
+    pub fn autosave(&mut self, player: &Player, time: u64) {
+        let dirty: Vec<_> = self.dirty.drain().collect();
+        for coord in dirty {
+            let chunk = match self.chunks.get(&coord) {
+                Some(chunk) => Box::new((**chunk).clone()),
+                None => continue,
+            };
+            self.save_chunk(coord, chunk);
+        }
+        self.save_world(player, time);
+    }
+
+    /// Saves the player, the world time and every edit made so far. Chunks are saved as they
+    /// leave memory.
+    pub fn save_world(&mut self, player: &Player, time: u64) {
+        let mut world_meta = self.world_meta().clone();
+        world_meta.time = time;
+        self.chunk_loader.save_world_meta(world_meta);
+        self.chunk_loader.save_player(player);
+        self.chunk_loader.save_pending_edits(&self.pending_edits);
+        self.chunk_loader.save_player_edits(&self.player_edits);
+    }
+
     pub fn update_view(&mut self, view: Camera) {
         use self::ChunkState::*;
         let mut loaded_chunk_coords = FnvHashSet::from_iter(
@@ -157,45 +314,116 @@ impl ChunkManager {
         }
 
         for out_of_range_coord in loaded_chunk_coords {
-            let state = self.chunk_states.get_mut(out_of_range_coord);
-
-            match *state {
-                Ready => {
-                    self.chunk_vbufs.remove(&out_of_range_coord).unwrap();
+            {
+                let state = self.chunk_states.get_mut(out_of_range_coord);
+                match *state {
+                    Ready => {
+                        self.chunk_vbufs.remove(&out_of_range_coord).unwrap();
+                    }
+                    Unmeshed | Meshing => {}
+                    Saved | Loading | NonExistent | Generating => unreachable!(),
                 }
-                Unmeshed | Meshing => {}
-                Saved | Loading | NonExistent | Generating => unreachable!(),
+                *state = ChunkState::Saved;
             }
 
             let chunk = self.chunks.remove(&out_of_range_coord).unwrap();
-            self.chunk_loader.enqueue_unload(out_of_range_coord, chunk);
-            *state = ChunkState::Saved;
+            self.unload_chunk(out_of_range_coord, chunk);
         }
     }
 
+    /// Saves a chunk leaving memory, unless the save already has it as it is.
+    fn unload_chunk(&mut self, coord: ChunkCoord, chunk: Box<Chunk>) {
+        if self.dirty.remove(&coord) {
+            self.save_chunk(coord, chunk);
+        } else {
+            self.save_stats.skipped += 1;
+        }
+    }
+
+    fn save_chunk(&mut self, coord: ChunkCoord, chunk: Box<Chunk>) {
+        if let Some(ref mut preview) = self.regeneration.preview {
+            preview.written.insert(coord);
+        }
+        self.chunk_loader.enqueue_unload(coord, chunk);
+        self.save_stats.written += 1;
+    }
+
+    pub fn save_stats(&self) -> SaveStats {
+        self.save_stats
+    }
+
     pub fn tick(&mut self, display: &Display, view: Camera) {
         use self::ChunkState::*;
 
-        for (coord, chunk) in self.chunk_loader.iter_loaded() {
+        let mut arrived = Vec::new();
+        let mut failed = Vec::new();
+        for (coord, result) in self.chunk_loader.iter_loaded() {
             let state = self.chunk_states.get_mut(coord);
             match *state {
                 Saved | Ready | Unmeshed | Meshing | Generating | NonExistent => unreachable!(),
                 Loading => {
+                    let mut chunk = match result {
+                        Ok(chunk) => chunk,
+                        Err(e) => {
+                            failed.push((coord, e));
+                            continue;
+                        }
+                    };
+                    if let Some(edits) = self.pending_edits.take(coord) {
+                        apply_edits(&mut chunk, &edits);
+                        self.dirty.insert(coord);
+                    }
                     self.chunks.insert(coord, chunk);
                     *state = ChunkState::Unmeshed;
+                    self.load_attempts.remove(&coord);
+                    arrived.push(coord);
                 }
             }
         }
+        for (coord, e) in failed {
+            self.recover_chunk(coord, e);
+        }
 
-        for (coord, chunk) in self.chunk_generator.iter_generated() {
+        let mut foreign_edits = Vec::new();
+        let mut new_structures = Vec::new();
+        let mut recovered = Vec::new();
+        for (coord, mut chunk, edits, structures) in self.chunk_generator.iter_generated() {
             let state = self.chunk_states.get_mut(coord);
             match *state {
                 Saved | Ready | Unmeshed | Meshing | Loading | NonExistent => unreachable!(),
                 Generating => {
+                    if let Some(edits) = self.pending_edits.take(coord) {
+                        apply_edits(&mut chunk, &edits);
+                    }
                     self.chunks.insert(coord, chunk);
+                    self.dirty.insert(coord);
                     *state = ChunkState::Unmeshed;
+                    arrived.push(coord);
+                    if self.recovering.remove(&coord) {
+                        recovered.push(coord);
+                    }
                 }
             }
+            foreign_edits.extend(edits);
+            new_structures.extend(structures);
+        }
+        // Recovered chunks take the place of the broken copy in the save straight away.
+        for coord in recovered {
+            self.dirty.remove(&coord);
+            let chunk = self.chunks[&coord].clone();
+            self.save_chunk(coord, chunk);
+            self.chunk_loader.mark_regenerated(coord);
+        }
+        new_structures.retain(|structure| !self.structures.contains(structure));
+        if !new_structures.is_empty() {
+            self.structures.extend(new_structures.iter().cloned());
+            self.chunk_loader.save_structures(new_structures);
+        }
+        for (coord, edit) in foreign_edits {
+            self.route_edit(coord, edit);
+        }
+        for coord in arrived {
+            self.blend_borders(coord);
         }
 
         self.update_view(view);
@@ -203,12 +431,19 @@ impl ChunkManager {
         for (coord, mesh) in self.chunk_mesher.iter_meshed() {
             let state = self.chunk_states.get_mut(coord);
             match *state {
-                Ready | Unmeshed | Meshing => {
+                Ready | Meshing => {
                     let vbuf = VertexBuffer::new(display, &mesh).unwrap();
                     self.chunk_vbufs.insert(coord, vbuf);
                     *state = ChunkState::Ready;
                 }
 
+                // The chunk changed after this mesh was started. It's better than nothing until
+                // the new one is ready, but it stays unmeshed.
+                Unmeshed => {
+                    let vbuf = VertexBuffer::new(display, &mesh).unwrap();
+                    self.chunk_vbufs.insert(coord, vbuf);
+                }
+
                 // The mesh is useless if we've been evicted from memory in the meantime.
                 // Throw it away.
                 Saved | Loading | NonExistent | Generating => continue,
@@ -221,7 +456,236 @@ impl ChunkManager {
                 let state_count = self.chunk_states.states.values().filter(|&&s| s == state).count();
                 ui.text(im_str!("{:?}: {}", state, state_count));
             }
-        })
+            ui.text(im_str!("Pending edits: {}", self.pending_edits.len()));
+            ui.text(im_str!("Outdated chunks in save: {}", self.chunk_loader.outdated_chunks().len()));
+            ui.text(im_str!("Player edits: {}", self.player_edits.len()));
+            ui.text(im_str!("Unsaved chunks: {}", self.dirty.len()));
+            ui.text(im_str!("Chunk writes: {} done, {} skipped", self.save_stats.written, self.save_stats.skipped));
+        });
+
+        self.update_preview();
+        self.regeneration_window(view);
+    }
+
+    fn regeneration_window(&mut self, view: Camera) {
+        let mut preview = false;
+        let mut apply = false;
+        {
+            let settings = &mut self.regeneration;
+            let waiting = settings.preview.as_ref().map_or(0, |preview| preview.waiting.len());
+            ui.window(im_str!("Regenerate")).build(|| {
+                ui.input_int(im_str!("centre_x"), &mut settings.centre_x).build();
+                ui.input_int(im_str!("centre_z"), &mut settings.centre_z).build();
+                if ui.small_button(im_str!("Use my chunk")) {
+                    let here = ChunkCoord::from_world_pos(point3_floor(view.pos));
+                    settings.centre_x = here.x;
+                    settings.centre_z = here.z;
+                }
+                ui.input_int(im_str!("radius"), &mut settings.radius).build();
+                settings.radius = settings.radius.max(0).min(MAX_REGENERATION_RADIUS);
+                ui.checkbox(im_str!("keep_player_edits"), &mut settings.keep_player_edits);
+
+                if waiting > 0 {
+                    ui.text(im_str!("Previewing, {} chunks to go", waiting));
+                    return;
+                }
+                preview = ui.small_button(im_str!("Preview"));
+                if let Some(ref regeneration) = settings.preview {
+                    ui.text(im_str!(
+                        "{} chunks, {} blocks change, {} chunks skipped",
+                        regeneration.chunks.len(), regeneration.diff.changed, regeneration.skipped
+                    ));
+                    for line in regeneration.diff.lines() {
+                        ui.text(im_str!("{}", line));
+                    }
+                    apply = ui.small_button(im_str!("Apply"));
+                    if ui.small_button(im_str!("Cancel")) {
+                        settings.preview = None;
+                    }
+                }
+            });
+        }
+
+        if preview {
+            let centre = ChunkCoord::new(self.regeneration.centre_x, self.regeneration.centre_z);
+            let radius = self.regeneration.radius;
+            let mut coords = Vec::new();
+            for x in centre.x - radius..centre.x + radius + 1 {
+                for z in centre.z - radius..centre.z + radius + 1 {
+                    coords.push(ChunkCoord::new(x, z));
+                }
+            }
+            let keep_player_edits = self.regeneration.keep_player_edits;
+            self.regeneration.preview = Some(self.preview_regeneration(&coords, keep_player_edits));
+        }
+        if apply {
+            if let Some(regeneration) = self.regeneration.preview.take() {
+                self.apply_regeneration(regeneration);
+            }
+        }
+    }
+
+    /// Deals with a chunk that failed to load. Failures that might go away are retried a few
+    /// times by putting the chunk back in the save's queue. Otherwise the chunk is quarantined
+    /// and generated again on the generator thread, keeping the player's edits.
+    fn recover_chunk(&mut self, coord: ChunkCoord, error: ChunkLoadError) {
+        let attempts = {
+            let attempts = self.load_attempts.entry(coord).or_insert(0);
+            *attempts += 1;
+            *attempts
+        };
+        if error.is_transient() && attempts < MAX_LOAD_ATTEMPTS {
+            warn!("Failed to load chunk {}, trying again: {}", coord, error);
+            // `update_view` loads it again if it's still wanted.
+            *self.chunk_states.get_mut(coord) = ChunkState::Saved;
+            return;
+        }
+        self.load_attempts.remove(&coord);
+
+        error!("Failed to load chunk {} because {}, generating it again", coord, error);
+        if error != ChunkLoadError::Missing {
+            self.chunk_loader.quarantine(coord, error.to_string());
+        }
+        self.chunk_generator.start_recover(coord, self.player_edits.get(coord).to_vec());
+        self.recovering.insert(coord);
+        *self.chunk_states.get_mut(coord) = ChunkState::Generating;
+    }
+
+    fn ensure_regenerator(&mut self) {
+        if self.regenerator.is_none() {
+            self.regenerator = Some(TerrainGenerator::new(
+                self.chunk_loader.seed(), self.chunk_loader.preset().clone(), load_ore_config(), load_structures()
+            ));
+        }
+    }
+
+    /// Starts generating `coords` again on the generator thread, to work out how they'd change
+    /// without changing anything. `update_preview` fills the preview in as they come back.
+    fn preview_regeneration(&mut self, coords: &[ChunkCoord], keep_player_edits: bool) -> RegenerationPreview {
+        use self::ChunkState::*;
+
+        let mut result = RegenerationPreview {
+            chunks: Vec::new(),
+            diff: BlockDiff::new(),
+            skipped: 0,
+            waiting: FnvHashMap::default(),
+            written: FnvHashSet::default(),
+        };
+        for &coord in coords {
+            // Chunks in memory may have changed since they were saved, and will overwrite the
+            // save when they're unloaded, so they're what's compared against.
+            let old = match self.chunk_states.get(coord) {
+                Ready | Unmeshed | Meshing => OldChunk::Loaded(self.chunks[&coord].clone()),
+                Saved => OldChunk::Loading(self.chunk_loader.load_later(coord)),
+                NonExistent | Loading | Generating => {
+                    result.skipped += 1;
+                    continue;
+                }
+            };
+
+            let player_edits = if keep_player_edits { Some(self.player_edits.get(coord).to_vec()) } else { None };
+            self.chunk_generator.start_regenerate(coord, player_edits);
+            result.waiting.insert(coord, PendingRegeneration { old, new: None });
+        }
+        result
+    }
+
+    /// Diffs the chunks of the preview that have been both read and regenerated.
+    fn update_preview(&mut self) {
+        let preview = match self.regeneration.preview {
+            Some(ref mut preview) => preview,
+            None => return,
+        };
+        for (coord, chunk, foreign_edits) in self.chunk_generator.iter_regenerated() {
+            if let Some(pending) = preview.waiting.get_mut(&coord) {
+                pending.new = Some((coord, chunk, foreign_edits));
+            }
+        }
+
+        let mut finished = Vec::new();
+        for (&coord, pending) in preview.waiting.iter_mut() {
+            let loaded = match pending.old {
+                OldChunk::Loading(ref rx) => match rx.try_recv() {
+                    Ok(result) => Some(result),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => Some(Err(database_stopped())),
+                },
+                OldChunk::Loaded(_) => None,
+            };
+            match loaded {
+                Some(Ok(chunk)) => pending.old = OldChunk::Loaded(chunk),
+                Some(Err(e)) => {
+                    warn!("Skipping chunk {}, it couldn't be loaded because {}", coord, e);
+                    finished.push(coord);
+                    continue;
+                }
+                None => {}
+            }
+            if let (&OldChunk::Loaded(_), &Some(_)) = (&pending.old, &pending.new) {
+                finished.push(coord);
+            }
+        }
+
+        for coord in finished {
+            match preview.waiting.remove(&coord).unwrap() {
+                PendingRegeneration { old: OldChunk::Loaded(old), new: Some((_, chunk, foreign_edits)) } => {
+                    preview.diff.add(&BlockDiff::between(&old, &chunk));
+                    preview.chunks.push(RegeneratedChunk {
+                        coord,
+                        chunk,
+                        foreign_edits,
+                        old_checksum: chunk_checksum(&old),
+                    });
+                }
+                _ => preview.skipped += 1,
+            }
+        }
+    }
+
+    /// Writes regenerated chunks to the save, and swaps them in if they're in memory. Chunks
+    /// that have changed since the preview are left alone, since the player's edits since then
+    /// would be lost.
+    fn apply_regeneration(&mut self, regeneration: RegenerationPreview) {
+        use self::ChunkState::*;
+        let mut foreign_edits = Vec::new();
+        let mut changed = 0;
+        for regenerated in regeneration.chunks {
+            let RegeneratedChunk { coord, chunk, foreign_edits: edits, old_checksum } = regenerated;
+            let unchanged = match self.chunk_states.get(coord) {
+                Ready | Unmeshed | Meshing => chunk_checksum(&self.chunks[&coord]) == old_checksum,
+                Saved => !regeneration.written.contains(&coord),
+                // Only possible if the chunk started loading since the preview.
+                NonExistent | Loading | Generating => false,
+            };
+            if !unchanged {
+                changed += 1;
+                continue;
+            }
+
+            let state = self.chunk_states.get_mut(coord);
+            match *state {
+                Ready | Unmeshed | Meshing => {
+                    self.chunk_loader.enqueue_unload(coord, Box::new((*chunk).clone()));
+                    self.save_stats.written += 1;
+                    self.chunks.insert(coord, chunk);
+                    self.dirty.remove(&coord);
+                    *state = ChunkState::Unmeshed;
+                }
+                Saved => {
+                    self.chunk_loader.enqueue_unload(coord, chunk);
+                    self.save_stats.written += 1;
+                }
+                NonExistent | Loading | Generating => unreachable!(),
+            }
+            self.chunk_loader.mark_regenerated(coord);
+            foreign_edits.extend(edits);
+        }
+        if changed > 0 {
+            warn!("{} chunks changed since the preview and weren't regenerated, preview them again", changed);
+        }
+        for (coord, edit) in foreign_edits {
+            self.route_edit(coord, edit);
+        }
     }
 
     pub fn render(&mut self, frame: &mut Frame, clip_from_world: &Matrix4<f32>) {
@@ -269,19 +733,117 @@ impl ChunkManager {
         })
     }
 
+    /// The biome of the column containing `coord`, if its chunk is in memory.
+    pub fn get_biome(&self, coord: Coord) -> Option<Biome> {
+        let chunk_coord = ChunkCoord::from_world_pos(coord);
+        self.chunks.get(&chunk_coord).map(|chunk| {
+            chunk.get_biome(coord.x & (CHUNK_SIDE_LENGTH_MASK as i32), coord.z & (CHUNK_SIDE_LENGTH_MASK as i32))
+        })
+    }
+
     pub fn set_block(&mut self, coord: Coord, block: Block) {
         let chunk_coord = ChunkCoord::from_world_pos(coord);
         if let Some(chunk) = self.chunks.get_mut(&chunk_coord) {
-            chunk.set(Coord {
+            let pos = Coord {
                 x: coord.x & (CHUNK_SIDE_LENGTH_MASK as i32),
                 y: coord.y,
                 z: coord.z & (CHUNK_SIDE_LENGTH_MASK as i32)
-            }, block);
+            };
+            chunk.set(pos, block);
+            self.dirty.insert(chunk_coord);
+            self.player_edits.record(chunk_coord, BlockEdit { pos, block });
             self.chunk_states.set(chunk_coord, ChunkState::Meshing);
             self.chunk_mesher.start_meshing(chunk_coord, chunk);
         }
     }
 
+    /// Applies a decoration edit to a chunk in memory, or holds on to it until the chunk is
+    /// generated or loaded.
+    fn route_edit(&mut self, coord: ChunkCoord, edit: BlockEdit) {
+        use self::ChunkState::*;
+        let state = self.chunk_states.get_mut(coord);
+        match *state {
+            Ready | Unmeshed | Meshing => {
+                apply_edit(self.chunks.get_mut(&coord).unwrap(), edit);
+                self.dirty.insert(coord);
+                *state = ChunkState::Unmeshed;
+            }
+            Saved | Loading | NonExistent | Generating => {
+                self.pending_edits.add(coord, edit);
+            }
+        }
+    }
+
+    /// Blends the seams between the chunk at `coord` and any neighbours in memory, where one of
+    /// the two was made by an older generator and the other wasn't. Blending a seam again
+    /// doesn't change it, so chunks can be blended each time they're loaded.
+    fn blend_borders(&mut self, coord: ChunkCoord) {
+        let width = SETTINGS.border_blend_width;
+        if width <= 0 || !self.chunks.contains_key(&coord) {
+            return;
+        }
+
+        for &(dx, dz) in [(1, 0), (-1, 0), (0, 1), (0, -1)].iter() {
+            let neighbour = ChunkCoord::new(coord.x + dx, coord.z + dz);
+            let outdated = match (self.chunks.get(&coord), self.chunks.get(&neighbour)) {
+                (Some(chunk), Some(other)) => (
+                    self.registry.is_outdated(chunk.generator()),
+                    self.registry.is_outdated(other.generator()),
+                ),
+                _ => continue,
+            };
+            let (new_coord, old_coord) = match outdated {
+                (false, true) => (coord, neighbour),
+                (true, false) => (neighbour, coord),
+                _ => continue,
+            };
+
+            let ends = seam_ends(new_coord, old_coord, |a, b| self.is_seam(a, b));
+            let mut new = self.chunks.remove(&new_coord).unwrap();
+            let mut old = self.chunks.remove(&old_coord).unwrap();
+            let changed = blend_seam(&mut new, new_coord, &mut old, old_coord, width, ends, &self.player_edits);
+            self.chunks.insert(new_coord, new);
+            self.chunks.insert(old_coord, old);
+            if changed.0 {
+                self.chunk_states.set(new_coord, ChunkState::Unmeshed);
+                self.dirty.insert(new_coord);
+            }
+            if changed.1 {
+                self.chunk_states.set(old_coord, ChunkState::Unmeshed);
+                self.dirty.insert(old_coord);
+            }
+        }
+    }
+
+    /// Whether the seam between two chunks is blended, which it is when both are in memory and
+    /// only one is outdated.
+    fn is_seam(&self, a: ChunkCoord, b: ChunkCoord) -> bool {
+        match (self.chunks.get(&a), self.chunks.get(&b)) {
+            (Some(a), Some(b)) => self.registry.is_outdated(a.generator()) != self.registry.is_outdated(b.generator()),
+            _ => false,
+        }
+    }
+
+    /// The nearest placed structure called `name` to `pos`. Only structures whose origin chunk
+    /// has been generated are known.
+    pub fn locate(&self, name: &str, pos: Coord) -> Option<&StructureStart> {
+        self.structures.iter()
+            .filter(|structure| structure.name == name)
+            .min_by_key(|structure| {
+                let dx = (structure.pos.x - pos.x) as i64;
+                let dz = (structure.pos.z - pos.z) as i64;
+                dx * dx + dz * dz
+            })
+    }
+
+    /// The names of every structure placed so far, without repeats.
+    pub fn structure_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.structures.iter().map(|structure| &structure.name[..]).collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
     pub fn get_chunk_state(&self, coord: ChunkCoord) -> ChunkState {
         self.chunk_states.get(coord)
     }
@@ -293,9 +855,31 @@ impl ChunkManager {
 
 impl Drop for ChunkManager {
     fn drop(&mut self) {
-        for (coord, chunk) in self.chunks.drain() {
-            self.chunk_loader.enqueue_unload(coord, chunk);
+        // Chunks still being generated would be generated again next time, but the edits that
+        // spill out of them into chunks already saved would be lost.
+        let mut new_structures = Vec::new();
+        for (coord, mut chunk, edits, structures) in self.chunk_generator.finish() {
+            if let Some(pending) = self.pending_edits.take(coord) {
+                apply_edits(&mut chunk, &pending);
+            }
+            self.chunk_states.set(coord, ChunkState::Saved);
+            self.save_chunk(coord, chunk);
+            for (target, edit) in edits {
+                self.route_edit(target, edit);
+            }
+            new_structures.extend(structures);
+        }
+        new_structures.retain(|structure| !self.structures.contains(structure));
+        if !new_structures.is_empty() {
+            self.chunk_loader.save_structures(new_structures);
+        }
+
+        let chunks: Vec<_> = self.chunks.drain().collect();
+        for (coord, chunk) in chunks {
+            self.unload_chunk(coord, chunk);
         }
+        self.chunk_loader.save_pending_edits(&self.pending_edits);
+        self.chunk_loader.save_player_edits(&self.player_edits);
     }
 }
 
@@ -31,7 +31,7 @@ use math::*;
 //    },
 //}
 
-static BLOCK_SPECS: [[u8; 6]; 16] = [
+static BLOCK_SPECS: [[u8; 6]; 17] = [
     // [Block] = [Left, Right, Bottom, Top, Front, Back],
     [ 0,  1,  2,  3,  4,  5], // Block_Air - Dummy values to make glitches obvious
     [ 3,  3,  3,  3,  3,  3], // Block_Dirt
@@ -49,9 +49,10 @@ static BLOCK_SPECS: [[u8; 6]; 16] = [
     [24, 24, 24, 24, 24, 24], // Block_Leaf
     [28, 28, 28, 28, 28, 28], // Block_Sponge
     [38, 38, 37, 36, 38, 38], // Block_Sandstone
+    [205, 205, 205, 205, 205, 205], // Block_Water
 ];
 
-static BLOCK_NAMES: [&str; 16] = [
+static BLOCK_NAMES: [&str; 17] = [
     "Air",
     "Dirt",
     "Grass",
@@ -68,6 +69,7 @@ static BLOCK_NAMES: [&str; 16] = [
     "Leaf",
     "Sponge",
     "Sandstone",
+    "Water",
 ];
 
 static UNIT_CUBE_FACES: [[u8; 3]; 36] = [
@@ -158,8 +160,10 @@ implement_vertex!{
 }
 
 pub struct ChunkMesher {
-    thread_handle: thread::JoinHandle<()>,
-    tx_req: mpsc::Sender<Request>,
+    // Options so that drop can close the request channel, which stops the thread, and then
+    // join it.
+    thread_handle: Option<thread::JoinHandle<()>>,
+    tx_req: Option<mpsc::Sender<Request>>,
     rx_resp: mpsc::Receiver<Response>,
 }
 
@@ -181,12 +185,14 @@ impl ChunkMesher {
         });
 
         ChunkMesher {
-            tx_req, rx_resp, thread_handle
+            tx_req: Some(tx_req),
+            rx_resp,
+            thread_handle: Some(thread_handle),
         }
     }
 
     pub fn start_meshing(&mut self, coord: ChunkCoord, chunk: &Chunk) {
-        self.tx_req.send((coord, Box::new(chunk.clone()))).unwrap();
+        self.tx_req.as_ref().unwrap().send((coord, Box::new(chunk.clone()))).unwrap();
     }
 
     pub fn iter_meshed<'a>(&'a mut self) -> ResponseIter {
@@ -194,6 +200,15 @@ impl ChunkMesher {
     }
 }
 
+impl Drop for ChunkMesher {
+    fn drop(&mut self) {
+        self.tx_req.take();
+        if self.thread_handle.take().unwrap().join().is_err() {
+            error!("The chunk mesher thread panicked");
+        }
+    }
+}
+
 pub struct ResponseIter<'a>(mpsc::TryIter<'a, Response>);
 
 impl<'a> Iterator for ResponseIter<'a> {
@@ -220,8 +235,9 @@ pub fn create_mesh(chunk: &Chunk) -> Vec<ChunkVertex> {
                 0 <= adj_y && adj_y < WORLD_HEIGHT as i32 &&
                 0 <= adj_z && adj_z < CHUNK_SIDE_LENGTH as i32
             {
+                // Faces are hidden behind opaque blocks, and between two blocks of the same fluid.
                 let adjacent = chunk.get(Coord::new(adj_x, adj_y, adj_z));
-                if adjacent.ty != BlockType::Air {
+                if adjacent.is_opaque() || adjacent.ty == block.ty {
                     continue;
                 }
             }
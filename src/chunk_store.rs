@@ -0,0 +1,305 @@
+use deflate::deflate_bytes_zlib;
+use fnv::{ FnvHashMap, FnvHasher };
+use inflate::inflate_bytes_zlib;
+use rusqlite::Error as SqliteError;
+use rusqlite::ffi::ErrorCode;
+
+use std::fmt;
+use std::io;
+use std::hash::Hasher;
+use std::path::{ Path, PathBuf };
+
+use chunk::Chunk;
+use math::*;
+use region_store::RegionChunkStore;
+use sqlite_store::SqliteChunkStore;
+use world_generator::GeneratorStamp;
+
+/// Every chunk blob starts with this, then `CHUNK_BLOB_VERSION`, then the rest of the blob.
+pub const CHUNK_BLOB_MAGIC: &[u8] = b"CHNK";
+/// 1: the zlib compressed output of `Chunk::to_bytes`.
+pub const CHUNK_BLOB_VERSION: u8 = 1;
+
+/// Where a world's chunks are kept. Everything else about the world is always in the SQLite
+/// save. Writes may be grouped between `begin` and `commit`, so that a batch is either written
+/// completely or not at all on stores that can manage that.
+pub trait ChunkStore {
+    fn load(&mut self, coord: ChunkCoord) -> Result<Box<Chunk>, ChunkLoadError>;
+
+    fn save(&mut self, coord: ChunkCoord, chunk: &Chunk) -> Result<(), ChunkWriteError>;
+
+    /// Every chunk in the store, with the generator that made it.
+    fn list(&mut self) -> Result<Vec<(ChunkCoord, Option<GeneratorStamp>)>, String>;
+
+    fn delete(&mut self, coord: ChunkCoord) -> Result<(), ChunkWriteError>;
+
+    /// Moves a chunk that couldn't be loaded out of the way. Stores with nowhere to keep it
+    /// for later just delete it.
+    fn quarantine(&mut self, coord: ChunkCoord, reason: &str) -> Result<(), ChunkWriteError> {
+        warn!("Deleting chunk {}, this store can't keep it aside: {}", coord, reason);
+        self.delete(coord)
+    }
+
+    fn begin(&mut self) -> Result<(), ChunkWriteError> {
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), ChunkWriteError> {
+        Ok(())
+    }
+
+    /// Throws away the writes since `begin`, where the store can.
+    fn rollback(&mut self) {}
+}
+
+/// The stores a world can be kept in, recorded in its metadata.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StoreKind {
+    /// In the `chunks` table of the save itself.
+    Sqlite,
+    /// In region files of 32 by 32 chunks, in a directory next to the save.
+    Region,
+}
+
+impl StoreKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            StoreKind::Sqlite => "sqlite",
+            StoreKind::Region => "region",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<StoreKind> {
+        match name {
+            "sqlite" => Some(StoreKind::Sqlite),
+            "region" => Some(StoreKind::Region),
+            _ => None,
+        }
+    }
+}
+
+/// Opens the chunk store of the kind given for the save at `save_path`.
+pub fn open_store(kind: StoreKind, save_path: &Path) -> Result<Box<ChunkStore + Send>, String> {
+    match kind {
+        StoreKind::Sqlite => Ok(Box::new(SqliteChunkStore::open(save_path)?)),
+        StoreKind::Region => Ok(Box::new(RegionChunkStore::open(region_dir(save_path))?)),
+    }
+}
+
+/// Where the region files of the save at `save_path` go, `save.regions` for `save.sqlite`.
+pub fn region_dir(save_path: &Path) -> PathBuf {
+    save_path.with_extension("regions")
+}
+
+/// Copies every chunk in `from` to `to`, in one batch. Returns how many there were.
+pub fn copy_chunks(from: &mut ChunkStore, to: &mut ChunkStore) -> Result<usize, String> {
+    let coords: Vec<_> = from.list()?.into_iter().map(|(coord, _)| coord).collect();
+    to.begin()?;
+    for &coord in coords.iter() {
+        let result = from.load(coord)
+            .map_err(|e| format!("chunk {} can't be read because {}", coord, e))
+            .and_then(|chunk| to.save(coord, &chunk).map_err(String::from));
+        if let Err(e) = result {
+            to.rollback();
+            return Err(e);
+        }
+    }
+    to.commit()?;
+    Ok(coords.len())
+}
+
+/// Why a chunk couldn't be read from the save.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChunkLoadError {
+    /// The chunk isn't in the save.
+    Missing,
+    /// The chunk doesn't match the checksum it was written with.
+    ChecksumMismatch,
+    /// The chunk can't be decoded.
+    Corrupt(String),
+    /// The store couldn't be read, the chunk itself may well be fine.
+    Database(String),
+}
+
+impl ChunkLoadError {
+    /// Whether trying again later might work.
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            ChunkLoadError::Database(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ChunkLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ChunkLoadError::Missing => write!(f, "it isn't in the save"),
+            ChunkLoadError::ChecksumMismatch => write!(f, "it doesn't match its checksum"),
+            ChunkLoadError::Corrupt(ref reason) => write!(f, "it's corrupt: {}", reason),
+            ChunkLoadError::Database(ref reason) => write!(f, "the save couldn't be read: {}", reason),
+        }
+    }
+}
+
+/// Why a chunk couldn't be written to the store.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChunkWriteError {
+    /// Might go away if the write is tried again later, like a locked database or a full disk.
+    Transient(String),
+    /// Fails the same way every time, like a broken constraint, a file that can't be written
+    /// to or a damaged region.
+    Permanent(String),
+}
+
+impl ChunkWriteError {
+    /// Sorts an error from SQLite. Busy and locked databases, full disks and failed I/O might
+    /// go away.
+    pub fn sqlite(e: SqliteError) -> ChunkWriteError {
+        let transient = match e {
+            SqliteError::SqliteFailure(ref failure, _) => match failure.code {
+                ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked |
+                ErrorCode::DiskFull | ErrorCode::SystemIOFailure => true,
+                _ => false,
+            },
+            _ => false,
+        };
+        ChunkWriteError::new(transient, e.to_string())
+    }
+
+    /// Sorts an I/O error, described by `message`. Damaged files, missing files and missing
+    /// permissions don't go away by themselves.
+    pub fn io(e: &io::Error, message: String) -> ChunkWriteError {
+        let transient = match e.kind() {
+            io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput | io::ErrorKind::UnexpectedEof |
+            io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied => false,
+            _ => true,
+        };
+        ChunkWriteError::new(transient, message)
+    }
+
+    fn new(transient: bool, message: String) -> ChunkWriteError {
+        if transient {
+            ChunkWriteError::Transient(message)
+        } else {
+            ChunkWriteError::Permanent(message)
+        }
+    }
+
+    /// Whether trying again later might work.
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            ChunkWriteError::Transient(_) => true,
+            ChunkWriteError::Permanent(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for ChunkWriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ChunkWriteError::Transient(ref reason) | ChunkWriteError::Permanent(ref reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl From<ChunkWriteError> for String {
+    fn from(e: ChunkWriteError) -> String {
+        e.to_string()
+    }
+}
+
+/// Catches chunks that were damaged after they were written, which would otherwise decode
+/// into garbage rather than fail.
+pub fn checksum(block_data: &[u8], biome_data: &[u8]) -> i64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(block_data);
+    hasher.write(biome_data);
+    hasher.finish() as i64
+}
+
+/// The blob a chunk's blocks are stored as, see `CHUNK_BLOB_VERSION`.
+pub fn encode_blocks(chunk: &Chunk) -> Vec<u8> {
+    let mut blob = CHUNK_BLOB_MAGIC.to_vec();
+    blob.push(CHUNK_BLOB_VERSION);
+    blob.extend(deflate_bytes_zlib(&chunk.to_bytes()));
+    blob
+}
+
+pub fn decode_blocks(blob: &[u8]) -> Result<Box<Chunk>, String> {
+    let header_len = CHUNK_BLOB_MAGIC.len() + 1;
+    if blob.len() < header_len || &blob[..CHUNK_BLOB_MAGIC.len()] != CHUNK_BLOB_MAGIC {
+        return Err("the block data has no format header".to_string());
+    }
+    let version = blob[CHUNK_BLOB_MAGIC.len()];
+    if version != CHUNK_BLOB_VERSION {
+        return Err(format!("unknown block data version {}", version));
+    }
+    let bytes = inflate_bytes_zlib(&blob[header_len..])?;
+    Chunk::from_bytes(&bytes)
+}
+
+/// Keeps chunks in memory only, for tests.
+pub struct MemoryChunkStore {
+    chunks: FnvHashMap<ChunkCoord, Box<Chunk>>,
+}
+
+impl MemoryChunkStore {
+    pub fn new() -> MemoryChunkStore {
+        MemoryChunkStore {
+            chunks: FnvHashMap::default(),
+        }
+    }
+}
+
+impl ChunkStore for MemoryChunkStore {
+    fn load(&mut self, coord: ChunkCoord) -> Result<Box<Chunk>, ChunkLoadError> {
+        self.chunks.get(&coord).cloned().ok_or(ChunkLoadError::Missing)
+    }
+
+    fn save(&mut self, coord: ChunkCoord, chunk: &Chunk) -> Result<(), ChunkWriteError> {
+        self.chunks.insert(coord, Box::new(chunk.clone()));
+        Ok(())
+    }
+
+    fn list(&mut self) -> Result<Vec<(ChunkCoord, Option<GeneratorStamp>)>, String> {
+        Ok(self.chunks.iter().map(|(&coord, chunk)| (coord, chunk.generator().cloned())).collect())
+    }
+
+    fn delete(&mut self, coord: ChunkCoord) -> Result<(), ChunkWriteError> {
+        self.chunks.remove(&coord);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block::{ Block, BlockType };
+
+    #[test]
+    fn chunks_are_copied_between_stores() {
+        let mut from = MemoryChunkStore::new();
+        for i in 0..5 {
+            let mut chunk = Chunk::new();
+            chunk.set(Coord::new(i, 20, 3), Block::new(BlockType::Sponge));
+            chunk.set_generator(Some(GeneratorStamp { name: "noise".to_string(), version: i as u32 }));
+            from.save(ChunkCoord::new(i * 40 - 80, -i), &chunk).unwrap();
+        }
+
+        let mut to = MemoryChunkStore::new();
+        assert_eq!(copy_chunks(&mut from, &mut to).unwrap(), 5);
+        let sorted = |mut entries: Vec<(ChunkCoord, Option<GeneratorStamp>)>| {
+            entries.sort_by_key(|&(coord, _)| coord.x);
+            entries
+        };
+        assert_eq!(sorted(to.list().unwrap()), sorted(from.list().unwrap()));
+        for i in 0..5 {
+            let chunk = to.load(ChunkCoord::new(i * 40 - 80, -i)).unwrap();
+            assert_eq!(chunk.get(Coord::new(i, 20, 3)).ty, BlockType::Sponge);
+        }
+
+        to.delete(ChunkCoord::new(-80, 0)).unwrap();
+        assert_eq!(to.load(ChunkCoord::new(-80, 0)).err(), Some(ChunkLoadError::Missing));
+    }
+}
@@ -0,0 +1,65 @@
+use std::path::PathBuf;
+use std::process;
+
+use chunk_loader::convert_storage;
+use chunk_store::StoreKind;
+use craft::load_settings;
+
+const USAGE: &str = "usage: craft convert <sqlite|region> [save path]
+
+Moves every chunk of the save, which defaults to save.sqlite, into the given kind of chunk store
+and records that the world is kept there. Region files go in a directory next to the save, named
+after it with a .regions extension. The rest of the world always stays in the save itself.";
+
+fn parse_args(args: &[String]) -> Result<(StoreKind, PathBuf), String> {
+    if args.is_empty() || args.len() > 2 {
+        return Err("expected 1 or 2 arguments".to_string());
+    }
+    let target = StoreKind::from_name(&args[0])
+        .ok_or_else(|| format!("{:?} isn't a chunk store, use sqlite or region", args[0]))?;
+    let save_path = args.get(1).cloned().unwrap_or_else(|| "save.sqlite".to_string());
+    Ok((target, save_path.into()))
+}
+
+/// Runs the `convert` subcommand with the arguments that follow it.
+pub fn run(args: &[String]) {
+    let (target, save_path) = match parse_args(args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    // Opening a save that isn't there would create an empty world rather than fail.
+    if !save_path.exists() {
+        eprintln!("There's no save at {:?}", save_path);
+        process::exit(1);
+    }
+
+    load_settings();
+    match convert_storage(&save_path, target) {
+        Ok(moved) => println!("Moved {} chunks into the {} store", moved, target.name()),
+        Err(e) => {
+            eprintln!("Failed to convert {:?}: {}", save_path, e);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn arguments_are_parsed() {
+        assert_eq!(parse_args(&args(&["region"])), Ok((StoreKind::Region, PathBuf::from("save.sqlite"))));
+        assert_eq!(parse_args(&args(&["sqlite", "other.sqlite"])), Ok((StoreKind::Sqlite, PathBuf::from("other.sqlite"))));
+        assert!(parse_args(&args(&[])).is_err());
+        assert!(parse_args(&args(&["anvil"])).is_err());
+        assert!(parse_args(&args(&["region", "a.sqlite", "b.sqlite"])).is_err());
+    }
+}
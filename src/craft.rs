@@ -1,6 +1,8 @@
 use std::io::prelude::*;
 use std::fs::{ File, OpenOptions };
 use std::path::PathBuf;
+use std::thread;
+use std::time::{ Duration, Instant };
 
 use fnv::FnvHashMap;
 use glium;
@@ -18,6 +20,7 @@ use chunk_manager::ChunkManager;
 use line_renderer::LineRenderer;
 use math::*;
 use player::Player;
+use signals;
 use utils::*;
 
 
@@ -33,6 +36,7 @@ pub struct Craft {
     line_renderer: LineRenderer,
     chunk_manager: ChunkManager,
     tick: u64,
+    last_autosave: Instant,
     player: Player,
 }
 
@@ -58,7 +62,7 @@ fn init_imgui_keymap(imgui: &mut ImGui) {
     imgui.set_imgui_key(ImGuiKey::Z, VirtualKeyCode::Z as u8);
 }
 
-fn load_settings() {
+pub fn load_settings() {
     if let Ok(mut file) = File::open("settings.toml") {
         let mut string = String::new();
         file.read_to_string(&mut string);
@@ -86,6 +90,17 @@ fn store_settings() {
     file.write_all(settings_string.as_bytes()).unwrap();
 }
 
+/// Saves the world metadata and the player whenever the game stops, on a panic as well as when it
+/// closes. The chunks are saved when the chunk manager is dropped, straight after.
+impl Drop for Craft {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            error!("The game panicked, saving the world before it stops.");
+        }
+        self.chunk_manager.save_world(&self.player, self.tick);
+    }
+}
+
 impl Craft {
     pub fn run() {
         let mut events_loop = glutin::EventsLoop::new();
@@ -101,6 +116,7 @@ impl Craft {
         let ref display = glium::Display::new(window, context, &events_loop).unwrap();
 
         load_settings();
+        signals::install_handlers();
         let mut app = Craft::new(display);
         let mut imgui = ImGui::init();
         let mut imgui_renderer = ImGuiRenderer::init(&mut imgui, display).unwrap();
@@ -109,6 +125,10 @@ impl Craft {
         let mut test_window_opened = true;
 
         while run {
+            if signals::shutdown_requested() {
+                info!("Asked to close, saving.");
+                break;
+            }
             events_loop.poll_events(|event| {
                 match event {
                     Event::WindowEvent { event: WindowEvent::Closed, .. } => {
@@ -179,12 +199,14 @@ impl Craft {
             debug!("main end frame");
         }
 
+        // The world is saved when `app` is dropped.
         store_settings();
     }
 
     fn new(display: &Display) -> Self {
         let (width, height) = display.gl_window().get_inner_size_pixels().unwrap();
         let aspect_ratio = (width as f32) / (height as f32);
+        let chunk_manager = ChunkManager::new(display, "save.sqlite".into());
         Craft {
             aspect_ratio: aspect_ratio,
             width, height,
@@ -194,9 +216,10 @@ impl Craft {
             keys: [false; VirtualKeyCode::Yen as usize],
 
             line_renderer: LineRenderer::new(display),
-            chunk_manager: ChunkManager::new(display, "save.sqlite".into()),
-            tick: 0,
-            player: Player::new(),
+            tick: chunk_manager.world_meta().time,
+            last_autosave: Instant::now(),
+            player: chunk_manager.load_player(),
+            chunk_manager,
         }
     }
 
@@ -211,6 +234,8 @@ impl Craft {
                 ui.input_float(im_str!("reach_distance"), &mut SETTINGS_MUT.reach_distance).step(1.0).build();
                 ui.input_float(im_str!("raycast_step_size"), &mut SETTINGS_MUT.raycast_step_size).build();
                 ui.input_float(im_str!("raycast_max_distance"), &mut SETTINGS_MUT.raycast_max_distance).step(1.0).build();
+                ui.input_int(im_str!("border_blend_width"), &mut SETTINGS_MUT.border_blend_width).build();
+                ui.input_int(im_str!("autosave_interval"), &mut SETTINGS_MUT.autosave_interval).build();
             });
         }
 
@@ -234,8 +259,26 @@ impl Craft {
         }
 
         ui.text(im_str!("{:?}", self.player.camera));
+        ui.text(im_str!("Game mode: {}", self.chunk_manager.world_meta().game_mode.name()));
+        match self.chunk_manager.get_biome(point3_floor(self.player.camera.pos)) {
+            Some(biome) => ui.text(im_str!("Biome: {}", biome.name())),
+            None => ui.text(im_str!("Biome: (not loaded)")),
+        }
+        let player_pos = point3_floor(self.player.camera.pos);
+        for name in self.chunk_manager.structure_names() {
+            if let Some(structure) = self.chunk_manager.locate(name, player_pos) {
+                let pos = structure.pos;
+                ui.text(im_str!("Nearest {}: {}, {}, {}", name, pos.x, pos.y, pos.z));
+            }
+        }
         self.chunk_manager.tick(display, self.player.camera);
         self.tick += 1;
+
+        let interval = SETTINGS.autosave_interval;
+        if interval > 0 && self.last_autosave.elapsed() >= Duration::from_secs(interval as u64) {
+            self.chunk_manager.autosave(&self.player, self.tick);
+            self.last_autosave = Instant::now();
+        }
     }
 
     fn render(&mut self, display: &Display, frame: &mut Frame) {
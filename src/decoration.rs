@@ -0,0 +1,337 @@
+use fnv::FnvHashMap;
+
+use biome::Biome;
+use block::{ Block, BlockType };
+use chunk::{ Chunk, CHUNK_SIDE_LENGTH, CHUNK_SIDE_LENGTH_MASK, WORLD_HEIGHT };
+use math::*;
+use random::Rng;
+
+const DECORATION_SALT: u32 = 300;
+
+/// A block to be written into a chunk by the decoration stage. `pos` is local to the chunk.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BlockEdit {
+    pub pos: Coord,
+    pub block: Block,
+}
+
+/// Decides whether a decoration block may replace what's already there.
+///
+/// Decorations never replace terrain, only air and other decorations, and the block with the
+/// higher rank wins. Because of that, applying the same set of edits in any order gives the same
+/// result, which is what makes decoration independent of the order chunks are generated in.
+fn decoration_rank(ty: BlockType) -> Option<u8> {
+    match ty {
+        BlockType::Air => Some(0),
+        BlockType::Leaf => Some(1),
+        BlockType::Log => Some(2),
+        BlockType::Cobblestone => Some(3),
+        _ => None,
+    }
+}
+
+pub fn apply_edit(chunk: &mut Chunk, edit: BlockEdit) {
+    let existing = chunk.get(edit.pos).ty;
+    if let (Some(old), Some(new)) = (decoration_rank(existing), decoration_rank(edit.block.ty)) {
+        if new > old {
+            chunk.set(edit.pos, edit.block);
+        }
+    }
+}
+
+pub fn apply_edits(chunk: &mut Chunk, edits: &[BlockEdit]) {
+    for &edit in edits {
+        apply_edit(chunk, edit);
+    }
+}
+
+/// Edits aimed at chunks that weren't in memory when they were made. They're applied when the
+/// chunk is next generated or loaded.
+pub struct PendingEdits {
+    edits: FnvHashMap<ChunkCoord, Vec<BlockEdit>>,
+}
+
+impl PendingEdits {
+    pub fn new() -> PendingEdits {
+        PendingEdits {
+            edits: FnvHashMap::default(),
+        }
+    }
+
+    /// Does nothing if the same edit is already pending.
+    pub fn add(&mut self, coord: ChunkCoord, edit: BlockEdit) {
+        let edits = self.edits.entry(coord).or_insert_with(Vec::new);
+        if !edits.contains(&edit) {
+            edits.push(edit);
+        }
+    }
+
+    pub fn take(&mut self, coord: ChunkCoord) -> Option<Vec<BlockEdit>> {
+        self.edits.remove(&coord)
+    }
+
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item=(ChunkCoord, &'a [BlockEdit])> + 'a {
+        self.edits.iter().map(|(&coord, edits)| (coord, &edits[..]))
+    }
+
+    pub fn len(&self) -> usize {
+        self.edits.values().map(|edits| edits.len()).sum()
+    }
+}
+
+/// Starts edit lists that store block states. Edits used to be stored without the marker, but
+/// those always start with an x coordinate inside the chunk, which is never this large.
+const EDITS_WITH_STATE: u8 = 0x81;
+
+pub fn edits_to_bytes(edits: &[BlockEdit]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(1 + edits.len() * 5);
+    bytes.push(EDITS_WITH_STATE);
+    for edit in edits {
+        bytes.push(edit.pos.x as u8);
+        bytes.push(edit.pos.y as u8);
+        bytes.push(edit.pos.z as u8);
+        bytes.push(edit.block.ty as u8);
+        bytes.push(edit.block.state);
+    }
+    bytes
+}
+
+/// Reads edits written by `edits_to_bytes`, or in the older form without states. Fails if the
+/// bytes aren't a whole number of edits, or an edit is outside the chunk or of an unknown type.
+pub fn edits_from_bytes(bytes: &[u8]) -> Result<Vec<BlockEdit>, String> {
+    let (bytes, edit_len) = if bytes.first() == Some(&EDITS_WITH_STATE) { (&bytes[1..], 5) } else { (bytes, 4) };
+    if bytes.len() % edit_len != 0 {
+        return Err(format!("{} bytes left over after the edits", bytes.len() % edit_len));
+    }
+    bytes.chunks(edit_len)
+        .map(|b| {
+            let pos = Coord::new(b[0] as i32, b[1] as i32, b[2] as i32);
+            if pos.x as usize >= CHUNK_SIDE_LENGTH || pos.y as usize >= WORLD_HEIGHT || pos.z as usize >= CHUNK_SIDE_LENGTH {
+                return Err(format!("edit at {:?} is outside the chunk", pos));
+            }
+            let ty = BlockType::from_id(b[3]).ok_or_else(|| format!("unknown block type {}", b[3]))?;
+            let state = if edit_len == 5 { b[4] } else { 0 };
+            Ok(BlockEdit { pos, block: Block::with_state(ty, state) })
+        })
+        .collect()
+}
+
+/// Places trees and boulders on top of generated terrain. A decoration belongs to the chunk its
+/// base is in, but it may spill over into neighbouring chunks; those blocks are handed back
+/// instead of being written.
+pub struct Decorator {
+    seed: u32,
+}
+
+struct DecorationSink<'a> {
+    coord: ChunkCoord,
+    chunk: &'a mut Chunk,
+    foreign: Vec<(ChunkCoord, BlockEdit)>,
+}
+
+impl<'a> DecorationSink<'a> {
+    /// `pos` is relative to the chunk being decorated, and may be outside of it.
+    fn place(&mut self, pos: Coord, ty: BlockType) {
+        if pos.y < 0 || pos.y >= WORLD_HEIGHT as i32 {
+            return;
+        }
+
+        let world_pos = Coord {
+            x: self.coord.x * CHUNK_SIDE_LENGTH as i32 + pos.x,
+            y: pos.y,
+            z: self.coord.z * CHUNK_SIDE_LENGTH as i32 + pos.z,
+        };
+        let target = ChunkCoord::from_world_pos(world_pos);
+        let edit = BlockEdit {
+            pos: Coord {
+                x: world_pos.x & CHUNK_SIDE_LENGTH_MASK as i32,
+                y: pos.y,
+                z: world_pos.z & CHUNK_SIDE_LENGTH_MASK as i32,
+            },
+            block: Block::new(ty),
+        };
+
+        if target == self.coord {
+            apply_edit(self.chunk, edit);
+        } else {
+            self.foreign.push((target, edit));
+        }
+    }
+}
+
+impl Decorator {
+    pub fn new(seed: u32) -> Decorator {
+        Decorator { seed }
+    }
+
+    /// Decorates `chunk` and returns the edits that fall into other chunks.
+    pub fn decorate(&self, coord: ChunkCoord, chunk: &mut Chunk) -> Vec<(ChunkCoord, BlockEdit)> {
+        let mut rng = Rng::for_chunk(self.seed, coord, DECORATION_SALT);
+
+        // Pick every site before placing anything, so that decorations in this chunk can't
+        // affect where the others go.
+        let mut sites = Vec::new();
+        let attempts = rng.range(0, 10);
+        for _ in 0..attempts {
+            let x = rng.range(0, CHUNK_SIDE_LENGTH as i32);
+            let z = rng.range(0, CHUNK_SIDE_LENGTH as i32);
+            let roll = rng.next_f64();
+            let variant = rng.next_u64();
+            if let Some(surface) = surface_height(chunk, x, z) {
+                sites.push((Coord { x, y: surface, z }, roll, variant));
+            }
+        }
+
+        let mut sink = DecorationSink {
+            coord,
+            chunk,
+            foreign: Vec::new(),
+        };
+
+        for (site, roll, variant) in sites {
+            let biome = sink.chunk.get_biome(site.x, site.z);
+            let surface = sink.chunk.get(site).ty;
+            let (tree_chance, boulder_chance) = match biome {
+                Biome::Forest => (0.8, 0.05),
+                Biome::Plains => (0.08, 0.02),
+                Biome::Tundra => (0.04, 0.05),
+                Biome::Mountains => (0.02, 0.25),
+                Biome::Desert => (0.0, 0.0),
+            };
+
+            if roll < tree_chance {
+                if surface == BlockType::Grass || surface == BlockType::Dirt {
+                    place_tree(&mut sink, site, variant);
+                }
+            } else if roll < tree_chance + boulder_chance && !surface.is_fluid() {
+                place_boulder(&mut sink, site, variant);
+            }
+        }
+
+        sink.foreign
+    }
+}
+
+fn surface_height(chunk: &Chunk, x: i32, z: i32) -> Option<i32> {
+    (1..WORLD_HEIGHT as i32)
+        .rev()
+        .find(|&y| !chunk.get(Coord { x, y, z }).is_air())
+}
+
+/// A log trunk with a rounded canopy of leaves, `base` is the ground block it grows from.
+fn place_tree(sink: &mut DecorationSink, base: Coord, variant: u64) {
+    let trunk_height = 4 + (variant % 3) as i32;
+    let top = base.y + trunk_height;
+
+    for dy in -2..2 {
+        let radius = if dy >= 0 { 1 } else { 2 };
+        for dx in -radius..radius + 1 {
+            for dz in -radius..radius + 1 {
+                // Knock the corners off the wider layers.
+                if radius == 2 && dx.abs() == 2 && dz.abs() == 2 {
+                    continue;
+                }
+                sink.place(Coord::new(base.x + dx, top + dy, base.z + dz), BlockType::Leaf);
+            }
+        }
+    }
+
+    for y in base.y + 1..top {
+        sink.place(Coord::new(base.x, y, base.z), BlockType::Log);
+    }
+}
+
+/// A lumpy ball of cobblestone resting on the ground at `base`.
+fn place_boulder(sink: &mut DecorationSink, base: Coord, variant: u64) {
+    let radius = 1 + (variant % 2) as i32;
+    for dx in -radius..radius + 1 {
+        for dy in -radius..radius + 1 {
+            for dz in -radius..radius + 1 {
+                if dx * dx + dy * dy + dz * dz <= radius * radius + 1 {
+                    sink.place(Coord::new(base.x + dx, base.y + radius + dy, base.z + dz), BlockType::Cobblestone);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chunk_generator::TerrainGenerator;
+
+    /// Generates and decorates `coords` in order, routing spilled edits the same way the chunk
+    /// manager does, and returns the final bytes of every chunk.
+    fn generate_in_order(coords: &[ChunkCoord]) -> FnvHashMap<ChunkCoord, Vec<u8>> {
+        let terrain = TerrainGenerator::with_defaults(5);
+        let decorator = Decorator::new(5);
+        let mut chunks: FnvHashMap<ChunkCoord, Box<Chunk>> = FnvHashMap::default();
+        let mut pending = PendingEdits::new();
+
+        for &coord in coords {
+            let mut chunk = terrain.generate(coord);
+            let foreign = decorator.decorate(coord, &mut chunk);
+            if let Some(edits) = pending.take(coord) {
+                apply_edits(&mut chunk, &edits);
+            }
+            chunks.insert(coord, chunk);
+
+            for (target, edit) in foreign {
+                match chunks.get_mut(&target) {
+                    Some(chunk) => apply_edit(chunk, edit),
+                    None => pending.add(target, edit),
+                }
+            }
+        }
+
+        chunks.into_iter().map(|(coord, chunk)| (coord, chunk.to_bytes())).collect()
+    }
+
+    #[test]
+    fn decoration_does_not_depend_on_generation_order() {
+        let mut coords = Vec::new();
+        for x in -2..3 {
+            for z in -2..3 {
+                coords.push(ChunkCoord::new(x, z));
+            }
+        }
+        let forwards = generate_in_order(&coords);
+        coords.reverse();
+        let backwards = generate_in_order(&coords);
+
+        // Only the middle chunks have all of their neighbours generated.
+        for x in -1..2 {
+            for z in -1..2 {
+                let coord = ChunkCoord::new(x, z);
+                assert!(forwards[&coord] == backwards[&coord], "chunk {} differs", coord);
+            }
+        }
+    }
+
+    #[test]
+    fn pending_edits_round_trip_through_bytes() {
+        let edits = vec![
+            BlockEdit { pos: Coord::new(0, 64, 15), block: Block::new(BlockType::Leaf) },
+            BlockEdit { pos: Coord::new(7, 127, 3), block: Block::with_state(BlockType::Log, 2) },
+        ];
+        assert_eq!(edits_from_bytes(&edits_to_bytes(&edits)), Ok(edits));
+    }
+
+    #[test]
+    fn edits_without_states_still_load() {
+        let edits = edits_from_bytes(&[15, 64, 0, BlockType::Leaf as u8, 1, 2, 3, BlockType::Sand as u8]);
+        assert_eq!(edits, Ok(vec![
+            BlockEdit { pos: Coord::new(15, 64, 0), block: Block::new(BlockType::Leaf) },
+            BlockEdit { pos: Coord::new(1, 2, 3), block: Block::new(BlockType::Sand) },
+        ]));
+    }
+
+    #[test]
+    fn damaged_edits_are_errors() {
+        assert!(edits_from_bytes(&[EDITS_WITH_STATE, 1, 2, 3, BlockType::Leaf as u8]).is_err());
+        assert!(edits_from_bytes(&[1, 2, 3, 200]).is_err());
+        assert!(edits_from_bytes(&[EDITS_WITH_STATE, 16, 2, 3, BlockType::Leaf as u8, 0]).is_err());
+        assert!(edits_from_bytes(&[1, 200, 3, BlockType::Leaf as u8]).is_err());
+        assert_eq!(edits_from_bytes(&[]), Ok(Vec::new()));
+    }
+}
@@ -0,0 +1,241 @@
+use block::{ Block, BlockType };
+use chunk::{ Chunk, CHUNK_SIDE_LENGTH, WORLD_HEIGHT };
+use math::*;
+use noise_generator::NoiseTerrain;
+use world_generator::{ GeneratorConfig, GeneratorRegistry, WorldGenerator, DIRT_DEPTH };
+use world_noise::OctaveNoise;
+
+const DENSITY_NOISE_SALT: u32 = 500;
+const ISLAND_NOISE_SALT: u32 = 501;
+
+/// The density field is only evaluated on a lattice this many blocks apart and interpolated in
+/// between. The lattice is aligned to world coordinates, so neighbouring chunks share the samples
+/// on their border and the terrain joins up.
+const SAMPLE_SPACING_XZ: i32 = 4;
+const SAMPLE_SPACING_Y: i32 = 8;
+const SAMPLES_XZ: usize = CHUNK_SIDE_LENGTH / SAMPLE_SPACING_XZ as usize + 1;
+const SAMPLES_Y: usize = WORLD_HEIGHT / SAMPLE_SPACING_Y as usize + 1;
+
+/// The shape of density terrain. Part of a world preset.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DensityConfig {
+    /// The height the ground settles around.
+    pub base_height: f64,
+    /// How many blocks it takes for the height bias to cancel out a full strength noise value.
+    /// Larger values give taller, wilder terrain.
+    pub height_scale: f64,
+    /// The frequency of the lowest noise octave, in cycles per block.
+    pub frequency: f64,
+    pub octaves: usize,
+    pub persistence: f64,
+    /// Vertical distances are multiplied by this before sampling the noise. Below 1 stretches
+    /// features into cliffs and spires, above 1 flattens them into ledges.
+    pub vertical_squash: f64,
+    /// The height floating islands form around, islands are turned off by a strength of 0.
+    pub island_height: f64,
+    /// How far above and below `island_height` islands reach.
+    pub island_thickness: f64,
+    pub island_strength: f64,
+    /// How much of the sky has islands, between 0 and 1.
+    pub island_coverage: f64,
+}
+
+impl Default for DensityConfig {
+    fn default() -> DensityConfig {
+        DensityConfig {
+            base_height: 48.0,
+            height_scale: 24.0,
+            frequency: 1.0 / 96.0,
+            octaves: 4,
+            persistence: 0.5,
+            vertical_squash: 1.5,
+            island_height: 96.0,
+            island_thickness: 10.0,
+            island_strength: 0.0,
+            island_coverage: 0.3,
+        }
+    }
+}
+
+/// Terrain from a 3D density field, solid wherever the density is above zero. Unlike a
+/// heightmap this can make overhangs, arches and floating islands.
+pub struct DensityGenerator {
+    config: DensityConfig,
+}
+
+impl DensityGenerator {
+    pub const VERSION: u32 = 1;
+
+    pub fn create(_: &GeneratorRegistry, config: &GeneratorConfig) -> Box<WorldGenerator> {
+        Box::new(DensityGenerator {
+            config: config.preset.density.clone().unwrap_or_default(),
+        })
+    }
+}
+
+impl WorldGenerator for DensityGenerator {
+    fn name(&self) -> &'static str {
+        "density"
+    }
+
+    fn version(&self) -> u32 {
+        Self::VERSION
+    }
+
+    fn generate(&self, coord: ChunkCoord, seed: u32) -> Box<Chunk> {
+        let field = DensityField::new(seed, &self.config);
+        let origin_x = coord.x * CHUNK_SIDE_LENGTH as i32;
+        let origin_z = coord.z * CHUNK_SIDE_LENGTH as i32;
+
+        let mut samples = vec![0.0; SAMPLES_XZ * SAMPLES_Y * SAMPLES_XZ];
+        let sample_index = |i: usize, j: usize, k: usize| (i * SAMPLES_XZ + k) * SAMPLES_Y + j;
+        for i in 0..SAMPLES_XZ {
+            for k in 0..SAMPLES_XZ {
+                for j in 0..SAMPLES_Y {
+                    samples[sample_index(i, j, k)] = field.density(
+                        origin_x + i as i32 * SAMPLE_SPACING_XZ,
+                        j as i32 * SAMPLE_SPACING_Y,
+                        origin_z + k as i32 * SAMPLE_SPACING_XZ,
+                    );
+                }
+            }
+        }
+
+        let mut chunk = Chunk::new();
+        let biomes = NoiseTerrain::new(seed, None);
+        for x in 0..CHUNK_SIDE_LENGTH as i32 {
+            for z in 0..CHUNK_SIDE_LENGTH as i32 {
+                let biome = biomes.biome_at(origin_x + x, origin_z + z);
+                chunk.set_biome(x, z, biome);
+
+                let i = (x / SAMPLE_SPACING_XZ) as usize;
+                let k = (z / SAMPLE_SPACING_XZ) as usize;
+                let tx = (x % SAMPLE_SPACING_XZ) as f64 / SAMPLE_SPACING_XZ as f64;
+                let tz = (z % SAMPLE_SPACING_XZ) as f64 / SAMPLE_SPACING_XZ as f64;
+
+                // Work downwards so each block knows how far it is below the last air block.
+                let mut depth = -1;
+                for y in (0..WORLD_HEIGHT as i32).rev() {
+                    let j = (y / SAMPLE_SPACING_Y) as usize;
+                    let ty = (y % SAMPLE_SPACING_Y) as f64 / SAMPLE_SPACING_Y as f64;
+                    let corner = |di: usize, dj: usize, dk: usize| samples[sample_index(i + di, j + dj, k + dk)];
+                    let density = lerp(
+                        lerp(
+                            lerp(corner(0, 0, 0), corner(1, 0, 0), tx),
+                            lerp(corner(0, 0, 1), corner(1, 0, 1), tx),
+                            tz,
+                        ),
+                        lerp(
+                            lerp(corner(0, 1, 0), corner(1, 1, 0), tx),
+                            lerp(corner(0, 1, 1), corner(1, 1, 1), tx),
+                            tz,
+                        ),
+                        ty,
+                    );
+
+                    if density <= 0.0 && y > 0 {
+                        depth = -1;
+                        continue;
+                    }
+                    depth += 1;
+
+                    let block = if y == 0 {
+                        BlockType::Bedrock
+                    } else if depth == 0 {
+                        biome.surface_block()
+                    } else if depth <= DIRT_DEPTH {
+                        biome.filler_block()
+                    } else {
+                        BlockType::Stone
+                    };
+                    chunk.set(Coord { x, y, z }, Block::new(block));
+                }
+            }
+        }
+        chunk
+    }
+}
+
+/// The noise behind a `DensityGenerator` for one seed.
+struct DensityField<'a> {
+    config: &'a DensityConfig,
+    noise: OctaveNoise,
+    islands: OctaveNoise,
+}
+
+impl<'a> DensityField<'a> {
+    fn new(seed: u32, config: &'a DensityConfig) -> DensityField<'a> {
+        DensityField {
+            config,
+            noise: OctaveNoise::new(seed, DENSITY_NOISE_SALT, config.octaves, config.frequency, config.persistence),
+            islands: OctaveNoise::new(seed, ISLAND_NOISE_SALT, 2, config.frequency / 2.0, 0.5),
+        }
+    }
+
+    /// Positive where the block at world position `x`, `y`, `z` is solid.
+    fn density(&self, x: i32, y: i32, z: i32) -> f64 {
+        let config = self.config;
+        let (x, y, z) = (x as f64, y as f64, z as f64);
+        let noise = self.noise.get3(x, y * config.vertical_squash, z);
+        let mut density = noise + (config.base_height - y) / config.height_scale;
+
+        // Islands are a lens around `island_height`, only where the 2D mask clears the threshold.
+        if config.island_strength > 0.0 && config.island_thickness > 0.0 {
+            let lens = 1.0 - (y - config.island_height).abs() / config.island_thickness;
+            if lens > 0.0 {
+                let mask = self.islands.get2(x, z) - (1.0 - 2.0 * config.island_coverage);
+                if mask > 0.0 {
+                    density += lens * mask * config.island_strength + noise * lens;
+                }
+            }
+        }
+        density
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generator() -> DensityGenerator {
+        DensityGenerator {
+            config: DensityConfig {
+                island_strength: 4.0,
+                ..DensityConfig::default()
+            },
+        }
+    }
+
+    #[test]
+    fn same_seed_gives_same_chunk() {
+        let coord = ChunkCoord::new(-3, 8);
+        assert!(generator().generate(coord, 12).to_bytes() == generator().generate(coord, 12).to_bytes());
+    }
+
+    #[test]
+    fn solid_below_and_open_above() {
+        let generator = generator();
+        for &coord in [ChunkCoord::new(0, 0), ChunkCoord::new(40, -7)].iter() {
+            let chunk = generator.generate(coord, 5);
+            for x in 0..CHUNK_SIDE_LENGTH as i32 {
+                for z in 0..CHUNK_SIDE_LENGTH as i32 {
+                    assert!(chunk.get(Coord::new(x, 0, z)).ty == BlockType::Bedrock);
+                    assert!(chunk.get(Coord::new(x, 1, z)).ty != BlockType::Air);
+                    assert!(chunk.get(Coord::new(x, WORLD_HEIGHT as i32 - 1, z)).is_air());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lattice_points_match_the_field() {
+        let config = DensityConfig::default();
+        let field = DensityField::new(9, &config);
+        let chunk = DensityGenerator { config: config.clone() }.generate(ChunkCoord::new(1, 2), 9);
+        for &(x, y, z) in [(0, 16, 0), (4, 40, 8), (12, 48, 12), (8, 64, 4)].iter() {
+            let solid = field.density(16 + x, y, 32 + z) > 0.0;
+            assert_eq!(!chunk.get(Coord::new(x, y, z)).is_air(), solid);
+        }
+    }
+}
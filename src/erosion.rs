@@ -0,0 +1,308 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use fnv::FnvHashMap;
+
+use chunk::{ CHUNK_COLUMN_COUNT, CHUNK_SIDE_LENGTH, WORLD_HEIGHT };
+use math::*;
+use noise_generator::NoiseTerrain;
+use random::Rng;
+
+const EROSION_SALT: u32 = 600;
+
+/// Erosion is simulated over square regions of this many chunks, so a droplet can run further
+/// than one chunk, and every chunk in a region is cut from the same result.
+const REGION_CHUNKS: i32 = 8;
+const REGION_SIZE: i32 = REGION_CHUNKS * CHUNK_SIDE_LENGTH as i32;
+/// Extra columns simulated around each region, so water flowing in from outside still shapes
+/// the columns near its edge. Only the region itself is kept.
+const MARGIN: i32 = 16;
+/// Regions are simulated separately, so the changes fade out over this many columns towards
+/// each region edge. The edge columns keep their original height, which both sides agree on.
+const FADE_WIDTH: f64 = 8.0;
+/// Sediment deeper than this is filled in with the usual blocks underneath.
+const MAX_DEPOSIT_DEPTH: f64 = 8.0;
+/// How many eroded regions are kept around for the chunks still to come.
+const MAX_CACHED_REGIONS: usize = 32;
+
+/// Settings for the erosion pass. Part of a world preset.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ErosionConfig {
+    /// How many droplets run over each region.
+    pub droplets: u32,
+    /// How many steps a droplet takes before it is dropped, with whatever it still carries.
+    pub max_steps: u32,
+    /// Between 0 and 1, how much a droplet keeps its direction instead of following the slope.
+    pub inertia: f64,
+    /// How much sediment a droplet can carry for its speed, water and the slope it is on.
+    pub capacity: f64,
+    pub min_capacity: f64,
+    /// The fraction of its spare capacity a droplet picks up from the ground each step.
+    pub erode_rate: f64,
+    /// The fraction of its excess sediment a droplet leaves behind each step.
+    pub deposit_rate: f64,
+    /// The fraction of its water a droplet loses each step.
+    pub evaporation: f64,
+    pub gravity: f64,
+}
+
+impl Default for ErosionConfig {
+    fn default() -> ErosionConfig {
+        ErosionConfig {
+            droplets: 20000,
+            max_steps: 48,
+            inertia: 0.05,
+            capacity: 4.0,
+            min_capacity: 0.01,
+            erode_rate: 0.3,
+            deposit_rate: 0.3,
+            evaporation: 0.02,
+            gravity: 4.0,
+        }
+    }
+}
+
+/// The eroded heightmap of one region, indexed by `x + z * REGION_SIZE`.
+struct ErodedRegion {
+    heights: Vec<i32>,
+    /// How many blocks at the top of each column are sediment.
+    deposits: Vec<u8>,
+}
+
+/// Runs hydraulic erosion over the heightmap of `NoiseTerrain` before chunks are filled. The
+/// result for a region only depends on the seed and the region, and is cached so that each
+/// chunk in it doesn't simulate it again.
+pub struct Eroder {
+    config: ErosionConfig,
+    regions: RefCell<FnvHashMap<(u32, i32, i32), Rc<ErodedRegion>>>,
+    /// The cached regions, oldest first.
+    order: RefCell<VecDeque<(u32, i32, i32)>>,
+}
+
+impl Eroder {
+    pub fn new(config: ErosionConfig) -> Eroder {
+        Eroder {
+            config,
+            regions: RefCell::new(FnvHashMap::default()),
+            order: RefCell::new(VecDeque::new()),
+        }
+    }
+
+    /// The eroded height and sediment depth of each column in the chunk at `coord`, indexed by
+    /// `x + z * CHUNK_SIDE_LENGTH`.
+    pub fn chunk_columns(
+        &self,
+        terrain: &NoiseTerrain,
+        seed: u32,
+        coord: ChunkCoord,
+    ) -> ([i32; CHUNK_COLUMN_COUNT], [u8; CHUNK_COLUMN_COUNT]) {
+        let region_x = floor_div(coord.x, REGION_CHUNKS);
+        let region_z = floor_div(coord.z, REGION_CHUNKS);
+        let region = self.region(terrain, seed, region_x, region_z);
+
+        let offset_x = (coord.x - region_x * REGION_CHUNKS) * CHUNK_SIDE_LENGTH as i32;
+        let offset_z = (coord.z - region_z * REGION_CHUNKS) * CHUNK_SIDE_LENGTH as i32;
+        let mut heights = [0; CHUNK_COLUMN_COUNT];
+        let mut deposits = [0; CHUNK_COLUMN_COUNT];
+        for z in 0..CHUNK_SIDE_LENGTH as i32 {
+            for x in 0..CHUNK_SIDE_LENGTH as i32 {
+                let column = (x + z * CHUNK_SIDE_LENGTH as i32) as usize;
+                let i = (offset_x + x + (offset_z + z) * REGION_SIZE) as usize;
+                heights[column] = region.heights[i];
+                deposits[column] = region.deposits[i];
+            }
+        }
+        (heights, deposits)
+    }
+
+    fn region(&self, terrain: &NoiseTerrain, seed: u32, region_x: i32, region_z: i32) -> Rc<ErodedRegion> {
+        let key = (seed, region_x, region_z);
+        if let Some(region) = self.regions.borrow().get(&key) {
+            return region.clone();
+        }
+
+        let region = Rc::new(self.erode_region(terrain, seed, region_x, region_z));
+        let mut regions = self.regions.borrow_mut();
+        let mut order = self.order.borrow_mut();
+        if order.len() >= MAX_CACHED_REGIONS {
+            if let Some(oldest) = order.pop_front() {
+                regions.remove(&oldest);
+            }
+        }
+        regions.insert(key, region.clone());
+        order.push_back(key);
+        region
+    }
+
+    fn erode_region(&self, terrain: &NoiseTerrain, seed: u32, region_x: i32, region_z: i32) -> ErodedRegion {
+        let size = REGION_SIZE + 2 * MARGIN;
+        let original = terrain.height_grid(region_x * REGION_SIZE - MARGIN, region_z * REGION_SIZE - MARGIN, size);
+        let mut heights: Vec<f64> = original.iter().map(|&h| h as f64).collect();
+        let mut sediment = vec![0.0; heights.len()];
+        let mut rng = Rng::for_chunk(seed, ChunkCoord::new(region_x, region_z), EROSION_SALT);
+        erode(&mut heights, &mut sediment, size as usize, &self.config, &mut rng);
+
+        let mut region = ErodedRegion {
+            heights: Vec::with_capacity((REGION_SIZE * REGION_SIZE) as usize),
+            deposits: Vec::with_capacity((REGION_SIZE * REGION_SIZE) as usize),
+        };
+        for z in 0..REGION_SIZE {
+            for x in 0..REGION_SIZE {
+                let edge_distance = x.min(z).min(REGION_SIZE - 1 - x).min(REGION_SIZE - 1 - z);
+                let fade = (edge_distance as f64 / FADE_WIDTH).min(1.0);
+                let i = (x + MARGIN + (z + MARGIN) * size) as usize;
+
+                let change = (heights[i] - original[i] as f64) * fade;
+                let height = (original[i] as f64 + change).round() as i32;
+                let height = clamp(height, 1, WORLD_HEIGHT as i32 - 1);
+                let deposit = clamp(sediment[i] * fade, 0.0, MAX_DEPOSIT_DEPTH).floor() as i32;
+                // Sediment only shows where the ground actually built up.
+                let deposit = deposit.min(height - original[i]).max(0);
+
+                region.heights.push(height);
+                region.deposits.push(deposit as u8);
+            }
+        }
+        region
+    }
+}
+
+/// Runs `config.droplets` droplets of water over the `size` by `size` heightmap. Each one rolls
+/// downhill, picking up material where it speeds up and dropping it where it slows down or
+/// goes uphill. `sediment` keeps the net amount laid down at each point.
+fn erode(heights: &mut [f64], sediment: &mut [f64], size: usize, config: &ErosionConfig, rng: &mut Rng) {
+    let limit = (size - 1) as f64;
+    for _ in 0..config.droplets {
+        let mut x = rng.next_f64() * limit;
+        let mut z = rng.next_f64() * limit;
+        let (mut dir_x, mut dir_z) = (0.0, 0.0);
+        let mut speed = 1.0;
+        let mut water = 1.0;
+        let mut carried = 0.0;
+
+        for _ in 0..config.max_steps {
+            let (height, gradient_x, gradient_z) = sample(heights, size, x, z);
+            dir_x = dir_x * config.inertia - gradient_x * (1.0 - config.inertia);
+            dir_z = dir_z * config.inertia - gradient_z * (1.0 - config.inertia);
+            let length = (dir_x * dir_x + dir_z * dir_z).sqrt();
+            if length < 1e-9 {
+                // Flat ground, the droplet soaks in.
+                break;
+            }
+            dir_x /= length;
+            dir_z /= length;
+
+            let (old_x, old_z) = (x, z);
+            x += dir_x;
+            z += dir_z;
+            if x < 0.0 || z < 0.0 || x >= limit || z >= limit {
+                break;
+            }
+
+            let (new_height, _, _) = sample(heights, size, x, z);
+            let delta = new_height - height;
+            let capacity = (-delta * speed * water * config.capacity).max(config.min_capacity);
+            if delta > 0.0 || carried > capacity {
+                // Going uphill fills the dip behind, otherwise only the excess settles.
+                let amount = if delta > 0.0 {
+                    delta.min(carried)
+                } else {
+                    (carried - capacity) * config.deposit_rate
+                };
+                carried -= amount;
+                spread(heights, size, old_x, old_z, amount);
+                spread(sediment, size, old_x, old_z, amount);
+            } else {
+                // Never dig deeper than the step down, or the droplet would dig a pit.
+                let amount = ((capacity - carried) * config.erode_rate).min(-delta);
+                carried += amount;
+                spread(heights, size, old_x, old_z, -amount);
+                spread(sediment, size, old_x, old_z, -amount);
+            }
+
+            speed = (speed * speed - delta * config.gravity).max(0.0).sqrt();
+            water *= 1.0 - config.evaporation;
+        }
+    }
+}
+
+/// The bilinearly interpolated height at `x`, `z` and the slope of the cell it's in.
+fn sample(heights: &[f64], size: usize, x: f64, z: f64) -> (f64, f64, f64) {
+    let (cell_x, cell_z) = (x.floor() as usize, z.floor() as usize);
+    let (u, v) = (x - cell_x as f64, z - cell_z as f64);
+    let i = cell_x + cell_z * size;
+    let (h00, h10, h01, h11) = (heights[i], heights[i + 1], heights[i + size], heights[i + size + 1]);
+
+    let gradient_x = (h10 - h00) * (1.0 - v) + (h11 - h01) * v;
+    let gradient_z = (h01 - h00) * (1.0 - u) + (h11 - h10) * u;
+    let height = h00 * (1.0 - u) * (1.0 - v) + h10 * u * (1.0 - v) + h01 * (1.0 - u) * v + h11 * u * v;
+    (height, gradient_x, gradient_z)
+}
+
+/// Adds `amount` to the four points around `x`, `z`, weighted by how close they are.
+fn spread(map: &mut [f64], size: usize, x: f64, z: f64, amount: f64) {
+    let (cell_x, cell_z) = (x.floor() as usize, z.floor() as usize);
+    let (u, v) = (x - cell_x as f64, z - cell_z as f64);
+    let i = cell_x + cell_z * size;
+    map[i] += amount * (1.0 - u) * (1.0 - v);
+    map[i + 1] += amount * u * (1.0 - v);
+    map[i + size] += amount * (1.0 - u) * v;
+    map[i + size + 1] += amount * u * v;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn columns(seed: u32, coord: ChunkCoord) -> ([i32; CHUNK_COLUMN_COUNT], [u8; CHUNK_COLUMN_COUNT]) {
+        let terrain = NoiseTerrain::new(seed, Some(36));
+        Eroder::new(ErosionConfig::default()).chunk_columns(&terrain, seed, coord)
+    }
+
+    #[test]
+    fn same_seed_gives_same_heights() {
+        let coord = ChunkCoord::new(-5, 11);
+        let (heights, deposits) = columns(3, coord);
+        let (again, deposits_again) = columns(3, coord);
+        assert!(heights[..] == again[..]);
+        assert!(deposits[..] == deposits_again[..]);
+    }
+
+    #[test]
+    fn region_edges_keep_their_height() {
+        let terrain = NoiseTerrain::new(8, Some(36));
+        let eroder = Eroder::new(ErosionConfig::default());
+        // The chunks either side of the border between regions 0 and 1 on the x axis.
+        for &chunk_x in [REGION_CHUNKS - 1, REGION_CHUNKS].iter() {
+            let coord = ChunkCoord::new(chunk_x, 2);
+            let (heights, _) = eroder.chunk_columns(&terrain, 8, coord);
+            let x = if chunk_x == REGION_CHUNKS { 0 } else { CHUNK_SIDE_LENGTH as i32 - 1 };
+            for z in 0..CHUNK_SIDE_LENGTH as i32 {
+                let world_x = chunk_x * CHUNK_SIDE_LENGTH as i32 + x;
+                let world_z = 2 * CHUNK_SIDE_LENGTH as i32 + z;
+                let column = (x + z * CHUNK_SIDE_LENGTH as i32) as usize;
+                assert_eq!(heights[column], terrain.height_at(world_x, world_z));
+            }
+        }
+    }
+
+    #[test]
+    fn droplets_carry_material_downhill() {
+        // A slope falling away along x, flattening out at the bottom.
+        let size = 32;
+        let mut heights: Vec<f64> = (0..size * size)
+            .map(|i| (24.0 - (i % size) as f64).max(0.0) * 2.0)
+            .collect();
+        let before = heights.clone();
+        let mut sediment = vec![0.0; heights.len()];
+        let config = ErosionConfig { droplets: 2000, ..ErosionConfig::default() };
+        erode(&mut heights, &mut sediment, size, &config, &mut Rng::new(1));
+
+        let slope: f64 = (0..size * size).filter(|i| i % size < 20).map(|i| heights[i] - before[i]).sum();
+        let foot: f64 = (0..size * size).filter(|i| i % size > 24).map(|i| sediment[i]).sum();
+        assert!(slope < 0.0);
+        assert!(foot > 0.0);
+    }
+}
@@ -0,0 +1,296 @@
+use image;
+use image::{ GrayImage, RgbImage };
+
+use biome::Biome;
+use block::{ Block, BlockType };
+use chunk::{ Chunk, CHUNK_SIDE_LENGTH, WORLD_HEIGHT };
+use math::*;
+use world_generator::{ GeneratorConfig, GeneratorRegistry, WorldGenerator, DIRT_DEPTH };
+
+/// Where to find a heightmap image and how to place it in the world. Part of a world preset.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HeightmapConfig {
+    /// A grayscale image, black is `min_height` and white is `max_height`.
+    pub path: String,
+    /// An optional image the same size as the heightmap, whose colours pick the surface blocks.
+    #[serde(default)]
+    pub color_path: Option<String>,
+    /// The world position of the top left pixel. Each pixel is one column, x to the right and z
+    /// downwards.
+    pub origin_x: i32,
+    pub origin_z: i32,
+    pub min_height: i32,
+    pub max_height: i32,
+    /// The generator used for columns outside of the image.
+    pub fallback: String,
+}
+
+/// The colours a designer can paint into the colour image, anything else picks the closest.
+static SURFACE_PALETTE: [([u8; 3], BlockType); 7] = [
+    ([ 95, 159,  53], BlockType::Grass),
+    ([134,  96,  67], BlockType::Dirt),
+    ([219, 207, 163], BlockType::Sand),
+    ([216, 200, 140], BlockType::Sandstone),
+    ([125, 125, 125], BlockType::Stone),
+    ([ 80,  80,  80], BlockType::Cobblestone),
+    ([150, 140, 165], BlockType::Gravel),
+];
+
+/// Terrain taken from a grayscale image, with another generator filling in around it.
+pub struct HeightmapGenerator {
+    heights: GrayImage,
+    colors: Option<RgbImage>,
+    config: HeightmapConfig,
+    fallback: Box<WorldGenerator>,
+}
+
+impl HeightmapGenerator {
+    pub const VERSION: u32 = 1;
+
+    pub fn create(registry: &GeneratorRegistry, config: &GeneratorConfig) -> Box<WorldGenerator> {
+        let heightmap = match config.preset.heightmap {
+            Some(ref heightmap) => heightmap.clone(),
+            None => {
+                warn!("The heightmap generator needs a [heightmap] section in the preset, using noise.");
+                return registry.create("noise", config).unwrap();
+            }
+        };
+
+        let fallback_name = if heightmap.fallback == "heightmap" { "noise" } else { &heightmap.fallback[..] };
+        let fallback = registry.create(fallback_name, config).unwrap_or_else(|| {
+            warn!("No world generator called {:?} for the heightmap fallback, using noise.", fallback_name);
+            registry.create("noise", config).unwrap()
+        });
+
+        let heights = match image::open(&heightmap.path) {
+            Ok(image) => image.to_luma(),
+            Err(e) => {
+                warn!("Failed to load heightmap {:?}: {}, using {:?}.", heightmap.path, e, fallback.name());
+                return fallback;
+            }
+        };
+
+        let colors = heightmap.color_path.as_ref().and_then(|path| {
+            match image::open(path) {
+                Ok(image) => Some(image.to_rgb()),
+                Err(e) => {
+                    warn!("Failed to load heightmap colours {:?}: {}.", path, e);
+                    None
+                }
+            }
+        });
+
+        Box::new(HeightmapGenerator {
+            heights,
+            colors,
+            config: heightmap,
+            fallback,
+        })
+    }
+
+    /// The pixel covering the column at world position `x`, `z`, if the image covers it.
+    fn pixel_at(&self, x: i32, z: i32) -> Option<(u32, u32)> {
+        let px = x - self.config.origin_x;
+        let pz = z - self.config.origin_z;
+        let (width, height) = self.heights.dimensions();
+        if px >= 0 && pz >= 0 && (px as u32) < width && (pz as u32) < height {
+            Some((px as u32, pz as u32))
+        } else {
+            None
+        }
+    }
+
+    fn column_height(&self, (px, pz): (u32, u32)) -> i32 {
+        let brightness = self.heights.get_pixel(px, pz).data[0] as i32;
+        let range = self.config.max_height - self.config.min_height;
+        clamp(self.config.min_height + brightness * range / 255, 1, WORLD_HEIGHT as i32 - 1)
+    }
+
+    fn surface_block(&self, (px, pz): (u32, u32)) -> BlockType {
+        let colors = match self.colors {
+            Some(ref colors) => colors,
+            None => return BlockType::Grass,
+        };
+
+        let (width, height) = colors.dimensions();
+        if px >= width || pz >= height {
+            return BlockType::Grass;
+        }
+
+        let color = colors.get_pixel(px, pz).data;
+        let distance = |c: &[u8; 3]| -> i32 {
+            (0..3).map(|i| (c[i] as i32 - color[i] as i32).pow(2)).sum()
+        };
+        SURFACE_PALETTE.iter()
+            .min_by_key(|&&(ref c, _)| distance(c))
+            .map(|&(_, ty)| ty)
+            .unwrap()
+    }
+}
+
+fn filler_block(surface: BlockType) -> BlockType {
+    match surface {
+        BlockType::Grass => BlockType::Dirt,
+        BlockType::Sand => BlockType::Sandstone,
+        other => other,
+    }
+}
+
+fn biome_for_surface(surface: BlockType) -> Biome {
+    match surface {
+        BlockType::Sand | BlockType::Sandstone => Biome::Desert,
+        BlockType::Stone | BlockType::Cobblestone | BlockType::Gravel => Biome::Mountains,
+        _ => Biome::Plains,
+    }
+}
+
+impl WorldGenerator for HeightmapGenerator {
+    fn name(&self) -> &'static str {
+        "heightmap"
+    }
+
+    fn version(&self) -> u32 {
+        Self::VERSION
+    }
+
+    fn generate(&self, coord: ChunkCoord, seed: u32) -> Box<Chunk> {
+        let origin_x = coord.x * CHUNK_SIDE_LENGTH as i32;
+        let origin_z = coord.z * CHUNK_SIDE_LENGTH as i32;
+
+        let mut chunk = Chunk::new();
+        let mut any_outside = false;
+        for x in 0..CHUNK_SIDE_LENGTH as i32 {
+            for z in 0..CHUNK_SIDE_LENGTH as i32 {
+                if self.pixel_at(origin_x + x, origin_z + z).is_none() {
+                    any_outside = true;
+                }
+            }
+        }
+        if any_outside {
+            chunk = self.fallback.generate(coord, seed);
+        }
+
+        for x in 0..CHUNK_SIDE_LENGTH as i32 {
+            for z in 0..CHUNK_SIDE_LENGTH as i32 {
+                let pixel = match self.pixel_at(origin_x + x, origin_z + z) {
+                    Some(pixel) => pixel,
+                    None => continue,
+                };
+
+                let height = self.column_height(pixel);
+                let surface = self.surface_block(pixel);
+                chunk.set_biome(x, z, biome_for_surface(surface));
+
+                for y in 0..WORLD_HEIGHT as i32 {
+                    let ty = if y == 0 {
+                        BlockType::Bedrock
+                    } else if y == height {
+                        surface
+                    } else if y > height {
+                        BlockType::Air
+                    } else if y >= height - DIRT_DEPTH {
+                        filler_block(surface)
+                    } else {
+                        BlockType::Stone
+                    };
+                    chunk.set(Coord { x, y, z }, Block::new(ty));
+                }
+            }
+        }
+
+        chunk
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ ImageBuffer, Luma, Rgb };
+    use preset::WorldPreset;
+    use world_generator::VoidGenerator;
+
+    /// The image covers world x -4..-1 and z 8..9, the far corner of chunk (-1, 0).
+    fn config() -> HeightmapConfig {
+        HeightmapConfig {
+            path: "no such heightmap.png".to_string(),
+            color_path: None,
+            origin_x: -4,
+            origin_z: 8,
+            min_height: 10,
+            max_height: 61,
+            fallback: "void".to_string(),
+        }
+    }
+
+    /// A 4 by 2 heightmap getting brighter to the right, so its columns are 10, 27, 44 and 61
+    /// high.
+    fn generator(colors: Option<RgbImage>) -> HeightmapGenerator {
+        HeightmapGenerator {
+            heights: ImageBuffer::from_fn(4, 2, |x, _| Luma([x as u8 * 85])),
+            colors,
+            config: config(),
+            fallback: Box::new(VoidGenerator),
+        }
+    }
+
+    #[test]
+    fn brightness_sets_the_height() {
+        let chunk = generator(None).generate(ChunkCoord::new(-1, 0), 1);
+        for (px, &height) in [10, 27, 44, 61].iter().enumerate() {
+            let (x, z) = (12 + px as i32, 9);
+            assert_eq!(chunk.get(Coord::new(x, 0, z)).ty, BlockType::Bedrock);
+            assert_eq!(chunk.get(Coord::new(x, height - 1, z)).ty, BlockType::Dirt);
+            assert_eq!(chunk.get(Coord::new(x, height, z)).ty, BlockType::Grass);
+            assert_eq!(chunk.get(Coord::new(x, height + 1, z)).ty, BlockType::Air);
+        }
+    }
+
+    #[test]
+    fn the_origin_anchors_the_top_left_pixel() {
+        let generator = generator(None);
+        assert_eq!(generator.pixel_at(-4, 8), Some((0, 0)));
+        assert_eq!(generator.pixel_at(-1, 9), Some((3, 1)));
+        assert_eq!(generator.pixel_at(-5, 8), None);
+        assert_eq!(generator.pixel_at(0, 8), None);
+        assert_eq!(generator.pixel_at(-4, 7), None);
+        assert_eq!(generator.pixel_at(-4, 10), None);
+    }
+
+    #[test]
+    fn colours_pick_the_surface() {
+        // Sand on the left, something close to stone on the right, and one column short so the
+        // last column falls back to grass.
+        let colors = ImageBuffer::from_fn(3, 2, |x, _| if x < 2 { Rgb([219, 207, 163]) } else { Rgb([120, 128, 118]) });
+        let chunk = generator(Some(colors)).generate(ChunkCoord::new(-1, 0), 1);
+
+        assert_eq!(chunk.get(Coord::new(12, 10, 8)).ty, BlockType::Sand);
+        assert_eq!(chunk.get(Coord::new(12, 9, 8)).ty, BlockType::Sandstone);
+        assert_eq!(chunk.get_biome(12, 8), Biome::Desert);
+        assert_eq!(chunk.get(Coord::new(14, 44, 8)).ty, BlockType::Stone);
+        assert_eq!(chunk.get_biome(14, 8), Biome::Mountains);
+        assert_eq!(chunk.get(Coord::new(15, 61, 8)).ty, BlockType::Grass);
+    }
+
+    #[test]
+    fn the_fallback_fills_in_around_the_image() {
+        let generator = generator(None);
+        let chunk = generator.generate(ChunkCoord::new(-1, 0), 1);
+        // Just outside the image, on either side of it.
+        assert_eq!(chunk.get(Coord::new(11, 0, 8)).ty, BlockType::Air);
+        assert_eq!(chunk.get(Coord::new(12, 0, 10)).ty, BlockType::Air);
+
+        let chunk = generator.generate(ChunkCoord::new(5, 5), 1);
+        assert!(chunk.iter().all(|(_, block)| block.is_air()));
+    }
+
+    #[test]
+    fn missing_images_use_the_fallback() {
+        let preset = WorldPreset {
+            terrain: "heightmap".to_string(),
+            heightmap: Some(config()),
+            ..WorldPreset::default()
+        };
+        let generator = HeightmapGenerator::create(&GeneratorRegistry::new(), &GeneratorConfig { preset: &preset });
+        assert_eq!(generator.name(), "void");
+    }
+}
@@ -0,0 +1,89 @@
+use block::BlockType;
+
+/// The number of slots the player has.
+pub const INVENTORY_SIZE: usize = 9;
+
+/// The most blocks of one type a slot can hold.
+pub const MAX_STACK: u32 = 64;
+
+/// A number of blocks of the same type, in one slot.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Stack {
+    pub block: BlockType,
+    pub count: u32,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Inventory {
+    pub slots: [Option<Stack>; INVENTORY_SIZE],
+    /// The slot the player has picked.
+    pub selected: usize,
+}
+
+impl Inventory {
+    pub fn new() -> Inventory {
+        Inventory {
+            slots: [None; INVENTORY_SIZE],
+            selected: 0,
+        }
+    }
+
+    /// What a new player starts with.
+    pub fn starting() -> Inventory {
+        use block::BlockType::*;
+        let mut inventory = Inventory::new();
+        let blocks = [Stone, Cobblestone, Dirt, Grass, Sand, Wood, Log, Leaf, Sponge];
+        for (slot, &block) in inventory.slots.iter_mut().zip(blocks.iter()) {
+            *slot = Some(Stack { block, count: MAX_STACK });
+        }
+        inventory
+    }
+
+    /// The selected slot, then five bytes for each slot: the block type, 0 for an empty slot,
+    /// and the count as a little endian u32.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.selected as u8];
+        for slot in self.slots.iter() {
+            let (block, count) = match *slot {
+                Some(stack) => (stack.block as u8, stack.count),
+                None => (BlockType::Air as u8, 0),
+            };
+            bytes.push(block);
+            for i in 0..4 {
+                bytes.push((count >> (i * 8)) as u8);
+            }
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Inventory, String> {
+        if bytes.len() != 1 + INVENTORY_SIZE * 5 {
+            return Err(format!("an inventory is {} bytes, not {}", 1 + INVENTORY_SIZE * 5, bytes.len()));
+        }
+        let mut inventory = Inventory::new();
+        inventory.selected = (bytes[0] as usize).min(INVENTORY_SIZE - 1);
+        for (slot, data) in inventory.slots.iter_mut().zip(bytes[1..].chunks(5)) {
+            let block = BlockType::from_id(data[0]).ok_or_else(|| format!("unknown block type {}", data[0]))?;
+            let count = (0..4).fold(0, |count, i| count | (data[1 + i] as u32) << (i * 8));
+            if block != BlockType::Air && count > 0 {
+                *slot = Some(Stack { block, count: count.min(MAX_STACK) });
+            }
+        }
+        Ok(inventory)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_round_trip() {
+        let mut inventory = Inventory::starting();
+        inventory.selected = 4;
+        inventory.slots[4] = Some(Stack { block: BlockType::Sand, count: 3 });
+        inventory.slots[2] = None;
+        assert_eq!(Inventory::from_bytes(&inventory.to_bytes()), Ok(inventory));
+        assert!(Inventory::from_bytes(&[0; 3]).is_err());
+    }
+}
@@ -0,0 +1,551 @@
+use std::cell::Cell;
+use std::ffi::{ CStr, CString };
+use std::fs::File;
+use std::io::prelude::*;
+use std::os::raw::{ c_char, c_int };
+use std::ptr;
+
+use fnv::FnvHashMap;
+
+use self::ffi::{
+    lua_State, luaL_loadbuffer, luaL_newstate, luaL_openlibs, lua_close, lua_getfield, lua_pcall,
+    lua_createtable, lua_pushcclosure, lua_pushinteger, lua_pushlightuserdata, lua_pushnumber,
+    lua_setfield, lua_settop, lua_tolstring, lua_tonumber, lua_touserdata, lua_type,
+};
+
+use block::{ Block, BlockType, BLOCK_TYPE_COUNT };
+use chunk::{ Chunk, CHUNK_SIDE_LENGTH, WORLD_HEIGHT };
+use math::*;
+use preset::LuaConfig;
+use world_generator::{ GeneratorConfig, GeneratorRegistry, GeneratorStamp, WorldGenerator };
+use world_noise::OctaveNoise;
+
+/// The parts of the LuaJIT C API the generator uses. Declared here rather than taken from
+/// `luajit-sys`, whose `lua_CFunction` is an empty struct rather than a function pointer, so
+/// Rust functions can't be handed to Lua through it. `bindings_match_luajit` checks these.
+#[allow(non_camel_case_types)]
+mod ffi {
+    use std::os::raw::{ c_char, c_double, c_int, c_void };
+
+    pub enum lua_State {}
+
+    pub type lua_CFunction = extern "C" fn(*mut lua_State) -> c_int;
+
+    #[link(name = "luajit-5.1")]
+    extern "C" {
+        pub fn luaL_newstate() -> *mut lua_State;
+        pub fn luaL_openlibs(state: *mut lua_State);
+        pub fn luaL_loadbuffer(state: *mut lua_State, buffer: *const c_char, size: usize, name: *const c_char) -> c_int;
+        pub fn lua_close(state: *mut lua_State);
+        pub fn lua_pcall(state: *mut lua_State, nargs: c_int, nresults: c_int, errfunc: c_int) -> c_int;
+        pub fn lua_getfield(state: *mut lua_State, index: c_int, key: *const c_char);
+        pub fn lua_setfield(state: *mut lua_State, index: c_int, key: *const c_char);
+        pub fn lua_createtable(state: *mut lua_State, narr: c_int, nrec: c_int);
+        pub fn lua_settop(state: *mut lua_State, index: c_int);
+        pub fn lua_type(state: *mut lua_State, index: c_int) -> c_int;
+        pub fn lua_pushcclosure(state: *mut lua_State, function: lua_CFunction, n: c_int);
+        pub fn lua_pushinteger(state: *mut lua_State, n: isize);
+        pub fn lua_pushnumber(state: *mut lua_State, n: c_double);
+        pub fn lua_pushlightuserdata(state: *mut lua_State, p: *mut c_void);
+        pub fn lua_tolstring(state: *mut lua_State, index: c_int, len: *mut usize) -> *const c_char;
+        pub fn lua_tonumber(state: *mut lua_State, index: c_int) -> c_double;
+        pub fn lua_touserdata(state: *mut lua_State, index: c_int) -> *mut c_void;
+    }
+}
+
+// These are macros in the Lua headers, so they aren't part of the API above.
+const LUA_GLOBALSINDEX: c_int = -10002;
+const LUA_TNUMBER: c_int = 3;
+const LUA_TFUNCTION: c_int = 6;
+
+fn lua_upvalueindex(i: c_int) -> c_int {
+    LUA_GLOBALSINDEX - i
+}
+
+/// Keeps the noise fields scripts ask for apart from the ones the built in passes use.
+const SCRIPT_NOISE_SALT: u32 = 1000;
+
+/// Terrain made by a Lua script.
+///
+/// The script must define a global function `generate(chunk_x, chunk_z, seed)`, which fills in
+/// the chunk through these globals:
+///
+/// - `set_block(x, y, z, block [, state])` and `get_block(x, y, z)`, with coordinates local to
+///   the chunk. Blocks are numbers, named in the `blocks` table, e.g. `blocks.Stone`, and
+///   `get_block` returns the block's state second.
+/// - `noise2(salt, x, z [, octaves])` and `noise3(salt, x, y, z [, octaves])`, Perlin noise
+///   seeded from the world seed and `salt`, roughly in the range -1 to 1.
+/// - `CHUNK_SIDE_LENGTH` and `WORLD_HEIGHT`.
+///
+/// If the script raises an error, or misuses one of the functions, the chunk is made by the
+/// fallback generator instead and stamped with version 0, so it's listed as outdated and can be
+/// regenerated once the script is fixed.
+pub struct LuaGenerator {
+    state: *mut lua_State,
+    /// Owned by the generator, Lua holds pointers to it as an upvalue of every function.
+    context: *mut ScriptContext,
+    fallback: Box<WorldGenerator>,
+    reported_error: Cell<bool>,
+}
+
+/// What the functions given to the script work on.
+struct ScriptContext {
+    seed: u32,
+    /// The chunk being generated, null outside of a call to `generate`.
+    chunk: *mut Chunk,
+    noise: FnvHashMap<(u32, usize), OctaveNoise>,
+    /// Set when the script misuses a function. Raising a Lua error from Rust would unwind
+    /// through Rust frames, so the error is recorded and reported when the call returns.
+    error: Option<String>,
+}
+
+impl LuaGenerator {
+    pub const VERSION: u32 = 1;
+
+    pub fn create(registry: &GeneratorRegistry, config: &GeneratorConfig) -> Box<WorldGenerator> {
+        let lua = match config.preset.lua {
+            Some(ref lua) => lua.clone(),
+            None => {
+                warn!("The lua generator needs a [lua] section in the preset, using noise.");
+                return registry.create("noise", config).unwrap();
+            }
+        };
+
+        let fallback_name = if lua.fallback == "lua" { "noise" } else { &lua.fallback[..] };
+        let fallback = registry.create(fallback_name, config).unwrap_or_else(|| {
+            warn!("No world generator called {:?} for the lua fallback, using noise.", fallback_name);
+            registry.create("noise", config).unwrap()
+        });
+
+        let mut source = String::new();
+        if let Err(e) = File::open(&lua.path).and_then(|mut file| file.read_to_string(&mut source)) {
+            warn!("Failed to read generator script {:?}: {}, using {:?}.", lua.path, e, fallback.name());
+            return fallback;
+        }
+
+        match LuaGenerator::from_source(&source, &lua.path, fallback) {
+            Ok(generator) => Box::new(generator),
+            Err((message, fallback)) => {
+                warn!("Failed to load generator script {:?}: {}, using {:?}.", lua.path, message, fallback.name());
+                fallback
+            }
+        }
+    }
+
+    /// Runs the top level of `source` and checks that it defined `generate`. On failure the
+    /// fallback is handed back along with the error.
+    pub fn from_source(source: &str, name: &str, fallback: Box<WorldGenerator>)
+        -> Result<LuaGenerator, (String, Box<WorldGenerator>)>
+    {
+        unsafe {
+            let state = luaL_newstate();
+            if state.is_null() {
+                return Err(("couldn't create a Lua state".to_string(), fallback));
+            }
+            luaL_openlibs(state);
+
+            let context = Box::into_raw(Box::new(ScriptContext {
+                seed: 0,
+                chunk: ptr::null_mut(),
+                noise: FnvHashMap::default(),
+                error: None,
+            }));
+
+            if let Err(message) = load(state, context, source, name) {
+                lua_close(state);
+                drop(Box::from_raw(context));
+                return Err((message, fallback));
+            }
+
+            Ok(LuaGenerator {
+                state,
+                context,
+                fallback,
+                reported_error: Cell::new(false),
+            })
+        }
+    }
+
+    fn run(&self, coord: ChunkCoord, seed: u32, chunk: &mut Chunk) -> Result<(), String> {
+        unsafe {
+            let state = self.state;
+            {
+                let context = &mut *self.context;
+                if context.seed != seed {
+                    context.seed = seed;
+                    context.noise.clear();
+                }
+                context.chunk = chunk;
+                context.error = None;
+            }
+
+            lua_getfield(state, LUA_GLOBALSINDEX, b"generate\0".as_ptr() as *const c_char);
+            lua_pushinteger(state, coord.x as isize);
+            lua_pushinteger(state, coord.z as isize);
+            lua_pushnumber(state, seed as f64);
+            let result = lua_pcall(state, 3, 0, 0);
+
+            let context = &mut *self.context;
+            context.chunk = ptr::null_mut();
+            if result != 0 {
+                return Err(pop_error(state));
+            }
+            match context.error.take() {
+                Some(message) => Err(message),
+                None => Ok(()),
+            }
+        }
+    }
+}
+
+impl WorldGenerator for LuaGenerator {
+    fn name(&self) -> &'static str {
+        "lua"
+    }
+
+    fn version(&self) -> u32 {
+        Self::VERSION
+    }
+
+    fn generate(&self, coord: ChunkCoord, seed: u32) -> Box<Chunk> {
+        let mut chunk = Chunk::new();
+        match self.run(coord, seed, &mut chunk) {
+            Ok(()) => chunk,
+            Err(message) => {
+                // The same mistake usually happens in every chunk, so only the first is a warning.
+                if self.reported_error.replace(true) {
+                    debug!("Generator script failed on chunk {}: {}.", coord, message);
+                } else {
+                    warn!("Generator script failed on chunk {}: {}, using {:?}.", coord, message, self.fallback.name());
+                }
+                let mut chunk = self.fallback.generate(coord, seed);
+                chunk.set_generator(Some(GeneratorStamp { name: self.name().to_string(), version: 0 }));
+                chunk
+            }
+        }
+    }
+}
+
+impl Drop for LuaGenerator {
+    fn drop(&mut self) {
+        unsafe {
+            lua_close(self.state);
+            drop(Box::from_raw(self.context));
+        }
+    }
+}
+
+unsafe fn load(state: *mut lua_State, context: *mut ScriptContext, source: &str, name: &str) -> Result<(), String> {
+    register(state, context, b"set_block\0", lua_set_block);
+    register(state, context, b"get_block\0", lua_get_block);
+    register(state, context, b"noise2\0", lua_noise2);
+    register(state, context, b"noise3\0", lua_noise3);
+
+    lua_pushinteger(state, CHUNK_SIDE_LENGTH as isize);
+    lua_setfield(state, LUA_GLOBALSINDEX, b"CHUNK_SIDE_LENGTH\0".as_ptr() as *const c_char);
+    lua_pushinteger(state, WORLD_HEIGHT as isize);
+    lua_setfield(state, LUA_GLOBALSINDEX, b"WORLD_HEIGHT\0".as_ptr() as *const c_char);
+
+    lua_createtable(state, 0, BLOCK_TYPE_COUNT as c_int);
+    for id in 0..BLOCK_TYPE_COUNT {
+        let name = CString::new(format!("{:?}", BlockType::from(id as u8))).unwrap();
+        lua_pushinteger(state, id as isize);
+        lua_setfield(state, -2, name.as_ptr());
+    }
+    lua_setfield(state, LUA_GLOBALSINDEX, b"blocks\0".as_ptr() as *const c_char);
+
+    let chunk_name = CString::new(format!("@{}", name)).unwrap_or_default();
+    if luaL_loadbuffer(state, source.as_ptr() as *const c_char, source.len(), chunk_name.as_ptr()) != 0 {
+        return Err(pop_error(state));
+    }
+    if lua_pcall(state, 0, 0, 0) != 0 {
+        return Err(pop_error(state));
+    }
+
+    lua_getfield(state, LUA_GLOBALSINDEX, b"generate\0".as_ptr() as *const c_char);
+    let defined = lua_type(state, -1) == LUA_TFUNCTION;
+    lua_settop(state, -2);
+    if defined {
+        Ok(())
+    } else {
+        Err("the script doesn't define a generate function".to_string())
+    }
+}
+
+/// Sets the global `name` to `function`, which gets the context as its upvalue.
+unsafe fn register(
+    state: *mut lua_State,
+    context: *mut ScriptContext,
+    name: &[u8],
+    function: extern "C" fn(*mut lua_State) -> c_int,
+) {
+    lua_pushlightuserdata(state, context as *mut _);
+    lua_pushcclosure(state, function, 1);
+    lua_setfield(state, LUA_GLOBALSINDEX, name.as_ptr() as *const c_char);
+}
+
+/// Takes the error message a failed call left on the stack.
+unsafe fn pop_error(state: *mut lua_State) -> String {
+    let message = lua_tolstring(state, -1, ptr::null_mut());
+    let message = if message.is_null() {
+        "unknown error".to_string()
+    } else {
+        CStr::from_ptr(message).to_string_lossy().into_owned()
+    };
+    lua_settop(state, -2);
+    message
+}
+
+unsafe fn context<'a>(state: *mut lua_State) -> &'a mut ScriptContext {
+    &mut *(lua_touserdata(state, lua_upvalueindex(1)) as *mut ScriptContext)
+}
+
+unsafe fn number_arg(state: *mut lua_State, index: c_int) -> Option<f64> {
+    if lua_type(state, index) == LUA_TNUMBER {
+        Some(lua_tonumber(state, index))
+    } else {
+        None
+    }
+}
+
+/// The chunk position given by the first three arguments, if it's inside the chunk.
+unsafe fn position_arg(state: *mut lua_State) -> Option<Coord> {
+    let x = number_arg(state, 1)? as i32;
+    let y = number_arg(state, 2)? as i32;
+    let z = number_arg(state, 3)? as i32;
+    let side = CHUNK_SIDE_LENGTH as i32;
+    if 0 <= x && x < side && 0 <= y && y < WORLD_HEIGHT as i32 && 0 <= z && z < side {
+        Some(Coord { x, y, z })
+    } else {
+        None
+    }
+}
+
+fn fail(context: &mut ScriptContext, message: String) -> c_int {
+    if context.error.is_none() {
+        context.error = Some(message);
+    }
+    0
+}
+
+extern "C" fn lua_set_block(state: *mut lua_State) -> c_int {
+    unsafe {
+        let context = context(state);
+        if context.chunk.is_null() {
+            return fail(context, "set_block called outside of generate".to_string());
+        }
+        let pos = match position_arg(state) {
+            Some(pos) => pos,
+            None => return fail(context, "set_block needs a position inside the chunk".to_string()),
+        };
+        let id = match number_arg(state, 4) {
+            Some(id) if id >= 0.0 && (id as usize) < BLOCK_TYPE_COUNT => id as u8,
+            _ => return fail(context, "set_block needs a block from the blocks table".to_string()),
+        };
+        let block_state = match number_arg(state, 5) {
+            Some(block_state) if block_state >= 0.0 && block_state < 256.0 => block_state as u8,
+            Some(_) => return fail(context, "set_block needs a state between 0 and 255".to_string()),
+            None => 0,
+        };
+        (*context.chunk).set(pos, Block::with_state(BlockType::from(id), block_state));
+        0
+    }
+}
+
+extern "C" fn lua_get_block(state: *mut lua_State) -> c_int {
+    unsafe {
+        let context = context(state);
+        if context.chunk.is_null() {
+            return fail(context, "get_block called outside of generate".to_string());
+        }
+        let pos = match position_arg(state) {
+            Some(pos) => pos,
+            None => return fail(context, "get_block needs a position inside the chunk".to_string()),
+        };
+        let block = (*context.chunk).get(pos);
+        lua_pushinteger(state, block.ty as isize);
+        lua_pushinteger(state, block.state as isize);
+        2
+    }
+}
+
+/// The noise field for `salt` and `octaves`, made the first time it's asked for.
+fn noise_field(context: &mut ScriptContext, salt: f64, octaves: Option<f64>) -> &OctaveNoise {
+    let salt = salt as u32;
+    let octaves = octaves.map_or(1, |octaves| octaves.max(1.0).min(16.0) as usize);
+    let seed = context.seed;
+    context.noise.entry((salt, octaves)).or_insert_with(|| {
+        OctaveNoise::new(seed, SCRIPT_NOISE_SALT.wrapping_add(salt), octaves, 1.0, 0.5)
+    })
+}
+
+extern "C" fn lua_noise2(state: *mut lua_State) -> c_int {
+    unsafe {
+        let context = context(state);
+        let (salt, x, z) = match (number_arg(state, 1), number_arg(state, 2), number_arg(state, 3)) {
+            (Some(salt), Some(x), Some(z)) => (salt, x, z),
+            _ => return fail(context, "noise2 needs a salt, x and z".to_string()),
+        };
+        let value = noise_field(context, salt, number_arg(state, 4)).get2(x, z);
+        lua_pushnumber(state, value);
+        1
+    }
+}
+
+extern "C" fn lua_noise3(state: *mut lua_State) -> c_int {
+    unsafe {
+        let context = context(state);
+        let args = (number_arg(state, 1), number_arg(state, 2), number_arg(state, 3), number_arg(state, 4));
+        let (salt, x, y, z) = match args {
+            (Some(salt), Some(x), Some(y), Some(z)) => (salt, x, y, z),
+            _ => return fail(context, "noise3 needs a salt, x, y and z".to_string()),
+        };
+        let value = noise_field(context, salt, number_arg(state, 5)).get3(x, y, z);
+        lua_pushnumber(state, value);
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use world_generator::VoidGenerator;
+
+    const LAYERS_SCRIPT: &str = "
+        function generate(chunk_x, chunk_z, seed)
+            for x = 0, CHUNK_SIDE_LENGTH - 1 do
+                for z = 0, CHUNK_SIDE_LENGTH - 1 do
+                    -- Perlin noise is 0 at whole numbers, so the coordinates are scaled down.
+                    local height = 20 + math.floor(noise2(1, (chunk_x * 16 + x) / 50, (chunk_z * 16 + z) / 50) * 8)
+                    for y = 0, height do
+                        set_block(x, y, z, blocks.Stone)
+                    end
+                    set_block(x, 0, z, blocks.Bedrock)
+                end
+            end
+        end
+    ";
+
+    fn load(source: &str) -> Result<LuaGenerator, String> {
+        LuaGenerator::from_source(source, "test", Box::new(VoidGenerator)).map_err(|(message, _)| message)
+    }
+
+    extern "C" fn upvalue_plus_one(state: *mut lua_State) -> c_int {
+        unsafe {
+            let value = *(lua_touserdata(state, lua_upvalueindex(1)) as *mut i32);
+            lua_pushinteger(state, value as isize + 1);
+        }
+        1
+    }
+
+    unsafe fn run(state: *mut lua_State, source: &str) -> Result<(), String> {
+        if luaL_loadbuffer(state, source.as_ptr() as *const c_char, source.len(), b"test\0".as_ptr() as *const c_char) != 0 {
+            return Err(pop_error(state));
+        }
+        if lua_pcall(state, 0, 0, 0) != 0 {
+            return Err(pop_error(state));
+        }
+        Ok(())
+    }
+
+    unsafe fn global_number(state: *mut lua_State, name: &[u8]) -> f64 {
+        lua_getfield(state, LUA_GLOBALSINDEX, name.as_ptr() as *const c_char);
+        let value = lua_tonumber(state, -1);
+        lua_settop(state, -2);
+        value
+    }
+
+    /// Nothing checks the declarations in `ffi` against the LuaJIT headers, so this goes through
+    /// each of them, and the constants standing in for macros.
+    #[test]
+    fn bindings_match_luajit() {
+        unsafe {
+            let state = luaL_newstate();
+            assert!(!state.is_null());
+            luaL_openlibs(state);
+
+            let mut value = 7i32;
+            lua_pushinteger(state, -123456789);
+            lua_pushnumber(state, 0.25);
+            lua_pushlightuserdata(state, &mut value as *mut i32 as *mut _);
+            assert_eq!(lua_type(state, -3), LUA_TNUMBER);
+            assert_eq!(lua_tonumber(state, -3), -123456789.0);
+            assert_eq!(lua_tonumber(state, -2), 0.25);
+            assert_eq!(lua_touserdata(state, -1) as *mut i32, &mut value as *mut i32);
+            lua_settop(state, 0);
+
+            run(state, "answer = 6 * 7; greeting = 'hello' .. ' there'").unwrap();
+            assert_eq!(global_number(state, b"answer\0"), 42.0);
+            lua_getfield(state, LUA_GLOBALSINDEX, b"greeting\0".as_ptr() as *const c_char);
+            let mut len = 0;
+            let greeting = lua_tolstring(state, -1, &mut len);
+            assert_eq!((CStr::from_ptr(greeting).to_str().unwrap(), len), ("hello there", 11));
+            lua_settop(state, 0);
+
+            lua_createtable(state, 0, 1);
+            lua_pushinteger(state, 5);
+            lua_setfield(state, -2, b"x\0".as_ptr() as *const c_char);
+            lua_setfield(state, LUA_GLOBALSINDEX, b"t\0".as_ptr() as *const c_char);
+            lua_pushlightuserdata(state, &mut value as *mut i32 as *mut _);
+            lua_pushcclosure(state, upvalue_plus_one, 1);
+            lua_setfield(state, LUA_GLOBALSINDEX, b"plus_one\0".as_ptr() as *const c_char);
+            lua_getfield(state, LUA_GLOBALSINDEX, b"plus_one\0".as_ptr() as *const c_char);
+            assert_eq!(lua_type(state, -1), LUA_TFUNCTION);
+            lua_settop(state, 0);
+            run(state, "result = plus_one() + t.x").unwrap();
+            assert_eq!(global_number(state, b"result\0"), 13.0);
+
+            assert!(run(state, "error('boom')").unwrap_err().contains("boom"));
+            assert!(run(state, "if").is_err());
+            lua_close(state);
+        }
+    }
+
+    #[test]
+    fn script_fills_the_chunk() {
+        let generator = load(LAYERS_SCRIPT).unwrap();
+        let chunk = generator.generate(ChunkCoord::new(3, -2), 7);
+        assert!(chunk.generator().is_none());
+        assert!(chunk.get(Coord::new(4, 0, 9)).ty == BlockType::Bedrock);
+        assert!(chunk.get(Coord::new(4, 5, 9)).ty == BlockType::Stone);
+        assert!(chunk.get(Coord::new(4, 100, 9)).ty == BlockType::Air);
+    }
+
+    /// The height of the highest block in a column.
+    fn height(chunk: &Chunk, x: i32, z: i32) -> i32 {
+        (0..WORLD_HEIGHT as i32).rev().find(|&y| !chunk.get(Coord::new(x, y, z)).is_air()).unwrap()
+    }
+
+    #[test]
+    fn scripts_are_deterministic() {
+        let a = load(LAYERS_SCRIPT).unwrap();
+        let b = load(LAYERS_SCRIPT).unwrap();
+        let coord = ChunkCoord::new(-5, 12);
+        let chunk = a.generate(coord, 99);
+        assert!(chunk.to_bytes() == b.generate(coord, 99).to_bytes());
+        assert!(chunk.to_bytes() != b.generate(coord, 100).to_bytes());
+
+        // The noise reaches the script, rather than every column being the same.
+        let first = height(&chunk, 0, 0);
+        let mut heights = (0..CHUNK_SIDE_LENGTH as i32).flat_map(|x| (0..CHUNK_SIDE_LENGTH as i32).map(move |z| (x, z)));
+        assert!(heights.any(|(x, z)| height(&chunk, x, z) != first));
+    }
+
+    #[test]
+    fn broken_scripts_are_rejected() {
+        assert!(load("function generate(").is_err());
+        assert!(load("error('nope')").is_err());
+        assert!(load("x = 1").is_err());
+    }
+
+    #[test]
+    fn failing_chunks_use_the_fallback() {
+        let generator = load("function generate(x, z) set_block(99, 0, 0, blocks.Stone) end").unwrap();
+        let chunk = generator.generate(ChunkCoord::new(0, 0), 1);
+        assert_eq!(chunk.generator(), Some(&GeneratorStamp { name: "lua".to_string(), version: 0 }));
+
+        let generator = load("function generate(x, z) error('nope') end").unwrap();
+        let chunk = generator.generate(ChunkCoord::new(0, 0), 1);
+        assert!(chunk.generator().unwrap().version == 0);
+    }
+}
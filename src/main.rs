@@ -13,10 +13,9 @@ extern crate imgui_glium_renderer;
 extern crate glium;
 #[macro_use]
 extern crate lazy_static;
-//extern crate luajit_sys;
 #[macro_use]
 extern crate log;
-//extern crate noise;
+extern crate noise;
 extern crate rusqlite;
 
 extern crate deflate;
@@ -28,19 +27,52 @@ extern crate serde;
 extern crate toml;
 
 
+mod biome;
 mod block;
+mod border_blend;
+mod carver;
 mod chunk;
 mod chunk_generator;
 mod chunk_loader;
 mod chunk_manager;
+mod chunk_store;
+mod convert;
 mod craft;
+mod decoration;
+mod density_generator;
+mod erosion;
+mod heightmap_generator;
+mod inventory;
 mod line_renderer;
+#[cfg(feature = "lua")]
+mod lua_generator;
 mod math;
+mod noise_generator;
 mod chunk_mesher;
+mod ores;
 mod player;
+mod player_edits;
+mod pregen;
+mod preset;
+mod random;
+mod regenerate;
+mod region_store;
+mod seed_preview;
+mod signals;
+mod sqlite_store;
+mod structures;
 mod utils;
+mod world_generator;
+mod world_meta;
+mod world_noise;
 
 
 fn main() {
-    craft::Craft::run();
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(|arg| &arg[..]) {
+        Some("convert") => convert::run(&args[2..]),
+        Some("pregen") => pregen::run(&args[2..]),
+        Some("preview") => seed_preview::run(&args[2..]),
+        _ => craft::Craft::run(),
+    }
 }
@@ -82,6 +82,29 @@ pub fn point3_floor(point: Point3<f32>) -> Point3<i32> {
     }
 }
 
+/// Integer division that rounds towards negative infinity, so that negative world coordinates
+/// end up in the right chunk.
+pub fn floor_div(a: i32, b: i32) -> i32 {
+    let d = a / b;
+    if (a % b != 0) && ((a < 0) != (b < 0)) { d - 1 } else { d }
+}
+
+/// `value`, or whichever of `min` and `max` it's past.
+pub fn clamp<T: PartialOrd>(value: T, min: T, max: T) -> T {
+    if value < min {
+        min
+    } else if value > max {
+        max
+    } else {
+        value
+    }
+}
+
+/// The point `t` of the way from `a` to `b`.
+pub fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + (b - a) * t
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub struct ChunkCoord {
     pub x: i32,
@@ -93,11 +116,14 @@ impl ChunkCoord {
         ChunkCoord { x, z }
     }
 
+    /// The chunk a block is in. Rounds down, so the blocks at -1 and -16 are in chunk -1
+    /// rather than chunk 0, matching the `CHUNK_SIDE_LENGTH_MASK` used for the block's position
+    /// inside the chunk.
     pub fn from_world_pos(v: Point3<i32>) -> ChunkCoord {
         use chunk::CHUNK_SIDE_LENGTH;
         ChunkCoord {
-            x: v.x / (CHUNK_SIDE_LENGTH as i32),
-            z: v.z / (CHUNK_SIDE_LENGTH as i32)
+            x: floor_div(v.x, CHUNK_SIDE_LENGTH as i32),
+            z: floor_div(v.z, CHUNK_SIDE_LENGTH as i32)
         }
     }
 }
@@ -156,3 +182,33 @@ unsafe impl Attribute for Color {
         AttributeType::U8U8U8U8
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chunk::{ CHUNK_SIDE_LENGTH, CHUNK_SIDE_LENGTH_MASK };
+
+    #[test]
+    fn floor_div_rounds_down() {
+        assert_eq!(floor_div(7, 2), 3);
+        assert_eq!(floor_div(-7, 2), -4);
+        assert_eq!(floor_div(-8, 2), -4);
+        assert_eq!(floor_div(7, -2), -4);
+        assert_eq!(floor_div(0, 5), 0);
+    }
+
+    #[test]
+    fn negative_blocks_are_in_negative_chunks() {
+        let side = CHUNK_SIDE_LENGTH as i32;
+        assert_eq!(ChunkCoord::from_world_pos(Coord::new(-1, 50, -1)), ChunkCoord::new(-1, -1));
+        assert_eq!(ChunkCoord::from_world_pos(Coord::new(-side, 0, 0)), ChunkCoord::new(-1, 0));
+        assert_eq!(ChunkCoord::from_world_pos(Coord::new(-side - 1, 0, side)), ChunkCoord::new(-2, 1));
+        assert_eq!(ChunkCoord::from_world_pos(Coord::new(side - 1, 0, 0)), ChunkCoord::new(0, 0));
+
+        // The chunk and the position inside it add back up to the block.
+        for &x in [-side - 1, -side, -1, 0, 1, side].iter() {
+            let chunk = ChunkCoord::from_world_pos(Coord::new(x, 0, 0));
+            assert_eq!(chunk.x * side + (x & CHUNK_SIDE_LENGTH_MASK as i32), x);
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use biome::Biome;
+use block::{ Block, BlockType };
+use chunk::{ Chunk, CHUNK_COLUMN_COUNT, CHUNK_SIDE_LENGTH, WORLD_HEIGHT };
+use erosion::Eroder;
+use math::*;
+use world_generator::{ WorldGenerator, DIRT_DEPTH };
+use world_noise::OctaveNoise;
+
+const HEIGHT_NOISE_SALT: u32 = 1;
+const TEMPERATURE_NOISE_SALT: u32 = 2;
+const HUMIDITY_NOISE_SALT: u32 = 3;
+const RIVER_NOISE_SALT: u32 = 4;
+
+/// How close to zero the river field must be for a column to be in a river valley.
+const RIVER_VALLEY_WIDTH: f64 = 0.06;
+/// How far below sea level the middle of a river bed is.
+const RIVER_DEPTH: f64 = 3.0;
+
+/// Biome terrain shapes are averaged over a square of cells around each column so that the
+/// height doesn't jump at biome borders.
+const BLEND_CELL_SIZE: i32 = 4;
+const BLEND_RADIUS: i32 = 2;
+
+/// Heightmap terrain from seeded gradient noise, shaped by biomes picked from temperature and
+/// humidity noise. With a sea level, rivers cut valleys down to it, and with an eroder the
+/// heightmap is weathered before the chunk is filled.
+pub struct NoiseGenerator {
+    pub sea_level: Option<i32>,
+    pub erosion: Option<Eroder>,
+}
+
+impl NoiseGenerator {
+    pub const VERSION: u32 = 2;
+}
+
+impl WorldGenerator for NoiseGenerator {
+    fn name(&self) -> &'static str {
+        "noise"
+    }
+
+    fn version(&self) -> u32 {
+        Self::VERSION
+    }
+
+    fn generate(&self, coord: ChunkCoord, seed: u32) -> Box<Chunk> {
+        let mut chunk = Chunk::new();
+        let terrain = NoiseTerrain::new(seed, self.sea_level);
+        match self.erosion {
+            Some(ref eroder) => {
+                let (heights, deposits) = eroder.chunk_columns(&terrain, seed, coord);
+                terrain.fill_columns(coord, &mut chunk, &heights, &deposits);
+            }
+            None => terrain.fill(coord, &mut chunk),
+        }
+        chunk
+    }
+}
+
+/// The noise fields behind `NoiseGenerator` for one seed. Everything is a pure function of the
+/// seed and the world position, so the same seed always produces the same world.
+pub struct NoiseTerrain {
+    height_noise: OctaveNoise,
+    temperature_noise: OctaveNoise,
+    humidity_noise: OctaveNoise,
+    river_noise: OctaveNoise,
+    sea_level: Option<i32>,
+}
+
+impl NoiseTerrain {
+    pub fn new(seed: u32, sea_level: Option<i32>) -> NoiseTerrain {
+        NoiseTerrain {
+            height_noise: OctaveNoise::new(seed, HEIGHT_NOISE_SALT, 5, 1.0 / 256.0, 0.5),
+            temperature_noise: OctaveNoise::new(seed, TEMPERATURE_NOISE_SALT, 2, 1.0 / 1024.0, 0.5),
+            humidity_noise: OctaveNoise::new(seed, HUMIDITY_NOISE_SALT, 2, 1.0 / 768.0, 0.5),
+            river_noise: OctaveNoise::new(seed, RIVER_NOISE_SALT, 2, 1.0 / 512.0, 0.5),
+            sea_level,
+        }
+    }
+
+    pub fn biome_at(&self, x: i32, z: i32) -> Biome {
+        let temperature = self.temperature_noise.get2(x as f64, z as f64);
+        let humidity = self.humidity_noise.get2(x as f64, z as f64);
+        Biome::from_climate(temperature, humidity)
+    }
+
+    /// The y coordinate of the topmost solid block in the column at world position `x`, `z`.
+    pub fn height_at(&self, x: i32, z: i32) -> i32 {
+        let cell_x = floor_div(x, BLEND_CELL_SIZE);
+        let cell_z = floor_div(z, BLEND_CELL_SIZE);
+        let corners = [
+            self.lattice_shape(cell_x, cell_z),
+            self.lattice_shape(cell_x + 1, cell_z),
+            self.lattice_shape(cell_x, cell_z + 1),
+            self.lattice_shape(cell_x + 1, cell_z + 1),
+        ];
+        let shape = interpolate_shape(&corners, x - cell_x * BLEND_CELL_SIZE, z - cell_z * BLEND_CELL_SIZE);
+        self.shaped_height(x, z, shape)
+    }
+
+    /// The heights of a `size` by `size` square of columns with its lowest corner at `min_x`,
+    /// `min_z`, indexed by `x + z * size`. The same as calling `height_at` for each column, but
+    /// the blend lattice is only worked out once.
+    pub fn height_grid(&self, min_x: i32, min_z: i32, size: i32) -> Vec<i32> {
+        let cell_x = floor_div(min_x, BLEND_CELL_SIZE);
+        let cell_z = floor_div(min_z, BLEND_CELL_SIZE);
+        let cells = floor_div(min_x + size - 1, BLEND_CELL_SIZE) - cell_x + 2;
+        let mut lattice = Vec::with_capacity((cells * cells) as usize);
+        for j in 0..cells {
+            for i in 0..cells {
+                lattice.push(self.lattice_shape(cell_x + i, cell_z + j));
+            }
+        }
+
+        let mut heights = Vec::with_capacity((size * size) as usize);
+        for z in min_z..min_z + size {
+            for x in min_x..min_x + size {
+                let i = floor_div(x, BLEND_CELL_SIZE) - cell_x;
+                let j = floor_div(z, BLEND_CELL_SIZE) - cell_z;
+                let at = |di: i32, dj: i32| lattice[((j + dj) * cells + i + di) as usize];
+                let corners = [at(0, 0), at(1, 0), at(0, 1), at(1, 1)];
+                let shape = interpolate_shape(
+                    &corners,
+                    x - floor_div(x, BLEND_CELL_SIZE) * BLEND_CELL_SIZE,
+                    z - floor_div(z, BLEND_CELL_SIZE) * BLEND_CELL_SIZE,
+                );
+                heights.push(self.shaped_height(x, z, shape));
+            }
+        }
+        heights
+    }
+
+    pub fn fill(&self, coord: ChunkCoord, chunk: &mut Chunk) {
+        let origin_x = coord.x * CHUNK_SIDE_LENGTH as i32;
+        let origin_z = coord.z * CHUNK_SIDE_LENGTH as i32;
+
+        let grid = self.height_grid(origin_x, origin_z, CHUNK_SIDE_LENGTH as i32);
+        let mut heights = [0; CHUNK_COLUMN_COUNT];
+        heights.copy_from_slice(&grid);
+        self.fill_columns(coord, chunk, &heights, &[0; CHUNK_COLUMN_COUNT]);
+    }
+
+    /// Fills the chunk from a heightmap indexed by `x + z * CHUNK_SIDE_LENGTH`. `deposits` is the
+    /// depth of sediment at the top of each column, laid down as sand or gravel.
+    pub fn fill_columns(
+        &self,
+        coord: ChunkCoord,
+        chunk: &mut Chunk,
+        heights: &[i32; CHUNK_COLUMN_COUNT],
+        deposits: &[u8; CHUNK_COLUMN_COUNT],
+    ) {
+        let origin_x = coord.x * CHUNK_SIDE_LENGTH as i32;
+        let origin_z = coord.z * CHUNK_SIDE_LENGTH as i32;
+
+        for x in 0..CHUNK_SIDE_LENGTH as i32 {
+            for z in 0..CHUNK_SIDE_LENGTH as i32 {
+                let world_x = origin_x + x;
+                let world_z = origin_z + z;
+                let column = (x + z * CHUNK_SIDE_LENGTH as i32) as usize;
+                let height = heights[column];
+                let deposit = deposits[column] as i32;
+
+                let biome = self.biome_at(world_x, world_z);
+                chunk.set_biome(x, z, biome);
+
+                // Beaches, and the beds of oceans, lakes and rivers, are sand.
+                let underwater = self.sea_level.map_or(false, |sea_level| height <= sea_level + 1);
+                let (surface, filler) = if underwater {
+                    (BlockType::Sand, BlockType::Sand)
+                } else {
+                    (biome.surface_block(), biome.filler_block())
+                };
+                // Fine sediment settles in deep basins and by water, coarser gravel elsewhere.
+                let sediment = if underwater || deposit >= 3 { BlockType::Sand } else { BlockType::Gravel };
+
+                for y in 0..height + 1 {
+                    let ty = if y == 0 {
+                        BlockType::Bedrock
+                    } else if y > height - deposit {
+                        sediment
+                    } else if y == height {
+                        surface
+                    } else if y >= height - DIRT_DEPTH {
+                        filler
+                    } else {
+                        BlockType::Stone
+                    };
+                    chunk.set(Coord { x, y, z }, Block::new(ty));
+                }
+            }
+        }
+    }
+
+    /// The average terrain shape of the biomes around the lattice point at `cell_x`, `cell_z`.
+    fn lattice_shape(&self, cell_x: i32, cell_z: i32) -> (f64, f64) {
+        let mut base = 0.0;
+        let mut variation = 0.0;
+        for dx in -BLEND_RADIUS..BLEND_RADIUS + 1 {
+            for dz in -BLEND_RADIUS..BLEND_RADIUS + 1 {
+                let biome = self.biome_at((cell_x + dx) * BLEND_CELL_SIZE, (cell_z + dz) * BLEND_CELL_SIZE);
+                let (b, v) = biome.terrain_shape();
+                base += b;
+                variation += v;
+            }
+        }
+        let samples = ((2 * BLEND_RADIUS + 1) * (2 * BLEND_RADIUS + 1)) as f64;
+        (base / samples, variation / samples)
+    }
+
+    fn shaped_height(&self, x: i32, z: i32, (base, variation): (f64, f64)) -> i32 {
+        let noise = self.height_noise.get2(x as f64, z as f64);
+        let mut height = base + noise * variation;
+
+        // Rivers follow the zero line of the river field, pulling the terrain down towards a bed
+        // just below sea level and leaving a valley either side.
+        if let Some(sea_level) = self.sea_level {
+            let distance = self.river_noise.get2(x as f64, z as f64).abs();
+            let bed = sea_level as f64 - RIVER_DEPTH;
+            if distance < RIVER_VALLEY_WIDTH && height > bed {
+                let t = distance / RIVER_VALLEY_WIDTH;
+                let valley = t * t * (3.0 - 2.0 * t);
+                height = bed + (height - bed) * valley;
+            }
+        }
+
+        clamp(height as i32, 1, WORLD_HEIGHT as i32 - 1)
+    }
+}
+
+/// Bilinearly interpolates between the shapes at the corners of a blend cell, in the order
+/// `(0, 0)`, `(1, 0)`, `(0, 1)`, `(1, 1)`.
+fn interpolate_shape(corners: &[(f64, f64); 4], offset_x: i32, offset_z: i32) -> (f64, f64) {
+    let tx = offset_x as f64 / BLEND_CELL_SIZE as f64;
+    let tz = offset_z as f64 / BLEND_CELL_SIZE as f64;
+    let base = lerp(lerp(corners[0].0, corners[1].0, tx), lerp(corners[2].0, corners[3].0, tx), tz);
+    let variation = lerp(lerp(corners[0].1, corners[1].1, tx), lerp(corners[2].1, corners[3].1, tx), tz);
+    (base, variation)
+}
@@ -0,0 +1,199 @@
+use std::fs::File;
+use std::io::prelude::*;
+
+use toml;
+
+use block::{ Block, BlockType };
+use chunk::{ Chunk, CHUNK_SIDE_LENGTH };
+use math::*;
+use random::Rng;
+
+const ORE_CONFIG_PATH: &str = "ores.toml";
+const ORE_SALT_BASE: u32 = 200;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OreSpec {
+    pub block: BlockType,
+    /// Veins are only started and grown within `min_height..max_height`.
+    pub min_height: i32,
+    pub max_height: i32,
+    /// The number of steps taken while growing each vein, the upper limit on its block count.
+    pub vein_size: u32,
+    pub veins_per_chunk: u32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OreConfig {
+    #[serde(rename = "ore")]
+    pub ores: Vec<OreSpec>,
+}
+
+impl Default for OreConfig {
+    fn default() -> OreConfig {
+        OreConfig {
+            ores: vec![
+                OreSpec { block: BlockType::CoalOre, min_height: 5, max_height: 80, vein_size: 12, veins_per_chunk: 16 },
+                OreSpec { block: BlockType::IronOre, min_height: 5, max_height: 48, vein_size: 8, veins_per_chunk: 10 },
+                OreSpec { block: BlockType::GoldOre, min_height: 5, max_height: 24, vein_size: 7, veins_per_chunk: 3 },
+            ],
+        }
+    }
+}
+
+/// Reads the ore distribution from `ores.toml`. If there isn't one the defaults are written out,
+/// so there's a file to tweak.
+pub fn load_ore_config() -> OreConfig {
+    if let Ok(mut file) = File::open(ORE_CONFIG_PATH) {
+        let mut string = String::new();
+        file.read_to_string(&mut string);
+        match toml::de::from_str(&string) {
+            Ok(config) => {
+                info!("Loaded ore config successfully.");
+                return config;
+            }
+            Err(e) => {
+                warn!("Failed to parse ore config: {}.", e);
+                return OreConfig::default();
+            }
+        }
+    }
+
+    info!("No ore config found, writing defaults.");
+    let config = OreConfig::default();
+    let config_string = toml::ser::to_string_pretty(&config).unwrap();
+    if let Ok(mut file) = File::create(ORE_CONFIG_PATH) {
+        file.write_all(config_string.as_bytes());
+    }
+    config
+}
+
+/// Scatters blob-shaped ore veins through the stone of a chunk. Each ore draws from its own
+/// random stream seeded by the world seed and chunk position, so changing one ore's settings
+/// doesn't move the others.
+pub struct OrePlacer {
+    seed: u32,
+    config: OreConfig,
+}
+
+impl OrePlacer {
+    pub fn new(seed: u32, config: OreConfig) -> OrePlacer {
+        OrePlacer { seed, config }
+    }
+
+    pub fn place(&self, coord: ChunkCoord, chunk: &mut Chunk) {
+        for (i, ore) in self.config.ores.iter().enumerate() {
+            let mut rng = Rng::for_chunk(self.seed, coord, ORE_SALT_BASE + i as u32);
+            for _ in 0..ore.veins_per_chunk {
+                let start = Coord {
+                    x: rng.range(0, CHUNK_SIDE_LENGTH as i32),
+                    y: rng.range(ore.min_height, ore.max_height),
+                    z: rng.range(0, CHUNK_SIDE_LENGTH as i32),
+                };
+                grow_vein(chunk, &mut rng, ore, start);
+            }
+        }
+    }
+}
+
+/// Grows a vein by a random walk from `start`, replacing stone along the way. Steps that leave
+/// the chunk or the ore's height range are kept but place nothing, so the vein is clipped.
+fn grow_vein(chunk: &mut Chunk, rng: &mut Rng, ore: &OreSpec, start: Coord) {
+    let mut pos = start;
+    for _ in 0..ore.vein_size {
+        let in_chunk = pos.x >= 0 && pos.x < CHUNK_SIDE_LENGTH as i32 &&
+            pos.z >= 0 && pos.z < CHUNK_SIDE_LENGTH as i32;
+        let in_range = pos.y >= ore.min_height && pos.y < ore.max_height;
+        if in_chunk && in_range && chunk.get(pos).ty == BlockType::Stone {
+            chunk.set(pos, Block::new(ore.block));
+        }
+
+        match rng.range(0, 6) {
+            0 => pos.x -= 1,
+            1 => pos.x += 1,
+            2 => pos.y -= 1,
+            3 => pos.y += 1,
+            4 => pos.z -= 1,
+            _ => pos.z += 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chunk::{ EMPTY_CHUNK, WORLD_HEIGHT };
+
+    const SAMPLE_SIZE: i32 = 10;
+
+    fn stone_chunk() -> Box<Chunk> {
+        let mut chunk = Chunk::new();
+        for (coord, _) in EMPTY_CHUNK.iter() {
+            chunk.set(coord, Block::new(BlockType::Stone));
+        }
+        chunk
+    }
+
+    fn count_ores(placer: &OrePlacer) -> Vec<(BlockType, usize)> {
+        let mut counts: Vec<_> = placer.config.ores.iter().map(|ore| (ore.block, 0)).collect();
+        for x in 0..SAMPLE_SIZE {
+            for z in 0..SAMPLE_SIZE {
+                let mut chunk = stone_chunk();
+                placer.place(ChunkCoord::new(x - SAMPLE_SIZE / 2, z - SAMPLE_SIZE / 2), &mut chunk);
+                for (_, block) in chunk.iter() {
+                    for count in counts.iter_mut() {
+                        if count.0 == block.ty {
+                            count.1 += 1;
+                        }
+                    }
+                }
+            }
+        }
+        counts
+    }
+
+    #[test]
+    fn ore_frequencies_follow_config() {
+        let placer = OrePlacer::new(1234, OreConfig::default());
+        let counts = count_ores(&placer);
+        let chunks = (SAMPLE_SIZE * SAMPLE_SIZE) as f64;
+
+        for (ore, &(_, count)) in placer.config.ores.iter().zip(counts.iter()) {
+            // Random walks revisit blocks and get clipped at the chunk edges, so fewer blocks
+            // than the upper limit are placed, but it shouldn't be far off.
+            let upper_limit = (ore.vein_size * ore.veins_per_chunk) as f64;
+            let per_chunk = count as f64 / chunks;
+            assert!(per_chunk <= upper_limit, "{:?}: {} per chunk", ore.block, per_chunk);
+            assert!(per_chunk >= upper_limit * 0.3, "{:?}: {} per chunk", ore.block, per_chunk);
+        }
+
+        // Coal is the most common and gold the rarest.
+        assert!(counts[0].1 > counts[1].1);
+        assert!(counts[1].1 > counts[2].1);
+    }
+
+    #[test]
+    fn ores_stay_within_height_range() {
+        let config = OreConfig::default();
+        let placer = OrePlacer::new(99, config.clone());
+        let mut chunk = stone_chunk();
+        placer.place(ChunkCoord::new(3, -8), &mut chunk);
+        for (coord, block) in chunk.iter() {
+            if let Some(ore) = config.ores.iter().find(|ore| ore.block == block.ty) {
+                assert!(coord.y >= ore.min_height && coord.y < ore.max_height);
+                assert!(coord.y < WORLD_HEIGHT as i32);
+            }
+        }
+    }
+
+    #[test]
+    fn placement_is_deterministic() {
+        let first = OrePlacer::new(42, OreConfig::default());
+        let second = OrePlacer::new(42, OreConfig::default());
+        let coord = ChunkCoord::new(-5, 11);
+        let mut a = stone_chunk();
+        let mut b = stone_chunk();
+        first.place(coord, &mut a);
+        second.place(coord, &mut b);
+        assert!(a.to_bytes() == b.to_bytes());
+    }
+}
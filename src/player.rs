@@ -2,20 +2,28 @@ use math::*;
 use block::Block;
 use chunk::CHUNK_SIDE_LENGTH;
 use chunk_manager::ChunkManager;
+use inventory::Inventory;
 use utils::*;
 
+/// How far above the block they stand in the player's eyes are.
+const EYE_HEIGHT: f32 = 1.6;
+
+#[derive(Clone, Debug)]
 pub struct Player {
     pub camera: Camera,
+    pub inventory: Inventory,
 }
 
 impl Player {
-    pub fn new() -> Player {
+    /// A new player standing in the block at `spawn`.
+    pub fn new(spawn: Coord) -> Player {
         Player {
             camera: Camera {
-                pos: Point3::new(0.0, 45.0, 0.0),
+                pos: Point3::new(spawn.x as f32 + 0.5, spawn.y as f32 + EYE_HEIGHT, spawn.z as f32 + 0.5),
                 h_angle: Deg(0.0),
                 v_angle: Deg(0.0),
-            }
+            },
+            inventory: Inventory::starting(),
         }
     }
 
@@ -36,8 +44,8 @@ impl Player {
 #[derive(Clone, Copy, Debug)]
 pub struct Camera {
     pub pos: Point3<f32>,
-    h_angle: Deg<f32>,
-    v_angle: Deg<f32>,
+    pub h_angle: Deg<f32>,
+    pub v_angle: Deg<f32>,
 }
 
 // Iterates over ChunkCoords inside [min, max]
@@ -124,7 +132,7 @@ impl Camera {
             let next_coord: Point3<i32> = point3_floor(cur);
             if next_coord != cur_coord {
                 let block = chunks.get_block(next_coord);
-                if !block.is_air() {
+                if !block.is_air() && !block.is_fluid() {
 //                    let last_step = next_coord - cur_coord;
 //                    let side = Side::from_vector(last_step).unwrap();
                     if return_last_empty_coord {
@@ -0,0 +1,63 @@
+use fnv::FnvHashMap;
+
+use decoration::BlockEdit;
+use math::*;
+
+/// Every block the player has placed or broken, by chunk, so that tools which rewrite generated
+/// terrain can leave them alone. Only the latest edit at each position is kept.
+pub struct PlayerEdits {
+    edits: FnvHashMap<ChunkCoord, Vec<BlockEdit>>,
+}
+
+impl PlayerEdits {
+    pub fn new() -> PlayerEdits {
+        PlayerEdits {
+            edits: FnvHashMap::default(),
+        }
+    }
+
+    pub fn record(&mut self, coord: ChunkCoord, edit: BlockEdit) {
+        let edits = self.edits.entry(coord).or_insert_with(Vec::new);
+        match edits.iter_mut().find(|existing| existing.pos == edit.pos) {
+            Some(existing) => *existing = edit,
+            None => edits.push(edit),
+        }
+    }
+
+    /// The edits made in the chunk at `coord`, positions are local to the chunk.
+    pub fn get(&self, coord: ChunkCoord) -> &[BlockEdit] {
+        self.edits.get(&coord).map_or(&[], |edits| &edits[..])
+    }
+
+    pub fn contains(&self, coord: ChunkCoord, pos: Coord) -> bool {
+        self.get(coord).iter().any(|edit| edit.pos == pos)
+    }
+
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item=(ChunkCoord, &'a [BlockEdit])> + 'a {
+        self.edits.iter().map(|(&coord, edits)| (coord, &edits[..]))
+    }
+
+    pub fn len(&self) -> usize {
+        self.edits.values().map(|edits| edits.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block::{ Block, BlockType };
+
+    #[test]
+    fn later_edits_replace_earlier_ones() {
+        let coord = ChunkCoord::new(1, -1);
+        let mut edits = PlayerEdits::new();
+        edits.record(coord, BlockEdit { pos: Coord::new(1, 2, 3), block: Block::new(BlockType::Stone) });
+        edits.record(coord, BlockEdit { pos: Coord::new(4, 5, 6), block: Block::new(BlockType::Air) });
+        edits.record(coord, BlockEdit { pos: Coord::new(1, 2, 3), block: Block::new(BlockType::Air) });
+
+        assert_eq!(edits.len(), 2);
+        assert!(edits.contains(coord, Coord::new(4, 5, 6)));
+        assert!(!edits.contains(ChunkCoord::new(0, 0), Coord::new(4, 5, 6)));
+        assert_eq!(edits.get(coord)[0].block, Block::new(BlockType::Air));
+    }
+}
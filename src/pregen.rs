@@ -0,0 +1,256 @@
+use std::path::PathBuf;
+use std::process;
+use std::time::Instant;
+
+use chunk_generator::ChunkGenerator;
+use chunk_loader::ChunkLoader;
+use chunk_manager::{ ChunkState, ChunkStates };
+use chunk::CHUNK_SIDE_LENGTH;
+use craft::load_settings;
+use decoration::{ PendingEdits, apply_edits };
+use math::*;
+use ores::load_ore_config;
+use structures::load_structures;
+
+const USAGE: &str = "usage: craft pregen <x> <z> <radius> [square|circle] [save path]
+
+Generates every chunk within <radius> chunks of the block at <x>, <z> and writes them to the save,
+which defaults to save.sqlite. Chunks already in the save are skipped, so an interrupted run can
+be resumed by running the same command again.";
+
+/// How many chunks are queued on the generator at once. Enough to keep it busy, without holding
+/// the whole area in memory when it's faster than the database.
+const MAX_IN_FLIGHT: usize = 64;
+/// How often, in chunks, progress is printed and the pending edits are saved.
+const CHECKPOINT_INTERVAL: usize = 256;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Shape {
+    Square,
+    Circle,
+}
+
+pub struct PregenArea {
+    pub centre: ChunkCoord,
+    pub radius: i32,
+    pub shape: Shape,
+}
+
+impl PregenArea {
+    /// Every chunk in the area, nearest to the centre first, so that a partial run leaves a
+    /// usable area around the centre.
+    pub fn chunks(&self) -> Vec<ChunkCoord> {
+        let mut chunks = Vec::new();
+        for dx in -self.radius..self.radius + 1 {
+            for dz in -self.radius..self.radius + 1 {
+                if self.shape == Shape::Circle && dx * dx + dz * dz > self.radius * self.radius {
+                    continue;
+                }
+                chunks.push(ChunkCoord::new(self.centre.x + dx, self.centre.z + dz));
+            }
+        }
+        let centre = self.centre;
+        chunks.sort_by_key(|coord| {
+            let (dx, dz) = (coord.x - centre.x, coord.z - centre.z);
+            (dx * dx + dz * dz, coord.x, coord.z)
+        });
+        chunks
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<(PregenArea, PathBuf), String> {
+    if args.len() < 3 || args.len() > 5 {
+        return Err("expected between 3 and 5 arguments".to_string());
+    }
+    let x: i32 = args[0].parse().map_err(|_| format!("{:?} isn't a valid x coordinate", args[0]))?;
+    let z: i32 = args[1].parse().map_err(|_| format!("{:?} isn't a valid z coordinate", args[1]))?;
+    let radius: i32 = args[2].parse().map_err(|_| format!("{:?} isn't a valid radius", args[2]))?;
+    if radius < 0 {
+        return Err("the radius can't be negative".to_string());
+    }
+    let shape = match args.get(3).map(|s| &s[..]) {
+        None | Some("square") => Shape::Square,
+        Some("circle") => Shape::Circle,
+        Some(other) => return Err(format!("{:?} isn't a shape, use square or circle", other)),
+    };
+    let save_path = args.get(4).cloned().unwrap_or_else(|| "save.sqlite".to_string());
+
+    let area = PregenArea {
+        centre: ChunkCoord::from_world_pos(Coord { x, y: 0, z }),
+        radius,
+        shape,
+    };
+    Ok((area, save_path.into()))
+}
+
+/// Runs the `pregen` subcommand with the arguments that follow it.
+pub fn run(args: &[String]) {
+    let (area, save_path) = match parse_args(args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    load_settings();
+    pregenerate(&area, save_path);
+}
+
+pub fn pregenerate(area: &PregenArea, save_path: PathBuf) {
+    let (mut chunk_loader, chunk_states, mut pending_edits, _) = ChunkLoader::new(save_path);
+    let mut chunk_generator = ChunkGenerator::new(
+        chunk_loader.seed(), chunk_loader.preset().clone(), load_ore_config(), load_structures()
+    );
+
+    let all_chunks = area.chunks();
+    let missing: Vec<_> = all_chunks.iter()
+        .cloned()
+        .filter(|&coord| chunk_states.get(coord) == ChunkState::NonExistent)
+        .collect();
+    println!(
+        "Pre-generating {} chunks around {}, {} are already in the save",
+        all_chunks.len(), area.centre, all_chunks.len() - missing.len()
+    );
+    println!(
+        "Blocks {}..{} by {}..{}",
+        (area.centre.x - area.radius) * CHUNK_SIDE_LENGTH as i32,
+        (area.centre.x + area.radius + 1) * CHUNK_SIDE_LENGTH as i32,
+        (area.centre.z - area.radius) * CHUNK_SIDE_LENGTH as i32,
+        (area.centre.z + area.radius + 1) * CHUNK_SIDE_LENGTH as i32,
+    );
+
+    recover_edits(&mut chunk_generator, &chunk_states, &missing, &mut pending_edits);
+
+    let start_time = Instant::now();
+    let mut queue = missing.iter();
+    let mut in_flight = 0;
+    let mut done = 0;
+    while done < missing.len() {
+        while in_flight < MAX_IN_FLIGHT {
+            match queue.next() {
+                Some(&coord) => {
+                    chunk_generator.start_generate(coord);
+                    in_flight += 1;
+                }
+                None => break,
+            }
+        }
+
+        let (coord, mut chunk, foreign_edits, structures) = chunk_generator.wait_generated();
+        in_flight -= 1;
+        done += 1;
+
+        if let Some(edits) = pending_edits.take(coord) {
+            apply_edits(&mut chunk, &edits);
+        }
+        chunk_loader.enqueue_unload(coord, chunk);
+        // Edits for chunks generated later in the run are applied then, and the ones for chunks
+        // already in the save are applied when the game loads them.
+        for (target, edit) in foreign_edits {
+            pending_edits.add(target, edit);
+        }
+        if !structures.is_empty() {
+            chunk_loader.save_structures(structures);
+        }
+
+        if done % CHECKPOINT_INTERVAL == 0 || done == missing.len() {
+            chunk_loader.save_pending_edits(&pending_edits);
+            let elapsed = start_time.elapsed();
+            let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+            println!(
+                "{}/{} chunks ({:.1}%), {:.0} chunks/s",
+                done, missing.len(), 100.0 * done as f64 / missing.len() as f64, done as f64 / seconds
+            );
+        }
+    }
+
+    // Dropping the loader waits for everything queued to be written.
+    chunk_loader.save_pending_edits(&pending_edits);
+    drop(chunk_loader);
+    println!("Done");
+}
+
+/// The saved chunks next to any of `missing`, whose decorations may spill into them.
+fn saved_neighbours(chunk_states: &ChunkStates, missing: &[ChunkCoord]) -> Vec<ChunkCoord> {
+    let mut neighbours = Vec::new();
+    for coord in missing {
+        for dx in -1..2 {
+            for dz in -1..2 {
+                let neighbour = ChunkCoord::new(coord.x + dx, coord.z + dz);
+                if chunk_states.get(neighbour) != ChunkState::NonExistent && !neighbours.contains(&neighbour) {
+                    neighbours.push(neighbour);
+                }
+            }
+        }
+    }
+    neighbours
+}
+
+/// Pending edits are only saved at checkpoints, so a run that was killed can leave chunks in the
+/// save whose edits into the chunks still to do were lost. Decorating is deterministic, so the
+/// saved neighbours of those chunks are generated again to make them over. Edits that were saved
+/// after all aren't added twice.
+fn recover_edits(
+    chunk_generator: &mut ChunkGenerator,
+    chunk_states: &ChunkStates,
+    missing: &[ChunkCoord],
+    pending_edits: &mut PendingEdits,
+) {
+    let neighbours = saved_neighbours(chunk_states, missing);
+    for &coord in &neighbours {
+        chunk_generator.start_generate(coord);
+    }
+    for _ in 0..neighbours.len() {
+        let (_, _, foreign_edits, _) = chunk_generator.wait_generated();
+        for (target, edit) in foreign_edits {
+            if chunk_states.get(target) == ChunkState::NonExistent {
+                pending_edits.add(target, edit);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn circles_fit_inside_squares() {
+        let square = PregenArea { centre: ChunkCoord::new(3, -2), radius: 4, shape: Shape::Square }.chunks();
+        let circle = PregenArea { centre: ChunkCoord::new(3, -2), radius: 4, shape: Shape::Circle }.chunks();
+        assert_eq!(square.len(), 81);
+        assert!(circle.len() < square.len());
+        assert!(circle.iter().all(|coord| square.contains(coord)));
+        assert_eq!(square[0], ChunkCoord::new(3, -2));
+    }
+
+    #[test]
+    fn arguments_are_parsed() {
+        let (area, path) = parse_args(&args(&["-20", "40", "8", "circle", "other.sqlite"])).unwrap();
+        assert_eq!(area.centre, ChunkCoord::new(-2, 2));
+        assert_eq!(area.radius, 8);
+        assert_eq!(area.shape, Shape::Circle);
+        assert_eq!(path, PathBuf::from("other.sqlite"));
+
+        assert!(parse_args(&args(&["0", "0"])).is_err());
+        assert!(parse_args(&args(&["0", "0", "-1"])).is_err());
+        assert!(parse_args(&args(&["0", "0", "3", "hexagon"])).is_err());
+    }
+
+    #[test]
+    fn edits_are_recovered_from_saved_neighbours_only() {
+        let mut chunk_states = ChunkStates::new();
+        chunk_states.set(ChunkCoord::new(0, 0), ChunkState::Saved);
+        chunk_states.set(ChunkCoord::new(1, 1), ChunkState::Saved);
+        chunk_states.set(ChunkCoord::new(5, 5), ChunkState::Saved);
+        let missing = [ChunkCoord::new(1, 0), ChunkCoord::new(0, 1)];
+        let mut neighbours = saved_neighbours(&chunk_states, &missing);
+        neighbours.sort_by_key(|coord| (coord.x, coord.z));
+        assert_eq!(neighbours, vec![ChunkCoord::new(0, 0), ChunkCoord::new(1, 1)]);
+    }
+}
@@ -0,0 +1,257 @@
+use std::fs::File;
+use std::io::prelude::*;
+
+use toml;
+
+use block::{ Block, BlockType };
+use density_generator::DensityConfig;
+use erosion::ErosionConfig;
+use heightmap_generator::HeightmapConfig;
+use chunk::{ Chunk, CHUNK_SIDE_LENGTH, WORLD_HEIGHT };
+use math::*;
+
+const PRESETS_PATH: &str = "presets.toml";
+pub const DEFAULT_PRESET: &str = "default";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Layer {
+    pub block: BlockType,
+    pub thickness: u32,
+}
+
+/// Where to find a generator script, for the lua generator. The game must be built with the
+/// `lua` feature for it to be used, otherwise the world falls back to noise terrain.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LuaConfig {
+    pub path: String,
+    /// The generator used when the script can't be loaded, or fails on a chunk.
+    pub fallback: String,
+}
+
+/// Describes how a world is generated. Chosen when the world is created and stored in the save,
+/// so later changes to `presets.toml` don't affect existing worlds.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WorldPreset {
+    pub name: String,
+    /// The name of the world generator to use, one of those in the `GeneratorRegistry`.
+    pub terrain: String,
+    pub caves: bool,
+    pub ores: bool,
+    pub decorations: bool,
+    /// Whether structures from the templates in `structures/` are placed.
+    #[serde(default)]
+    pub structures: bool,
+    /// Air below this height is filled with water, making oceans and lakes in low terrain.
+    #[serde(default)]
+    pub sea_level: Option<i32>,
+    // TOML needs tables after plain values, so these stay at the end.
+    /// Stacked from y = 0 upwards, only used by flat terrain.
+    #[serde(default)]
+    pub layers: Vec<Layer>,
+    /// Only used by the heightmap generator.
+    #[serde(default)]
+    pub heightmap: Option<HeightmapConfig>,
+    /// Only used by the lua generator.
+    #[serde(default)]
+    pub lua: Option<LuaConfig>,
+    /// Only used by the density generator, which uses the defaults when this is missing.
+    #[serde(default)]
+    pub density: Option<DensityConfig>,
+    /// Weathers noise terrain with a hydraulic erosion pass when present.
+    #[serde(default)]
+    pub erosion: Option<ErosionConfig>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PresetFile {
+    #[serde(rename = "preset")]
+    presets: Vec<WorldPreset>,
+}
+
+fn default_presets() -> Vec<WorldPreset> {
+    vec![
+        WorldPreset {
+            name: DEFAULT_PRESET.to_string(),
+            terrain: "noise".to_string(),
+            caves: true,
+            ores: true,
+            decorations: true,
+            structures: true,
+            sea_level: Some(36),
+            layers: Vec::new(),
+            heightmap: None,
+            lua: None,
+            density: None,
+            erosion: None,
+        },
+        WorldPreset {
+            name: "flat".to_string(),
+            terrain: "flat".to_string(),
+            caves: false,
+            ores: false,
+            decorations: false,
+            structures: false,
+            sea_level: None,
+            layers: vec![
+                Layer { block: BlockType::Bedrock, thickness: 1 },
+                Layer { block: BlockType::Stone, thickness: 36 },
+                Layer { block: BlockType::Dirt, thickness: 3 },
+                Layer { block: BlockType::Grass, thickness: 1 },
+            ],
+            heightmap: None,
+            lua: None,
+            density: None,
+            erosion: None,
+        },
+        WorldPreset {
+            name: "void".to_string(),
+            terrain: "void".to_string(),
+            caves: false,
+            ores: false,
+            decorations: false,
+            structures: false,
+            sea_level: None,
+            layers: Vec::new(),
+            heightmap: None,
+            lua: None,
+            density: None,
+            erosion: None,
+        },
+        WorldPreset {
+            name: "islands".to_string(),
+            terrain: "density".to_string(),
+            caves: true,
+            ores: true,
+            decorations: true,
+            structures: false,
+            sea_level: None,
+            layers: Vec::new(),
+            heightmap: None,
+            lua: None,
+            density: Some(DensityConfig {
+                island_strength: 4.0,
+                ..DensityConfig::default()
+            }),
+            erosion: None,
+        },
+        WorldPreset {
+            name: "eroded".to_string(),
+            terrain: "noise".to_string(),
+            caves: true,
+            ores: true,
+            decorations: true,
+            structures: true,
+            sea_level: Some(36),
+            layers: Vec::new(),
+            heightmap: None,
+            lua: None,
+            density: None,
+            erosion: Some(ErosionConfig::default()),
+        },
+    ]
+}
+
+/// Reads the presets from `presets.toml`. If there isn't one the defaults are written out, so
+/// there's a file to add presets to.
+pub fn load_presets() -> Vec<WorldPreset> {
+    if let Ok(mut file) = File::open(PRESETS_PATH) {
+        let mut string = String::new();
+        file.read_to_string(&mut string);
+        match toml::de::from_str::<PresetFile>(&string) {
+            Ok(file) => {
+                info!("Loaded {} world presets.", file.presets.len());
+                return file.presets;
+            }
+            Err(e) => {
+                warn!("Failed to parse world presets: {}.", e);
+                return default_presets();
+            }
+        }
+    }
+
+    info!("No world presets found, writing defaults.");
+    let presets = default_presets();
+    let presets_string = toml::ser::to_string_pretty(&PresetFile { presets: presets.clone() }).unwrap();
+    if let Ok(mut file) = File::create(PRESETS_PATH) {
+        file.write_all(presets_string.as_bytes());
+    }
+    presets
+}
+
+/// Finds the preset called `name`, falling back to the built in default preset.
+pub fn find_preset(name: &str) -> WorldPreset {
+    let mut presets = load_presets();
+    if let Some(i) = presets.iter().position(|p| p.name == name) {
+        return presets.swap_remove(i);
+    }
+
+    warn!("No world preset called {:?}, using {:?}.", name, DEFAULT_PRESET);
+    WorldPreset::default()
+}
+
+impl Default for WorldPreset {
+    fn default() -> WorldPreset {
+        default_presets().swap_remove(0)
+    }
+}
+
+impl WorldPreset {
+    pub fn to_toml(&self) -> String {
+        toml::ser::to_string(self).unwrap()
+    }
+
+    pub fn from_toml(string: &str) -> Option<WorldPreset> {
+        toml::de::from_str(string).ok()
+    }
+}
+
+/// Stacks `layers` from the bottom of the chunk, anything above the world height is dropped.
+pub fn fill_layers(chunk: &mut Chunk, layers: &[Layer]) {
+    let mut y = 0;
+    for layer in layers {
+        for _ in 0..layer.thickness {
+            if y >= WORLD_HEIGHT as i32 {
+                return;
+            }
+            fill_layer(chunk, y, layer.block);
+            y += 1;
+        }
+    }
+}
+
+fn fill_layer(chunk: &mut Chunk, y: i32, ty: BlockType) {
+    for x in 0..CHUNK_SIDE_LENGTH as i32 {
+        for z in 0..CHUNK_SIDE_LENGTH as i32 {
+            chunk.set(Coord { x, y, z }, Block::new(ty))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_round_trip_through_toml() {
+        for preset in default_presets() {
+            let parsed = WorldPreset::from_toml(&preset.to_toml()).unwrap();
+            assert_eq!(parsed.to_toml(), preset.to_toml());
+        }
+    }
+
+    #[test]
+    fn layers_stack_from_the_bottom() {
+        let layers = [
+            Layer { block: BlockType::Bedrock, thickness: 1 },
+            Layer { block: BlockType::Stone, thickness: 2 },
+            Layer { block: BlockType::Grass, thickness: 1 },
+        ];
+        let mut chunk = Chunk::new();
+        fill_layers(&mut chunk, &layers);
+
+        let column: Vec<_> = (0..5).map(|y| chunk.get(Coord::new(3, y, 9)).ty).collect();
+        assert_eq!(column, vec![
+            BlockType::Bedrock, BlockType::Stone, BlockType::Stone, BlockType::Grass, BlockType::Air
+        ]);
+    }
+}
@@ -0,0 +1,61 @@
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+use math::ChunkCoord;
+
+/// The SplitMix64 finaliser. Cheap, and good enough at scrambling that neighbouring inputs give
+/// unrelated outputs, which is all we need for deriving seeds.
+pub fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+/// Derives an independent seed from the world seed, so that each noise field or random stream
+/// gets its own values without the user having to supply more than one seed.
+pub fn derive_seed(seed: u32, salt: u32) -> u32 {
+    mix(((seed as u64) << 32) | salt as u64) as u32
+}
+
+/// Picks a seed for a brand new world.
+pub fn random_seed() -> u32 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    mix(now.as_secs() ^ ((now.subsec_nanos() as u64) << 32)) as u32
+}
+
+/// A small, fast SplitMix64 generator. Unlike an OS-seeded RNG its output only depends on the
+/// seed it was created with, which is what world generation needs.
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    /// A generator unique to one chunk of one world, `salt` separates the different users.
+    pub fn for_chunk(seed: u32, coord: ChunkCoord, salt: u32) -> Rng {
+        let position = ((coord.x as u32 as u64) << 32) | coord.z as u32 as u64;
+        Rng::new(mix(((derive_seed(seed, salt) as u64) << 32) ^ mix(position)))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let result = mix(self.state);
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        result
+    }
+
+    /// A value in the range `min..max`, or `min` if the range is empty.
+    pub fn range(&mut self, min: i32, max: i32) -> i32 {
+        if max <= min {
+            return min;
+        }
+        min + (self.next_u64() % (max - min) as u64) as i32
+    }
+
+    /// A value in the range `0.0..1.0`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
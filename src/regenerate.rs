@@ -0,0 +1,148 @@
+use block::{ BlockType, BLOCK_TYPE_COUNT };
+use chunk::Chunk;
+use chunk_generator::TerrainGenerator;
+use decoration::{ BlockEdit, apply_edits };
+use math::*;
+
+/// How many blocks of each type a regeneration removes and adds.
+pub struct BlockDiff {
+    pub removed: [u32; BLOCK_TYPE_COUNT],
+    pub added: [u32; BLOCK_TYPE_COUNT],
+    /// The number of positions whose block changed.
+    pub changed: u32,
+}
+
+impl BlockDiff {
+    pub fn new() -> BlockDiff {
+        BlockDiff {
+            removed: [0; BLOCK_TYPE_COUNT],
+            added: [0; BLOCK_TYPE_COUNT],
+            changed: 0,
+        }
+    }
+
+    pub fn between(old: &Chunk, new: &Chunk) -> BlockDiff {
+        let mut diff = BlockDiff::new();
+        for ((_, old_block), (_, new_block)) in old.iter().zip(new.iter()) {
+            if old_block.ty != new_block.ty {
+                diff.removed[old_block.ty as usize] += 1;
+                diff.added[new_block.ty as usize] += 1;
+                diff.changed += 1;
+            }
+        }
+        diff
+    }
+
+    pub fn add(&mut self, other: &BlockDiff) {
+        for i in 0..BLOCK_TYPE_COUNT {
+            self.removed[i] += other.removed[i];
+            self.added[i] += other.added[i];
+        }
+        self.changed += other.changed;
+    }
+
+    /// One line per block type that changed, like `Stone: -120 +30`.
+    pub fn lines(&self) -> Vec<String> {
+        (0..BLOCK_TYPE_COUNT)
+            .filter(|&i| self.removed[i] != 0 || self.added[i] != 0)
+            .map(|i| format!("{:?}: -{} +{}", BlockType::from(i as u8), self.removed[i], self.added[i]))
+            .collect()
+    }
+}
+
+/// Generates the chunk at `coord` again, as it would come out if the world were new, including
+/// the decorations that spill into it from its neighbours. `player_edits` are written back on
+/// top when given. Also returns the decoration edits it spills into its own neighbours.
+pub fn regenerate_chunk(
+    terrain: &TerrainGenerator,
+    coord: ChunkCoord,
+    player_edits: Option<&[BlockEdit]>,
+) -> (Box<Chunk>, Vec<(ChunkCoord, BlockEdit)>) {
+    let (mut chunk, foreign_edits) = terrain.generate_decorated(coord);
+
+    // Decorations never reach further than the next chunk over.
+    for dx in -1..2 {
+        for dz in -1..2 {
+            if dx == 0 && dz == 0 {
+                continue;
+            }
+            let (_, spilled) = terrain.generate_decorated(ChunkCoord::new(coord.x + dx, coord.z + dz));
+            let edits: Vec<_> = spilled.into_iter()
+                .filter(|&(target, _)| target == coord)
+                .map(|(_, edit)| edit)
+                .collect();
+            apply_edits(&mut chunk, &edits);
+        }
+    }
+
+    if let Some(player_edits) = player_edits {
+        for edit in player_edits {
+            chunk.set(edit.pos, edit.block);
+        }
+    }
+
+    (chunk, foreign_edits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block::Block;
+    use decoration::{ PendingEdits, apply_edit };
+    use fnv::FnvHashMap;
+
+    #[test]
+    fn regenerating_matches_generating() {
+        let terrain = TerrainGenerator::with_defaults(17);
+        let mut chunks: FnvHashMap<ChunkCoord, Box<Chunk>> = FnvHashMap::default();
+        let mut pending = PendingEdits::new();
+        for x in -1..2 {
+            for z in -1..2 {
+                let coord = ChunkCoord::new(x, z);
+                let (mut chunk, foreign) = terrain.generate_decorated(coord);
+                if let Some(edits) = pending.take(coord) {
+                    apply_edits(&mut chunk, &edits);
+                }
+                chunks.insert(coord, chunk);
+                for (target, edit) in foreign {
+                    match chunks.get_mut(&target) {
+                        Some(chunk) => apply_edit(chunk, edit),
+                        None => pending.add(target, edit),
+                    }
+                }
+            }
+        }
+
+        let centre = ChunkCoord::new(0, 0);
+        let (regenerated, _) = regenerate_chunk(&terrain, centre, None);
+        assert!(regenerated.to_bytes() == chunks[&centre].to_bytes());
+    }
+
+    #[test]
+    fn player_edits_are_kept() {
+        let terrain = TerrainGenerator::with_defaults(17);
+        let edits = [
+            BlockEdit { pos: Coord::new(2, 1, 2), block: Block::new(BlockType::Air) },
+            BlockEdit { pos: Coord::new(5, 120, 9), block: Block::new(BlockType::Sponge) },
+        ];
+        let (chunk, _) = regenerate_chunk(&terrain, ChunkCoord::new(4, 4), Some(&edits));
+        for edit in edits.iter() {
+            assert_eq!(chunk.get(edit.pos), edit.block);
+        }
+    }
+
+    #[test]
+    fn diff_counts_each_block_type() {
+        let old = Chunk::new();
+        let mut new = Chunk::new();
+        new.set(Coord::new(0, 0, 0), Block::new(BlockType::Stone));
+        new.set(Coord::new(0, 1, 0), Block::new(BlockType::Stone));
+        new.set(Coord::new(0, 2, 0), Block::new(BlockType::Dirt));
+
+        let diff = BlockDiff::between(&old, &new);
+        assert_eq!(diff.changed, 3);
+        assert_eq!(diff.removed[BlockType::Air as usize], 3);
+        assert_eq!(diff.added[BlockType::Stone as usize], 2);
+        assert_eq!(diff.lines(), vec!["Air: -3 +0", "Dirt: -0 +1", "Stone: -0 +2"]);
+    }
+}
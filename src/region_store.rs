@@ -0,0 +1,498 @@
+use std::fs::{ self, File, OpenOptions };
+use std::io::{ self, Read, Seek, SeekFrom, Write };
+use std::path::{ Path, PathBuf };
+
+use fnv::FnvHashSet;
+
+use chunk::Chunk;
+use chunk_store::{ ChunkLoadError, ChunkStore, ChunkWriteError, checksum, decode_blocks, encode_blocks };
+use math::*;
+use world_generator::GeneratorStamp;
+
+/// Regions are this many chunks along each side.
+pub const REGION_SIDE: i32 = 32;
+/// log2 of `REGION_SIDE`, for finding the region a chunk is in.
+const REGION_SHIFT: i32 = 5;
+const REGION_CHUNKS: usize = (REGION_SIDE * REGION_SIDE) as usize;
+
+/// Every region file starts with this, then `REGION_VERSION`, then the table of where each
+/// chunk's record is in the file.
+const REGION_MAGIC: &[u8] = b"RGON";
+/// 1: a table entry is the offset and length of the record as little endian u32s, both 0 when
+/// the chunk isn't in the region. Each record is a checksum of the rest of it as a little
+/// endian i64, the generator's name length as a u8 and its name and version when there's a
+/// name, the biome data's length as a little endian u16 and the biome data, then the block
+/// data as `encode_blocks` writes it.
+const REGION_VERSION: u8 = 1;
+const HEADER_LEN: u64 = 5 + REGION_CHUNKS as u64 * 8;
+
+/// The longest a record can be before its biome and block data, enough for the generator name.
+const MAX_STAMP_LEN: usize = 8 + 1 + 255 + 4;
+
+/// Records are only ever appended, so every save leaves the old copy behind. A region file is
+/// rewritten without them once they take up more than this fraction of it...
+const MAX_WASTED_FRACTION: f64 = 0.5;
+/// ...and at least this many bytes, so small regions aren't rewritten all the time.
+const MIN_WASTED_BYTES: u64 = 1 << 20;
+
+/// Where quarantined chunks go, inside the store's directory. Each is a `.record` file holding
+/// its record as it was in the region, next to a `.reason` file saying why it was moved.
+const QUARANTINE_DIR: &str = "quarantine";
+
+/// Keeps chunks in region files of `REGION_SIDE` by `REGION_SIDE` chunks, named
+/// `r.<x>.<z>.region` after the region's coordinates, all in one directory.
+pub struct RegionChunkStore {
+    dir: PathBuf,
+    /// Regions written to since the last commit, which `commit` makes sure are on disk.
+    written: FnvHashSet<(i32, i32)>,
+}
+
+impl RegionChunkStore {
+    pub fn open(dir: PathBuf) -> Result<RegionChunkStore, String> {
+        fs::create_dir_all(&dir).map_err(|e| format!("failed to create {:?}: {}", dir, e))?;
+        Ok(RegionChunkStore {
+            dir,
+            written: FnvHashSet::default(),
+        })
+    }
+
+    fn region_path(&self, region: (i32, i32)) -> PathBuf {
+        self.dir.join(format!("r.{}.{}.region", region.0, region.1))
+    }
+}
+
+impl ChunkStore for RegionChunkStore {
+    fn load(&mut self, coord: ChunkCoord) -> Result<Box<Chunk>, ChunkLoadError> {
+        let (region, index) = region_of(coord);
+        let path = self.region_path(region);
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Err(ChunkLoadError::Missing),
+            Err(e) => return Err(load_error(&path, e)),
+        };
+        let entry = read_header(&mut file).map_err(|e| load_error(&path, e))?[index];
+        if entry.1 == 0 {
+            return Err(ChunkLoadError::Missing);
+        }
+        let bytes = read_record(&mut file, entry, entry.1 as usize).map_err(|e| load_error(&path, e))?;
+
+        let expected_checksum = read_u64(&bytes) as i64;
+        let body = &bytes[8..];
+        if checksum(body, &[]) != expected_checksum {
+            return Err(ChunkLoadError::ChecksumMismatch);
+        }
+        let corrupt = |what: &str| ChunkLoadError::Corrupt(format!("the {} is cut short", what));
+        let (stamp, rest) = read_stamp(body).ok_or_else(|| corrupt("generator"))?;
+        if rest.len() < 2 {
+            return Err(corrupt("biome data"));
+        }
+        let biome_len = rest[0] as usize | (rest[1] as usize) << 8;
+        if rest.len() < 2 + biome_len {
+            return Err(corrupt("biome data"));
+        }
+
+        let mut chunk = decode_blocks(&rest[2 + biome_len..]).map_err(ChunkLoadError::Corrupt)?;
+        chunk.set_biomes_from_bytes(&rest[2..2 + biome_len])
+            .map_err(|e| ChunkLoadError::Corrupt(format!("bad biome data, {}", e)))?;
+        chunk.set_generator(stamp);
+        Ok(chunk)
+    }
+
+    fn save(&mut self, coord: ChunkCoord, chunk: &Chunk) -> Result<(), ChunkWriteError> {
+        let (region, index) = region_of(coord);
+        let path = self.region_path(region);
+        write_record(&path, index, &encode_record(chunk))
+            .map_err(|e| ChunkWriteError::io(&e, format!("failed to write chunk {} to {:?}: {}", coord, path, e)))?;
+        self.written.insert(region);
+        Ok(())
+    }
+
+    fn list(&mut self) -> Result<Vec<(ChunkCoord, Option<GeneratorStamp>)>, String> {
+        let entries = fs::read_dir(&self.dir).map_err(|e| format!("failed to read {:?}: {}", self.dir, e))?;
+        let mut result = Vec::new();
+        for entry in entries {
+            let path = entry.map_err(|e| format!("failed to read {:?}: {}", self.dir, e))?.path();
+            let region = match path.file_name().and_then(|name| name.to_str()).and_then(parse_region_name) {
+                Some(region) => region,
+                None => continue,
+            };
+
+            let list_region = || -> io::Result<Vec<(ChunkCoord, Option<GeneratorStamp>)>> {
+                let mut file = File::open(&path)?;
+                let mut chunks = Vec::new();
+                for (index, &entry) in read_header(&mut file)?.iter().enumerate() {
+                    if entry.1 == 0 {
+                        continue;
+                    }
+                    let prefix = read_record(&mut file, entry, MAX_STAMP_LEN.min(entry.1 as usize))?;
+                    let stamp = if prefix.len() > 8 { read_stamp(&prefix[8..]).and_then(|(stamp, _)| stamp) } else { None };
+                    let coord = ChunkCoord::new(
+                        region.0 * REGION_SIDE + index as i32 % REGION_SIDE,
+                        region.1 * REGION_SIDE + index as i32 / REGION_SIDE,
+                    );
+                    chunks.push((coord, stamp));
+                }
+                Ok(chunks)
+            };
+            result.extend(list_region().map_err(|e| format!("failed to read {:?}: {}", path, e))?);
+        }
+        Ok(result)
+    }
+
+    fn delete(&mut self, coord: ChunkCoord) -> Result<(), ChunkWriteError> {
+        let (region, index) = region_of(coord);
+        let path = self.region_path(region);
+        if !path.exists() {
+            return Ok(());
+        }
+        let result = OpenOptions::new().write(true).open(&path)
+            .and_then(|mut file| write_entry(&mut file, index, (0, 0)));
+        result.map_err(|e| ChunkWriteError::io(&e, format!("failed to delete chunk {} from {:?}: {}", coord, path, e)))?;
+        self.written.insert(region);
+        Ok(())
+    }
+
+    /// Copies the chunk's record into the quarantine directory before taking it out of its
+    /// region. A region whose table can't be read is moved there whole, since none of its
+    /// chunks can be found.
+    fn quarantine(&mut self, coord: ChunkCoord, reason: &str) -> Result<(), ChunkWriteError> {
+        let (region, index) = region_of(coord);
+        let path = self.region_path(region);
+        quarantine_record(&path, region, index, coord, &self.dir.join(QUARANTINE_DIR), reason)
+            .map_err(|e| ChunkWriteError::io(&e, format!("failed to quarantine chunk {} from {:?}: {}", coord, path, e)))?;
+        self.delete(coord)
+    }
+
+    fn commit(&mut self) -> Result<(), ChunkWriteError> {
+        for region in self.written.drain() {
+            let path = self.dir.join(format!("r.{}.{}.region", region.0, region.1));
+            OpenOptions::new().write(true).open(&path)
+                .and_then(|file| file.sync_all())
+                .map_err(|e| ChunkWriteError::io(&e, format!("failed to write {:?}: {}", path, e)))?;
+        }
+        Ok(())
+    }
+}
+
+/// The region a chunk is in, and its index in the region's table.
+fn region_of(coord: ChunkCoord) -> ((i32, i32), usize) {
+    let region = (coord.x >> REGION_SHIFT, coord.z >> REGION_SHIFT);
+    let index = (coord.x & (REGION_SIDE - 1)) + (coord.z & (REGION_SIDE - 1)) * REGION_SIDE;
+    (region, index as usize)
+}
+
+fn parse_region_name(name: &str) -> Option<(i32, i32)> {
+    let parts: Vec<&str> = name.split('.').collect();
+    if parts.len() != 4 || parts[0] != "r" || parts[3] != "region" {
+        return None;
+    }
+    Some((parts[1].parse().ok()?, parts[2].parse().ok()?))
+}
+
+fn load_error(path: &Path, e: io::Error) -> ChunkLoadError {
+    match e.kind() {
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => {
+            ChunkLoadError::Corrupt(format!("region {:?} is damaged: {}", path, e))
+        }
+        _ => ChunkLoadError::Database(format!("failed to read region {:?}: {}", path, e)),
+    }
+}
+
+fn read_header(file: &mut File) -> io::Result<Vec<(u32, u32)>> {
+    let mut bytes = vec![0; HEADER_LEN as usize];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut bytes)?;
+    if &bytes[..REGION_MAGIC.len()] != REGION_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a region file"));
+    }
+    if bytes[4] != REGION_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown region version {}", bytes[4])));
+    }
+    Ok(bytes[5..].chunks(8).map(|entry| (read_u32(&entry[..4]), read_u32(&entry[4..]))).collect())
+}
+
+fn empty_header() -> Vec<u8> {
+    let mut header = REGION_MAGIC.to_vec();
+    header.push(REGION_VERSION);
+    header.resize(HEADER_LEN as usize, 0);
+    header
+}
+
+fn write_entry(file: &mut File, index: usize, entry: (u32, u32)) -> io::Result<()> {
+    let mut bytes = Vec::with_capacity(8);
+    push_u32(&mut bytes, entry.0);
+    push_u32(&mut bytes, entry.1);
+    file.seek(SeekFrom::Start(5 + index as u64 * 8))?;
+    file.write_all(&bytes)
+}
+
+/// Reads the first `len` bytes of the record a table entry points at.
+fn read_record(file: &mut File, entry: (u32, u32), len: usize) -> io::Result<Vec<u8>> {
+    let mut record = vec![0; len];
+    file.seek(SeekFrom::Start(entry.0 as u64))?;
+    file.read_exact(&mut record)?;
+    Ok(record)
+}
+
+/// Appends a record and points the table at it. The record is written before the table, so
+/// if the game stops in between the region still has the old copy.
+fn write_record(path: &Path, index: usize, record: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new().read(true).write(true).create(true).open(path)?;
+    if file.metadata()?.len() == 0 {
+        file.write_all(&empty_header())?;
+    }
+    let end = file.seek(SeekFrom::End(0))?;
+    file.write_all(record)?;
+    write_entry(&mut file, index, (end as u32, record.len() as u32))?;
+
+    let live: u64 = read_header(&mut file)?.iter().map(|entry| entry.1 as u64).sum();
+    let len = file.metadata()?.len();
+    let wasted = len - HEADER_LEN - live;
+    if wasted > MIN_WASTED_BYTES && wasted as f64 > len as f64 * MAX_WASTED_FRACTION {
+        drop(file);
+        compact(path)?;
+    }
+    Ok(())
+}
+
+/// Copies the record at `index` of the region at `path` into `dir`, with `reason` next to it.
+/// The region file is moved into `dir` instead when its table can't be read.
+fn quarantine_record(path: &Path, region: (i32, i32), index: usize, coord: ChunkCoord, dir: &Path, reason: &str) -> io::Result<()> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    fs::create_dir_all(dir)?;
+    let entry = match read_header(&mut file) {
+        Ok(header) => header[index],
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidData || e.kind() == io::ErrorKind::UnexpectedEof => {
+            drop(file);
+            let name = unused_name(dir, &format!("r.{}.{}", region.0, region.1));
+            File::create(dir.join(format!("{}.reason", name)))?.write_all(reason.as_bytes())?;
+            return fs::rename(path, dir.join(format!("{}.region", name)));
+        }
+        Err(e) => return Err(e),
+    };
+    if entry.1 == 0 {
+        return Ok(());
+    }
+
+    // A record that runs off the end of the file is kept as far as it goes.
+    let len = file.metadata()?.len().saturating_sub(entry.0 as u64).min(entry.1 as u64);
+    let record = read_record(&mut file, entry, len as usize)?;
+    let name = unused_name(dir, &format!("c.{}.{}", coord.x, coord.z));
+    File::create(dir.join(format!("{}.record", name)))?.write_all(&record)?;
+    File::create(dir.join(format!("{}.reason", name)))?.write_all(reason.as_bytes())
+}
+
+/// `base` followed by the first number that no quarantined file is named with yet, so a chunk
+/// quarantined again doesn't replace the earlier copy.
+fn unused_name(dir: &Path, base: &str) -> String {
+    (0..).map(|n| format!("{}.{}", base, n))
+        .find(|name| !dir.join(format!("{}.reason", name)).exists())
+        .unwrap()
+}
+
+/// Rewrites a region file with only the current copy of each chunk. The new file replaces the
+/// old one in one rename, so a crash part way through leaves the old one as it was.
+fn compact(path: &Path) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let header = read_header(&mut file)?;
+    let mut new_header = Vec::with_capacity(header.len());
+    let mut records = Vec::new();
+    for &entry in header.iter() {
+        if entry.1 == 0 {
+            new_header.push((0, 0));
+            continue;
+        }
+        new_header.push(((HEADER_LEN + records.len() as u64) as u32, entry.1));
+        records.extend(read_record(&mut file, entry, entry.1 as usize)?);
+    }
+
+    let mut bytes = empty_header();
+    bytes.truncate(5);
+    for &(offset, len) in new_header.iter() {
+        push_u32(&mut bytes, offset);
+        push_u32(&mut bytes, len);
+    }
+    bytes.extend(records);
+
+    let temp_path = path.with_extension("region.tmp");
+    let mut temp = File::create(&temp_path)?;
+    temp.write_all(&bytes)?;
+    temp.sync_all()?;
+    fs::rename(&temp_path, path)
+}
+
+fn encode_record(chunk: &Chunk) -> Vec<u8> {
+    let mut body = Vec::new();
+    match chunk.generator() {
+        Some(stamp) => {
+            assert!(!stamp.name.is_empty() && stamp.name.len() <= 255, "generator name {:?} can't be stored", stamp.name);
+            body.push(stamp.name.len() as u8);
+            body.extend(stamp.name.as_bytes());
+            push_u32(&mut body, stamp.version);
+        }
+        None => body.push(0),
+    }
+    let biome_data = chunk.biomes_to_bytes();
+    body.push(biome_data.len() as u8);
+    body.push((biome_data.len() >> 8) as u8);
+    body.extend(biome_data);
+    body.extend(encode_blocks(chunk));
+
+    let mut record = Vec::with_capacity(8 + body.len());
+    let sum = checksum(&body, &[]) as u64;
+    for i in 0..8 {
+        record.push((sum >> (i * 8)) as u8);
+    }
+    record.extend(body);
+    record
+}
+
+/// Reads the generator at the start of a record's body, returning it and the rest of the body.
+fn read_stamp(body: &[u8]) -> Option<(Option<GeneratorStamp>, &[u8])> {
+    let name_len = *body.get(0)? as usize;
+    if name_len == 0 {
+        return Some((None, &body[1..]));
+    }
+    if body.len() < 1 + name_len + 4 {
+        return None;
+    }
+    let name = String::from_utf8(body[1..1 + name_len].to_vec()).ok()?;
+    let version = read_u32(&body[1 + name_len..]);
+    Some((Some(GeneratorStamp { name, version }), &body[5 + name_len..]))
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    (0..4).fold(0, |n, i| n | (bytes[i] as u32) << (i * 8))
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    (0..8).fold(0, |n, i| n | (bytes[i] as u64) << (i * 8))
+}
+
+fn push_u32(bytes: &mut Vec<u8>, n: u32) {
+    for i in 0..4 {
+        bytes.push((n >> (i * 8)) as u8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use block::{ Block, BlockType };
+
+    /// An empty directory for a test's regions.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("craft-region-test-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn chunk_with(pos: Coord, version: u32) -> Box<Chunk> {
+        let mut chunk = Chunk::new();
+        chunk.set(pos, Block::with_state(BlockType::Log, 2));
+        chunk.set_generator(Some(GeneratorStamp { name: "noise".to_string(), version }));
+        chunk
+    }
+
+    #[test]
+    fn chunks_round_trip_across_regions() {
+        let dir = test_dir("round-trip");
+        let mut store = RegionChunkStore::open(dir.clone()).unwrap();
+        let coords = [ChunkCoord::new(0, 0), ChunkCoord::new(31, 31), ChunkCoord::new(-1, -33), ChunkCoord::new(70, -5)];
+        for (i, &coord) in coords.iter().enumerate() {
+            store.save(coord, &chunk_with(Coord::new(i as i32, 50, 7), i as u32)).unwrap();
+        }
+        store.commit().unwrap();
+
+        for (i, &coord) in coords.iter().enumerate() {
+            let chunk = store.load(coord).unwrap();
+            assert_eq!(chunk.get(Coord::new(i as i32, 50, 7)), Block::with_state(BlockType::Log, 2));
+            assert_eq!(chunk.generator().unwrap().version, i as u32);
+        }
+        assert_eq!(store.load(ChunkCoord::new(1, 0)).err(), Some(ChunkLoadError::Missing));
+        assert_eq!(store.load(ChunkCoord::new(500, 500)).err(), Some(ChunkLoadError::Missing));
+
+        let mut listed = store.list().unwrap();
+        listed.sort_by_key(|&(coord, _)| (coord.x, coord.z));
+        let mut expected: Vec<_> = coords.iter().enumerate()
+            .map(|(i, &coord)| (coord, Some(GeneratorStamp { name: "noise".to_string(), version: i as u32 })))
+            .collect();
+        expected.sort_by_key(|&(coord, _)| (coord.x, coord.z));
+        assert_eq!(listed, expected);
+
+        store.delete(ChunkCoord::new(31, 31)).unwrap();
+        assert_eq!(store.load(ChunkCoord::new(31, 31)).err(), Some(ChunkLoadError::Missing));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compacting_keeps_the_latest_copies() {
+        let dir = test_dir("compact");
+        let mut store = RegionChunkStore::open(dir.clone()).unwrap();
+        for version in 0..4 {
+            store.save(ChunkCoord::new(3, 4), &chunk_with(Coord::new(1, 1, 1), version)).unwrap();
+            store.save(ChunkCoord::new(5, 4), &chunk_with(Coord::new(2, 2, 2), version)).unwrap();
+        }
+        let path = store.region_path((0, 0));
+        let before = fs::metadata(&path).unwrap().len();
+        compact(&path).unwrap();
+        assert!(fs::metadata(&path).unwrap().len() < before);
+
+        assert_eq!(store.load(ChunkCoord::new(3, 4)).unwrap().generator().unwrap().version, 3);
+        assert_eq!(store.load(ChunkCoord::new(5, 4)).unwrap().get(Coord::new(2, 2, 2)).ty, BlockType::Log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn damaged_records_fail_their_checksum() {
+        let dir = test_dir("damaged");
+        let mut store = RegionChunkStore::open(dir.clone()).unwrap();
+        store.save(ChunkCoord::new(0, 0), &chunk_with(Coord::new(0, 0, 0), 1)).unwrap();
+
+        let path = store.region_path((0, 0));
+        let mut file = OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let mut last = [0];
+        file.seek(SeekFrom::End(-1)).unwrap();
+        file.read_exact(&mut last).unwrap();
+        file.seek(SeekFrom::End(-1)).unwrap();
+        file.write_all(&[last[0] ^ 0xFF]).unwrap();
+        drop(file);
+        assert_eq!(store.load(ChunkCoord::new(0, 0)).err(), Some(ChunkLoadError::ChecksumMismatch));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn quarantined_chunks_leave_the_save() {
+        let dir = test_dir("quarantine");
+        let mut store = RegionChunkStore::open(dir.clone()).unwrap();
+        store.save(ChunkCoord::new(-3, 2), &chunk_with(Coord::new(0, 0, 0), 1)).unwrap();
+        store.save(ChunkCoord::new(-4, 2), &chunk_with(Coord::new(0, 0, 0), 2)).unwrap();
+        let record = encode_record(&chunk_with(Coord::new(0, 0, 0), 1));
+
+        store.quarantine(ChunkCoord::new(-3, 2), "checksum").unwrap();
+        assert_eq!(store.load(ChunkCoord::new(-3, 2)).err(), Some(ChunkLoadError::Missing));
+        assert_eq!(store.list().unwrap().len(), 1);
+
+        let read = |name: &str| {
+            let mut bytes = Vec::new();
+            File::open(dir.join(QUARANTINE_DIR).join(name)).unwrap().read_to_end(&mut bytes).unwrap();
+            bytes
+        };
+        assert!(read("c.-3.2.0.record") == record);
+        assert_eq!(read("c.-3.2.0.reason"), b"checksum");
+
+        // A region with a damaged table goes to quarantine whole.
+        let path = store.region_path(region_of(ChunkCoord::new(-4, 2)).0);
+        OpenOptions::new().write(true).open(&path).unwrap().write_all(b"JUNK").unwrap();
+        store.quarantine(ChunkCoord::new(-4, 2), "damaged").unwrap();
+        assert!(!path.exists());
+        assert_eq!(read("r.-1.0.0.reason"), b"damaged");
+        assert!(store.list().unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+use std::path::PathBuf;
+use std::process;
+
+use image::{ Rgb, RgbImage };
+
+use biome::Biome;
+use chunk::{ CHUNK_COLUMN_COUNT, CHUNK_SIDE_LENGTH };
+use craft::load_settings;
+use erosion::Eroder;
+use math::*;
+use noise_generator::NoiseTerrain;
+use preset::{ WorldPreset, DEFAULT_PRESET, find_preset };
+
+const USAGE: &str = "usage: craft preview <seed> <x> <z> <size> [preset] [output path]
+
+Draws a <size> by <size> block map of the world the seed makes, centred on the block at <x>, <z>,
+one block per pixel. Only works for presets with noise terrain, and defaults to the default preset
+and preview.png. Nothing is generated or saved, so it's quick to try out seeds.";
+
+const MAX_SIZE: i32 = 4096;
+
+/// How much brighter or darker a pixel gets for each block of slope towards the north west.
+const SLOPE_SHADING: f64 = 0.08;
+
+pub struct PreviewArea {
+    pub seed: u32,
+    pub centre_x: i32,
+    pub centre_z: i32,
+    pub size: i32,
+}
+
+fn parse_args(args: &[String]) -> Result<(PreviewArea, String, PathBuf), String> {
+    if args.len() < 4 || args.len() > 6 {
+        return Err("expected between 4 and 6 arguments".to_string());
+    }
+    let seed: u32 = args[0].parse().map_err(|_| format!("{:?} isn't a valid seed", args[0]))?;
+    let centre_x: i32 = args[1].parse().map_err(|_| format!("{:?} isn't a valid x coordinate", args[1]))?;
+    let centre_z: i32 = args[2].parse().map_err(|_| format!("{:?} isn't a valid z coordinate", args[2]))?;
+    let size: i32 = args[3].parse().map_err(|_| format!("{:?} isn't a valid size", args[3]))?;
+    if size < 1 || size > MAX_SIZE {
+        return Err(format!("the size must be between 1 and {}", MAX_SIZE));
+    }
+    let preset = args.get(4).cloned().unwrap_or_else(|| DEFAULT_PRESET.to_string());
+    let output = args.get(5).cloned().unwrap_or_else(|| "preview.png".to_string());
+
+    Ok((PreviewArea { seed, centre_x, centre_z, size }, preset, output.into()))
+}
+
+/// Runs the `preview` subcommand with the arguments that follow it.
+pub fn run(args: &[String]) {
+    let (area, preset_name, output) = match parse_args(args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    load_settings();
+    let preset = find_preset(&preset_name);
+    if preset.terrain != "noise" {
+        eprintln!("The {:?} preset uses {} terrain, previews only work for noise terrain.", preset.name, preset.terrain);
+        process::exit(1);
+    }
+
+    println!("Drawing {} by {} blocks of seed {} around {}, {}", area.size, area.size, area.seed, area.centre_x, area.centre_z);
+    let image = render(&area, &preset);
+    if let Err(e) = image.save(&output) {
+        eprintln!("Failed to write {:?}: {}", output, e);
+        process::exit(1);
+    }
+    println!("Wrote {:?}", output);
+}
+
+/// Draws the area from the noise terrain's heightmap and biomes. Water is shaded by its depth,
+/// land by its biome, and both are lit from the north west.
+pub fn render(area: &PreviewArea, preset: &WorldPreset) -> RgbImage {
+    let terrain = NoiseTerrain::new(area.seed, preset.sea_level);
+    let eroder = preset.erosion.clone().map(Eroder::new);
+    let min_x = area.centre_x - area.size / 2;
+    let min_z = area.centre_z - area.size / 2;
+    let size = area.size as usize;
+
+    // Work a chunk at a time, the same way the generator does, but only keep the heights.
+    let mut heights = vec![0; size * size];
+    let min_chunk = ChunkCoord::from_world_pos(Coord { x: min_x, y: 0, z: min_z });
+    let max_chunk = ChunkCoord::from_world_pos(Coord { x: min_x + area.size - 1, y: 0, z: min_z + area.size - 1 });
+    for chunk_x in min_chunk.x..max_chunk.x + 1 {
+        for chunk_z in min_chunk.z..max_chunk.z + 1 {
+            let coord = ChunkCoord::new(chunk_x, chunk_z);
+            let origin_x = chunk_x * CHUNK_SIDE_LENGTH as i32;
+            let origin_z = chunk_z * CHUNK_SIDE_LENGTH as i32;
+            let columns = match eroder {
+                Some(ref eroder) => eroder.chunk_columns(&terrain, area.seed, coord).0,
+                None => {
+                    let mut columns = [0; CHUNK_COLUMN_COUNT];
+                    columns.copy_from_slice(&terrain.height_grid(origin_x, origin_z, CHUNK_SIDE_LENGTH as i32));
+                    columns
+                }
+            };
+
+            for z in 0..CHUNK_SIDE_LENGTH as i32 {
+                for x in 0..CHUNK_SIDE_LENGTH as i32 {
+                    let (px, pz) = (origin_x + x - min_x, origin_z + z - min_z);
+                    if px >= 0 && pz >= 0 && px < area.size && pz < area.size {
+                        heights[px as usize + pz as usize * size] = columns[(x + z * CHUNK_SIDE_LENGTH as i32) as usize];
+                    }
+                }
+            }
+        }
+    }
+
+    let mut image = RgbImage::new(area.size as u32, area.size as u32);
+    for pz in 0..size {
+        for px in 0..size {
+            let height = heights[px + pz * size];
+            let (x, z) = (min_x + px as i32, min_z + pz as i32);
+
+            let base = match preset.sea_level {
+                Some(sea_level) if height < sea_level => {
+                    let depth = (sea_level - height) as f64;
+                    let light = (1.0 - depth / 24.0).max(0.35);
+                    image.put_pixel(px as u32, pz as u32, shade([40, 90, 190], light));
+                    continue;
+                }
+                Some(sea_level) if height <= sea_level + 1 => [220, 205, 150],
+                _ => biome_color(terrain.biome_at(x, z)),
+            };
+
+            let west = if px > 0 { heights[px - 1 + pz * size] } else { height };
+            let north = if pz > 0 { heights[px + (pz - 1) * size] } else { height };
+            let slope = ((height - west) + (height - north)) as f64;
+            let light = (1.0 + slope * SLOPE_SHADING).max(0.5).min(1.5) * (0.8 + height as f64 / 320.0);
+            image.put_pixel(px as u32, pz as u32, shade(base, light));
+        }
+    }
+    image
+}
+
+fn biome_color(biome: Biome) -> [u8; 3] {
+    match biome {
+        Biome::Plains => [110, 165, 70],
+        Biome::Desert => [215, 195, 130],
+        Biome::Forest => [60, 115, 45],
+        Biome::Mountains => [135, 130, 125],
+        Biome::Tundra => [195, 205, 205],
+    }
+}
+
+fn shade(color: [u8; 3], light: f64) -> Rgb<u8> {
+    let channel = |c: u8| (c as f64 * light).max(0.0).min(255.0) as u8;
+    Rgb([channel(color[0]), channel(color[1]), channel(color[2])])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn arguments_are_parsed() {
+        let (area, preset, output) = parse_args(&args(&["42", "-100", "30", "512", "eroded", "map.png"])).unwrap();
+        assert_eq!((area.seed, area.centre_x, area.centre_z, area.size), (42, -100, 30, 512));
+        assert_eq!(preset, "eroded");
+        assert_eq!(output, PathBuf::from("map.png"));
+
+        assert!(parse_args(&args(&["42", "0", "0"])).is_err());
+        assert!(parse_args(&args(&["-1", "0", "0", "64"])).is_err());
+        assert!(parse_args(&args(&["42", "0", "0", "0"])).is_err());
+    }
+
+    #[test]
+    fn same_seed_gives_same_image() {
+        let area = PreviewArea { seed: 7, centre_x: -10, centre_z: 25, size: 40 };
+        let image = render(&area, &WorldPreset::default());
+        assert_eq!(image.dimensions(), (40, 40));
+        assert!(image.into_raw() == render(&area, &WorldPreset::default()).into_raw());
+    }
+}
@@ -0,0 +1,38 @@
+use std::sync::atomic::{ AtomicBool, Ordering, ATOMIC_BOOL_INIT };
+
+static SHUTDOWN_REQUESTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+#[cfg(unix)]
+mod ffi {
+    use std::os::raw::c_int;
+
+    pub const SIGINT: c_int = 2;
+    pub const SIGTERM: c_int = 15;
+
+    extern "C" {
+        pub fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn on_signal(_: ::std::os::raw::c_int) {
+    // Only an atomic store, anything more isn't safe in a signal handler.
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Makes SIGINT and SIGTERM ask the game to close, so it gets to save, rather than killing it.
+#[cfg(unix)]
+pub fn install_handlers() {
+    unsafe {
+        ffi::signal(ffi::SIGINT, on_signal);
+        ffi::signal(ffi::SIGTERM, on_signal);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn install_handlers() {}
+
+/// Whether a signal has asked the game to close.
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
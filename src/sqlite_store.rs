@@ -0,0 +1,233 @@
+use rusqlite::{ Connection, Error as SqliteError, Result as SqliteResult };
+
+use std::path::Path;
+
+use chunk::Chunk;
+use chunk_store::{ ChunkLoadError, ChunkStore, ChunkWriteError, checksum, decode_blocks, encode_blocks };
+use math::*;
+use world_generator::GeneratorStamp;
+
+/// Keeps chunks in the `chunks` table of the save itself, where every world kept them before
+/// there were other stores. Uses its own connection to the save, which must already be at the
+/// current schema version.
+pub struct SqliteChunkStore {
+    conn: Connection,
+}
+
+impl SqliteChunkStore {
+    pub fn open(path: &Path) -> Result<SqliteChunkStore, String> {
+        Connection::open(path)
+            .map(SqliteChunkStore::new)
+            .map_err(|e| format!("failed to open {:?}: {}", path, e))
+    }
+
+    pub fn new(conn: Connection) -> SqliteChunkStore {
+        SqliteChunkStore { conn }
+    }
+}
+
+impl ChunkStore for SqliteChunkStore {
+    fn load(&mut self, coord: ChunkCoord) -> Result<Box<Chunk>, ChunkLoadError> {
+        load_chunk(&self.conn, coord)
+    }
+
+    fn save(&mut self, coord: ChunkCoord, chunk: &Chunk) -> Result<(), ChunkWriteError> {
+        save_chunk(&self.conn, coord, chunk).map_err(ChunkWriteError::sqlite)
+    }
+
+    fn list(&mut self) -> Result<Vec<(ChunkCoord, Option<GeneratorStamp>)>, String> {
+        let list = || -> SqliteResult<_> {
+            let mut stmt = self.conn.prepare("SELECT x, z, generator, generator_version FROM chunks")?;
+            let mut result = Vec::new();
+            let mut iter = stmt.query(&[])?;
+            while let Some(row) = iter.next() {
+                let row = row?;
+                result.push((ChunkCoord::new(row.get(0), row.get(1)), read_stamp(row.get(2), row.get(3))));
+            }
+            Ok(result)
+        };
+        list().map_err(|e| e.to_string())
+    }
+
+    fn delete(&mut self, coord: ChunkCoord) -> Result<(), ChunkWriteError> {
+        self.conn.execute("DELETE FROM chunks WHERE x = ? AND z = ?", &[&coord.x, &coord.z])
+            .map(|_| ())
+            .map_err(ChunkWriteError::sqlite)
+    }
+
+    fn quarantine(&mut self, coord: ChunkCoord, reason: &str) -> Result<(), ChunkWriteError> {
+        quarantine_chunk(&self.conn, coord, reason).map_err(ChunkWriteError::sqlite)
+    }
+
+    fn begin(&mut self) -> Result<(), ChunkWriteError> {
+        self.conn.execute_batch("BEGIN").map_err(ChunkWriteError::sqlite)
+    }
+
+    fn commit(&mut self) -> Result<(), ChunkWriteError> {
+        self.conn.execute_batch("COMMIT").map_err(ChunkWriteError::sqlite)
+    }
+
+    fn rollback(&mut self) {
+        // Fails only if there's no transaction, in which case there's nothing to throw away.
+        let _ = self.conn.execute_batch("ROLLBACK");
+    }
+}
+
+pub fn save_chunk(conn: &Connection, coord: ChunkCoord, chunk: &Chunk) -> SqliteResult<()> {
+    let mut store_stmt = conn.prepare_cached("INSERT OR REPLACE INTO chunks (x, z, block_data, biome_data, generator, generator_version, checksum) VALUES (:x, :z, :block_data, :biome_data, :generator, :generator_version, :checksum)")?;
+
+    let block_data = encode_blocks(chunk);
+    let biome_data = chunk.biomes_to_bytes();
+    let generator = chunk.generator().map(|stamp| stamp.name.clone());
+    let generator_version = chunk.generator().map(|stamp| stamp.version as i64);
+    store_stmt.execute_named(&[
+        (":x", &coord.x),
+        (":z", &coord.z),
+        (":block_data", &block_data),
+        (":biome_data", &biome_data),
+        (":generator", &generator),
+        (":generator_version", &generator_version),
+        (":checksum", &checksum(&block_data, &biome_data))
+    ])?;
+    Ok(())
+}
+
+/// Moves a chunk's row into the quarantine table, so it can be looked at later but isn't
+/// loaded again.
+pub fn quarantine_chunk(conn: &Connection, coord: ChunkCoord, reason: &str) -> SqliteResult<()> {
+    conn.execute_named(
+        "INSERT INTO quarantine (x, z, block_data, biome_data, generator, generator_version, checksum, reason)
+         SELECT x, z, block_data, biome_data, generator, generator_version, checksum, :reason FROM chunks WHERE x = :x AND z = :z",
+        &[(":reason", &reason), (":x", &coord.x), (":z", &coord.z)]
+    )?;
+    conn.execute("DELETE FROM chunks WHERE x = ? AND z = ?", &[&coord.x, &coord.z])?;
+    Ok(())
+}
+
+pub fn load_chunk(conn: &Connection, coord: ChunkCoord) -> Result<Box<Chunk>, ChunkLoadError> {
+    let database_error = |e: SqliteError| match e {
+        SqliteError::QueryReturnedNoRows => ChunkLoadError::Missing,
+        e => ChunkLoadError::Database(e.to_string()),
+    };
+    let mut load_stmt = conn.prepare_cached("SELECT block_data, biome_data, generator, generator_version, checksum FROM chunks WHERE x = :x AND z = :z")
+        .map_err(&database_error)?;
+    //conn.blob_open(DatabaseName::Main, "chunks", "block_data", row, true)
+    let columns = load_stmt.query_row(
+        &[&coord.x, &coord.z],
+        |row| -> SqliteResult<_> {
+            Ok((
+                row.get_checked::<_, Vec<u8>>(0)?,
+                row.get_checked::<_, Option<Vec<u8>>>(1)?,
+                read_stamp(row.get_checked(2)?, row.get_checked(3)?),
+                row.get_checked::<_, Option<i64>>(4)?,
+            ))
+        }
+    ).map_err(&database_error)?;
+    let (block_data, biome_data, stamp, expected_checksum) = columns
+        .map_err(|e| ChunkLoadError::Corrupt(format!("a column has the wrong type: {}", e)))?;
+
+    // Rows saved before checksums existed, and not since, have none and are taken on trust.
+    if let Some(expected_checksum) = expected_checksum {
+        let biome_bytes = biome_data.as_ref().map_or(&[][..], |data| &data[..]);
+        if checksum(&block_data, biome_bytes) != expected_checksum {
+            return Err(ChunkLoadError::ChecksumMismatch);
+        }
+    }
+
+    let mut chunk = decode_blocks(&block_data).map_err(ChunkLoadError::Corrupt)?;
+    // Chunks saved before biomes existed are left as the default biome.
+    if let Some(biome_data) = biome_data {
+        chunk.set_biomes_from_bytes(&biome_data)
+            .map_err(|e| ChunkLoadError::Corrupt(format!("bad biome data, {}", e)))?;
+    }
+    chunk.set_generator(stamp);
+    Ok(chunk)
+}
+
+fn read_stamp(name: Option<String>, version: Option<i64>) -> Option<GeneratorStamp> {
+    match (name, version) {
+        (Some(name), Some(version)) => Some(GeneratorStamp { name, version: version as u32 }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deflate::deflate_bytes_zlib;
+    use block::{ Block, BlockType };
+    use chunk_loader::init_database;
+    use chunk_store::{ CHUNK_BLOB_MAGIC, CHUNK_BLOB_VERSION };
+
+    /// A new save holding one chunk at 0, 0, with its row then changed by `corrupt`.
+    fn corrupted_save(corrupt: &str) -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init_database(&mut conn);
+        let mut chunk = Chunk::new();
+        chunk.set(Coord::new(3, 70, 9), Block::new(BlockType::Sponge));
+        save_chunk(&conn, ChunkCoord::new(0, 0), &chunk).unwrap();
+        conn.execute_batch(corrupt).unwrap();
+        conn
+    }
+
+    /// Makes the stored checksum match whatever `block_data` now is, so that decoding is what
+    /// catches the damage.
+    fn fix_checksum(conn: &Connection) {
+        let (block_data, biome_data): (Vec<u8>, Vec<u8>) = conn.query_row(
+            "SELECT block_data, biome_data FROM chunks", &[], |row| (row.get(0), row.get(1))
+        ).unwrap();
+        conn.execute("UPDATE chunks SET checksum = ?", &[&checksum(&block_data, &biome_data)]).unwrap();
+    }
+
+    #[test]
+    fn damaged_rows_fail_their_checksum() {
+        let conn = corrupted_save("UPDATE chunks SET block_data = substr(block_data, 1, length(block_data) - 1) || x'00'");
+        assert_eq!(load_chunk(&conn, ChunkCoord::new(0, 0)).err(), Some(ChunkLoadError::ChecksumMismatch));
+        assert_eq!(load_chunk(&conn, ChunkCoord::new(1, 0)).err(), Some(ChunkLoadError::Missing));
+    }
+
+    #[test]
+    fn undecodable_rows_are_corrupt() {
+        let broken = [
+            // Not zlib.
+            "UPDATE chunks SET block_data = x'43484E4B01DEADBEEF'",
+            // No header.
+            "UPDATE chunks SET block_data = x'0102030405'",
+            // A header from the future.
+            "UPDATE chunks SET block_data = x'43484E4B07'",
+            // Not enough biomes.
+            "UPDATE chunks SET biome_data = x'0000'",
+        ];
+        for sql in broken.iter() {
+            let conn = corrupted_save(sql);
+            fix_checksum(&conn);
+            match load_chunk(&conn, ChunkCoord::new(0, 0)) {
+                Err(ChunkLoadError::Corrupt(_)) => {}
+                other => panic!("{:?} gave {:?}", sql, other.map(|_| ())),
+            }
+        }
+
+        // Blocks that decompress fine but are the wrong length.
+        let conn = corrupted_save("");
+        let mut blob = CHUNK_BLOB_MAGIC.to_vec();
+        blob.push(CHUNK_BLOB_VERSION);
+        blob.extend(deflate_bytes_zlib(&[1; 100]));
+        conn.execute("UPDATE chunks SET block_data = ?", &[&blob]).unwrap();
+        fix_checksum(&conn);
+        match load_chunk(&conn, ChunkCoord::new(0, 0)) {
+            Err(ChunkLoadError::Corrupt(_)) => {}
+            other => panic!("short blocks gave {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn quarantined_chunks_leave_the_save() {
+        let mut store = SqliteChunkStore::new(corrupted_save("UPDATE chunks SET checksum = checksum + 1"));
+        assert_eq!(store.list().unwrap().len(), 1);
+        store.quarantine(ChunkCoord::new(0, 0), "checksum").unwrap();
+        assert_eq!(store.load(ChunkCoord::new(0, 0)).err(), Some(ChunkLoadError::Missing));
+        assert!(store.list().unwrap().is_empty());
+        let (x, reason): (i64, String) = store.conn.query_row("SELECT x, reason FROM quarantine", &[], |row| (row.get(0), row.get(1))).unwrap();
+        assert_eq!((x, &reason[..]), (0, "checksum"));
+    }
+}
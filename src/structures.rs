@@ -0,0 +1,495 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::{ self, File };
+use std::hash::Hasher;
+use std::io::prelude::*;
+
+use fnv::{ FnvHashMap, FnvHasher };
+use toml;
+
+use block::{ Block, BlockType };
+use chunk::{ Chunk, CHUNK_SIDE_LENGTH, WORLD_HEIGHT };
+use math::*;
+use random::Rng;
+use world_generator::WorldGenerator;
+
+const STRUCTURES_PATH: &str = "structures";
+const STRUCTURE_SALT: u32 = 400;
+
+/// How far the ground under a surface structure may rise or fall across its footprint.
+const MAX_SLOPE: i32 = 2;
+/// The least amount of rock kept above an underground structure.
+const MIN_COVER: i32 = 4;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Placement {
+    /// Sits on dry, fairly flat ground, with the origin in the first air block above it.
+    Surface,
+    /// Buried somewhere below the lowest ground around it.
+    Underground,
+}
+
+/// A structure as written in a template file in `structures/`.
+///
+/// `layers` go from the bottom up, each one a list of rows along z, each row a string with one
+/// character per block along x. Characters are looked up in `palette`, and a space leaves
+/// whatever was generated there alone.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StructureTemplate {
+    pub name: String,
+    pub placement: Placement,
+    /// The side length, in chunks, of the square regions that get at most one of the structure.
+    pub spacing: i32,
+    /// The chance of a region getting the structure.
+    pub chance: f64,
+    /// The cell placed at the chosen spot, as `[x, y, z]`.
+    pub origin: [i32; 3],
+    pub layers: Vec<Vec<String>>,
+    // TOML needs tables after plain values, so this stays at the end.
+    pub palette: BTreeMap<String, BlockType>,
+}
+
+/// A template checked and unpacked into a grid of cells.
+pub struct Structure {
+    pub name: String,
+    placement: Placement,
+    spacing: i32,
+    chance: f64,
+    salt: u32,
+    /// The size of the grid along x, y and z.
+    size: Coord,
+    origin: Coord,
+    cells: Vec<Option<BlockType>>,
+}
+
+impl Structure {
+    pub fn from_template(template: &StructureTemplate) -> Result<Structure, String> {
+        let height = template.layers.len() as i32;
+        let depth = template.layers.first().map_or(0, |layer| layer.len()) as i32;
+        let width = template.layers.first().and_then(|layer| layer.first()).map_or(0, |row| row.chars().count()) as i32;
+        if width == 0 || height == 0 || depth == 0 {
+            return Err("the template has no blocks".to_string());
+        }
+        if template.spacing < 1 {
+            return Err("spacing must be at least 1".to_string());
+        }
+
+        let mut cells = Vec::with_capacity((width * height * depth) as usize);
+        for layer in template.layers.iter() {
+            if layer.len() as i32 != depth {
+                return Err("every layer must have the same number of rows".to_string());
+            }
+            for row in layer.iter() {
+                if row.chars().count() as i32 != width {
+                    return Err(format!("row {:?} is a different length to the others", row));
+                }
+                for c in row.chars() {
+                    if c == ' ' {
+                        cells.push(None);
+                        continue;
+                    }
+                    match template.palette.get(&c.to_string()) {
+                        Some(&ty) => cells.push(Some(ty)),
+                        None => return Err(format!("{:?} isn't in the palette", c)),
+                    }
+                }
+            }
+        }
+
+        let (ox, oy, oz) = (template.origin[0], template.origin[1], template.origin[2]);
+        if ox < 0 || ox >= width || oy < 0 || oy >= height || oz < 0 || oz >= depth {
+            return Err("the origin is outside of the template".to_string());
+        }
+
+        let mut hasher = FnvHasher::default();
+        hasher.write(template.name.as_bytes());
+
+        Ok(Structure {
+            name: template.name.clone(),
+            placement: template.placement,
+            spacing: template.spacing,
+            chance: template.chance,
+            // Salting by name rather than by position in the list means adding a template
+            // doesn't move all of the others.
+            salt: STRUCTURE_SALT.wrapping_add(hasher.finish() as u32),
+            size: Coord::new(width, height, depth),
+            origin: Coord::new(ox, oy, oz),
+            cells,
+        })
+    }
+
+    fn cell(&self, x: i32, y: i32, z: i32) -> Option<BlockType> {
+        self.cells[((y * self.size.z + z) * self.size.x + x) as usize]
+    }
+
+    /// Where the template cell at `x`, `z` ends up after mirroring and rotating, within the
+    /// rotated footprint.
+    fn orient(&self, x: i32, z: i32, rotation: u8, mirrored: bool) -> (i32, i32) {
+        let (mut width, mut depth) = (self.size.x, self.size.z);
+        let mut x = if mirrored { width - 1 - x } else { x };
+        let mut z = z;
+        for _ in 0..rotation {
+            let rotated_x = depth - 1 - z;
+            z = x;
+            x = rotated_x;
+            ::std::mem::swap(&mut width, &mut depth);
+        }
+        (x, z)
+    }
+
+    /// The world position of the footprint's lowest corner, and its size along x and z.
+    fn footprint(&self, start: &StructureStart) -> (i32, i32, i32, i32) {
+        let (origin_x, origin_z) = self.orient(self.origin.x, self.origin.z, start.rotation, start.mirrored);
+        let (width, depth) = if start.rotation % 2 == 0 {
+            (self.size.x, self.size.z)
+        } else {
+            (self.size.z, self.size.x)
+        };
+        (start.pos.x - origin_x, start.pos.z - origin_z, width, depth)
+    }
+}
+
+/// Reads every template in `structures/`. Templates that fail to parse are reported and left out.
+pub fn load_structures() -> Vec<Structure> {
+    let entries = match fs::read_dir(STRUCTURES_PATH) {
+        Ok(entries) => entries,
+        Err(_) => {
+            info!("No structure templates found.");
+            return Vec::new();
+        }
+    };
+
+    let mut paths: Vec<_> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().map_or(false, |ext| ext == "toml"))
+        .collect();
+    paths.sort();
+
+    let mut structures = Vec::new();
+    for path in paths {
+        let mut string = String::new();
+        if let Ok(mut file) = File::open(&path) {
+            file.read_to_string(&mut string);
+        }
+        let structure = toml::de::from_str::<StructureTemplate>(&string)
+            .map_err(|e| e.to_string())
+            .and_then(|template| Structure::from_template(&template));
+        match structure {
+            Ok(structure) => structures.push(structure),
+            Err(e) => warn!("Failed to load structure template {}: {}.", path.display(), e),
+        }
+    }
+    info!("Loaded {} structure templates.", structures.len());
+    structures
+}
+
+/// Where a structure was placed. `pos` is the world position of the template's origin.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct StructureStart {
+    pub name: String,
+    pub pos: Coord,
+    /// Quarter turns about the y axis, applied after mirroring.
+    pub rotation: u8,
+    /// Whether the template is flipped along x.
+    pub mirrored: bool,
+}
+
+/// Picks seeded positions for structures and writes the parts of them that fall in each chunk.
+///
+/// The world is split into square regions for each structure, and each region gets at most one,
+/// at a position that only depends on the seed and the region. Checking the spot means looking
+/// at the terrain, which may be in chunks that haven't been generated yet, so the placer asks the
+/// world generator for that terrain itself. The result is the same whichever order chunks are
+/// generated in.
+pub struct StructurePlacer {
+    seed: u32,
+    structures: Vec<Structure>,
+    /// Every region looked at so far, since they're needed again by each chunk they overlap.
+    starts: RefCell<FnvHashMap<(usize, i32, i32), Option<StructureStart>>>,
+}
+
+impl StructurePlacer {
+    pub fn new(seed: u32, structures: Vec<Structure>) -> StructurePlacer {
+        StructurePlacer {
+            seed,
+            structures,
+            starts: RefCell::new(FnvHashMap::default()),
+        }
+    }
+
+    /// Writes the parts of any structures overlapping the chunk at `coord`.
+    pub fn place(&self, terrain: &WorldGenerator, sea_level: Option<i32>, coord: ChunkCoord, chunk: &mut Chunk) {
+        let chunk_x = coord.x * CHUNK_SIDE_LENGTH as i32;
+        let chunk_z = coord.z * CHUNK_SIDE_LENGTH as i32;
+
+        for (i, structure) in self.structures.iter().enumerate() {
+            // Far enough to reach any region whose structure could hang over into this chunk.
+            let reach = (structure.size.x.max(structure.size.z) + CHUNK_SIDE_LENGTH as i32 - 1) / CHUNK_SIDE_LENGTH as i32 + 1;
+            for region_x in floor_div(coord.x - reach, structure.spacing)..floor_div(coord.x + reach, structure.spacing) + 1 {
+                for region_z in floor_div(coord.z - reach, structure.spacing)..floor_div(coord.z + reach, structure.spacing) + 1 {
+                    let start = match self.start(i, region_x, region_z, terrain, sea_level) {
+                        Some(start) => start,
+                        None => continue,
+                    };
+                    let (min_x, min_z, _, _) = structure.footprint(&start);
+
+                    for y in 0..structure.size.y {
+                        for z in 0..structure.size.z {
+                            for x in 0..structure.size.x {
+                                let ty = match structure.cell(x, y, z) {
+                                    Some(ty) => ty,
+                                    None => continue,
+                                };
+                                let (dx, dz) = structure.orient(x, z, start.rotation, start.mirrored);
+                                let local = Coord {
+                                    x: min_x + dx - chunk_x,
+                                    y: start.pos.y + y - structure.origin.y,
+                                    z: min_z + dz - chunk_z,
+                                };
+                                if 0 <= local.x && local.x < CHUNK_SIDE_LENGTH as i32 &&
+                                    0 < local.y && local.y < WORLD_HEIGHT as i32 &&
+                                    0 <= local.z && local.z < CHUNK_SIDE_LENGTH as i32
+                                {
+                                    chunk.set(local, Block::new(ty));
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// The structures whose origin is in the chunk at `coord`.
+    pub fn starts_in(&self, terrain: &WorldGenerator, sea_level: Option<i32>, coord: ChunkCoord) -> Vec<StructureStart> {
+        let mut result = Vec::new();
+        for (i, structure) in self.structures.iter().enumerate() {
+            let region_x = floor_div(coord.x, structure.spacing);
+            let region_z = floor_div(coord.z, structure.spacing);
+            if let Some(start) = self.start(i, region_x, region_z, terrain, sea_level) {
+                if ChunkCoord::from_world_pos(start.pos) == coord {
+                    result.push(start);
+                }
+            }
+        }
+        result
+    }
+
+    fn start(&self, i: usize, region_x: i32, region_z: i32, terrain: &WorldGenerator, sea_level: Option<i32>) -> Option<StructureStart> {
+        if let Some(start) = self.starts.borrow().get(&(i, region_x, region_z)) {
+            return start.clone();
+        }
+        let start = self.find_start(&self.structures[i], region_x, region_z, terrain, sea_level);
+        self.starts.borrow_mut().insert((i, region_x, region_z), start.clone());
+        start
+    }
+
+    fn find_start(&self, structure: &Structure, region_x: i32, region_z: i32, terrain: &WorldGenerator, sea_level: Option<i32>) -> Option<StructureStart> {
+        let mut rng = Rng::for_chunk(self.seed, ChunkCoord::new(region_x, region_z), structure.salt);
+        if rng.next_f64() >= structure.chance {
+            return None;
+        }
+
+        let side = CHUNK_SIDE_LENGTH as i32;
+        let x = (region_x * structure.spacing + rng.range(0, structure.spacing)) * side + rng.range(0, side);
+        let z = (region_z * structure.spacing + rng.range(0, structure.spacing)) * side + rng.range(0, side);
+        let rotation = rng.range(0, 4) as u8;
+        let mirrored = rng.range(0, 2) == 1;
+        let underground_roll = rng.next_f64();
+
+        let mut start = StructureStart {
+            name: structure.name.clone(),
+            pos: Coord { x, y: 0, z },
+            rotation,
+            mirrored,
+        };
+
+        // Look at the ground under the corners and middle of the footprint.
+        let mut ground = GroundSampler::new(terrain, self.seed);
+        let (min_x, min_z, width, depth) = structure.footprint(&start);
+        let samples = [
+            ground.height(min_x, min_z),
+            ground.height(min_x + width - 1, min_z),
+            ground.height(min_x, min_z + depth - 1),
+            ground.height(min_x + width - 1, min_z + depth - 1),
+            ground.height(min_x + width / 2, min_z + depth / 2),
+        ];
+        let lowest = *samples.iter().min().unwrap();
+        let highest = *samples.iter().max().unwrap();
+
+        start.pos.y = match structure.placement {
+            Placement::Surface => {
+                if highest - lowest > MAX_SLOPE || sea_level.map_or(false, |sea_level| lowest <= sea_level) {
+                    return None;
+                }
+                ground.height(x, z) + 1
+            }
+            Placement::Underground => {
+                let bottom = 1 + structure.origin.y;
+                let top = lowest - MIN_COVER - (structure.size.y - structure.origin.y);
+                if top < bottom {
+                    return None;
+                }
+                bottom + (underground_roll * (top - bottom + 1) as f64) as i32
+            }
+        };
+
+        if start.pos.y - structure.origin.y < 1 || start.pos.y - structure.origin.y + structure.size.y > WORLD_HEIGHT as i32 {
+            return None;
+        }
+        Some(start)
+    }
+}
+
+/// Finds ground heights from the raw output of the world generator, generating each chunk once.
+struct GroundSampler<'a> {
+    terrain: &'a WorldGenerator,
+    seed: u32,
+    chunks: FnvHashMap<ChunkCoord, Box<Chunk>>,
+}
+
+impl<'a> GroundSampler<'a> {
+    fn new(terrain: &'a WorldGenerator, seed: u32) -> GroundSampler<'a> {
+        GroundSampler {
+            terrain,
+            seed,
+            chunks: FnvHashMap::default(),
+        }
+    }
+
+    /// The y coordinate of the topmost block that isn't air or fluid, or 0 if there isn't one.
+    fn height(&mut self, x: i32, z: i32) -> i32 {
+        let coord = ChunkCoord::from_world_pos(Coord { x, y: 0, z });
+        let terrain = self.terrain;
+        let seed = self.seed;
+        let chunk = self.chunks.entry(coord).or_insert_with(|| terrain.generate(coord, seed));
+        let local_x = x - coord.x * CHUNK_SIDE_LENGTH as i32;
+        let local_z = z - coord.z * CHUNK_SIDE_LENGTH as i32;
+        (1..WORLD_HEIGHT as i32)
+            .rev()
+            .find(|&y| {
+                let block = chunk.get(Coord { x: local_x, y, z: local_z });
+                !block.is_air() && !block.is_fluid()
+            })
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use preset::WorldPreset;
+    use world_generator::{ GeneratorConfig, GeneratorRegistry };
+
+    fn template(placement: Placement) -> StructureTemplate {
+        let mut palette = BTreeMap::new();
+        palette.insert("#".to_string(), BlockType::Cobblestone);
+        palette.insert("L".to_string(), BlockType::Log);
+        StructureTemplate {
+            name: "test".to_string(),
+            placement,
+            spacing: 2,
+            chance: 1.0,
+            origin: [0, 0, 0],
+            layers: vec![
+                vec!["L##".to_string(), "#  ".to_string()],
+                vec!["#  ".to_string(), "   ".to_string()],
+            ],
+            palette,
+        }
+    }
+
+    #[test]
+    fn templates_are_checked() {
+        let mut bad = template(Placement::Surface);
+        bad.layers[1][0] = "#?".to_string();
+        assert!(Structure::from_template(&bad).is_err());
+
+        let mut bad = template(Placement::Surface);
+        bad.origin = [5, 0, 0];
+        assert!(Structure::from_template(&bad).is_err());
+
+        assert!(Structure::from_template(&template(Placement::Surface)).is_ok());
+    }
+
+    #[test]
+    fn orientations_stay_inside_the_footprint() {
+        let structure = Structure::from_template(&template(Placement::Surface)).unwrap();
+        for rotation in 0..4 {
+            for &mirrored in [false, true].iter() {
+                let (width, depth) = if rotation % 2 == 0 { (3, 2) } else { (2, 3) };
+                let mut seen = Vec::new();
+                for x in 0..3 {
+                    for z in 0..2 {
+                        let (ox, oz) = structure.orient(x, z, rotation, mirrored);
+                        assert!(0 <= ox && ox < width && 0 <= oz && oz < depth);
+                        assert!(!seen.contains(&(ox, oz)));
+                        seen.push((ox, oz));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Places the test structure over a few chunks, in the given order, and returns their bytes.
+    fn place_in_order(coords: &[ChunkCoord], placement: Placement) -> Vec<(ChunkCoord, Vec<u8>)> {
+        let preset = WorldPreset::default();
+        let terrain = GeneratorRegistry::new().create("noise", &GeneratorConfig { preset: &preset }).unwrap();
+        let structures = vec![Structure::from_template(&template(placement)).unwrap()];
+        let placer = StructurePlacer::new(21, structures);
+
+        let mut result: Vec<_> = coords.iter().map(|&coord| {
+            let mut chunk = terrain.generate(coord, 21);
+            placer.place(&*terrain, preset.sea_level, coord, &mut chunk);
+            (coord, chunk.to_bytes())
+        }).collect();
+        result.sort_by_key(|&(coord, _)| (coord.x, coord.z));
+        result
+    }
+
+    #[test]
+    fn placement_does_not_depend_on_generation_order() {
+        let mut coords = Vec::new();
+        for x in -3..3 {
+            for z in -3..3 {
+                coords.push(ChunkCoord::new(x, z));
+            }
+        }
+        for &placement in [Placement::Surface, Placement::Underground].iter() {
+            let forwards = place_in_order(&coords, placement);
+            coords.reverse();
+            let backwards = place_in_order(&coords, placement);
+            assert!(forwards == backwards);
+        }
+    }
+
+    #[test]
+    fn starts_are_recorded_in_their_chunk() {
+        let preset = WorldPreset::default();
+        let terrain = GeneratorRegistry::new().create("noise", &GeneratorConfig { preset: &preset }).unwrap();
+        let structures = vec![Structure::from_template(&template(Placement::Underground)).unwrap()];
+        let placer = StructurePlacer::new(4, structures);
+
+        let mut found = 0;
+        for x in -4..4 {
+            for z in -4..4 {
+                let coord = ChunkCoord::new(x, z);
+                for start in placer.starts_in(&*terrain, preset.sea_level, coord) {
+                    assert_eq!(ChunkCoord::from_world_pos(start.pos), coord);
+                    let mut chunk = terrain.generate(coord, 4);
+                    placer.place(&*terrain, preset.sea_level, coord, &mut chunk);
+                    let local = Coord::new(
+                        start.pos.x - x * CHUNK_SIDE_LENGTH as i32,
+                        start.pos.y,
+                        start.pos.z - z * CHUNK_SIDE_LENGTH as i32,
+                    );
+                    assert!(chunk.get(local).ty == BlockType::Log);
+                    found += 1;
+                }
+            }
+        }
+        // Every 2x2 region of chunks is given one with a chance of 1.
+        assert!(found > 0);
+    }
+}
@@ -30,6 +30,34 @@ pub struct Settings {
     pub reach_distance: f32,
     pub raycast_step_size: f32,
     pub raycast_max_distance: f32,
+    /// Only used when creating a new world, a random seed is picked if this is not set.
+    #[serde(default)]
+    pub world_seed: Option<u32>,
+    /// Only used when creating a new world, the name of a preset in `presets.toml`.
+    #[serde(default)]
+    pub world_preset: Option<String>,
+    /// Only used when creating a new world, either "creative" or "survival". Creative if not set.
+    #[serde(default)]
+    pub game_mode: Option<String>,
+    /// Only used when creating a new world, where its chunks are kept, either "sqlite" or
+    /// "region". SQLite if not set.
+    #[serde(default)]
+    pub chunk_store: Option<String>,
+    /// How many columns either side of the seam are reshaped where a chunk from an older
+    /// generator meets a new one, 0 turns blending off.
+    #[serde(default = "default_border_blend_width")]
+    pub border_blend_width: i32,
+    /// How many seconds apart changed chunks and the player are saved, 0 only saves on exit.
+    #[serde(default = "default_autosave_interval")]
+    pub autosave_interval: i32,
+}
+
+fn default_border_blend_width() -> i32 {
+    6
+}
+
+fn default_autosave_interval() -> i32 {
+    60
 }
 
 pub static mut SETTINGS_MUT: Settings = Settings {
@@ -41,6 +69,12 @@ pub static mut SETTINGS_MUT: Settings = Settings {
     reach_distance: 5.0,
     raycast_step_size: 0.01,
     raycast_max_distance: 5.0,
+    world_seed: None,
+    world_preset: None,
+    game_mode: None,
+    chunk_store: None,
+    border_blend_width: 6,
+    autosave_interval: 60,
 };
 
 pub struct SettingsWrapper;
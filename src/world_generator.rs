@@ -0,0 +1,166 @@
+use chunk::Chunk;
+use density_generator::DensityGenerator;
+use erosion::Eroder;
+use heightmap_generator::HeightmapGenerator;
+#[cfg(feature = "lua")]
+use lua_generator::LuaGenerator;
+use math::*;
+use noise_generator::NoiseGenerator;
+use preset::{ Layer, WorldPreset, fill_layers };
+
+/// How many blocks of dirt generators put under the surface block of a column.
+pub const DIRT_DEPTH: i32 = 3;
+
+/// Produces the terrain of a chunk. The later passes (ores, caves, decorations) are run on top
+/// by the chunk generator, so a generator only needs to worry about the shape of the land.
+///
+/// Generators must be pure functions of the chunk position and seed. Whenever a change would
+/// alter the output for an existing seed, bump `version` so the chunks it made can be told apart.
+pub trait WorldGenerator {
+    /// The name the generator is registered under.
+    fn name(&self) -> &'static str;
+    fn version(&self) -> u32;
+    fn generate(&self, coord: ChunkCoord, seed: u32) -> Box<Chunk>;
+}
+
+/// Records which generator produced a chunk, stored with the chunk in the save.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct GeneratorStamp {
+    pub name: String,
+    pub version: u32,
+}
+
+impl GeneratorStamp {
+    pub fn of(generator: &WorldGenerator) -> GeneratorStamp {
+        GeneratorStamp {
+            name: generator.name().to_string(),
+            version: generator.version(),
+        }
+    }
+}
+
+/// Everything a generator may be configured from.
+pub struct GeneratorConfig<'a> {
+    pub preset: &'a WorldPreset,
+}
+
+pub type GeneratorFactory = fn(&GeneratorRegistry, &GeneratorConfig) -> Box<WorldGenerator>;
+
+/// The named generators a world preset can pick from.
+pub struct GeneratorRegistry {
+    factories: Vec<(&'static str, u32, GeneratorFactory)>,
+}
+
+impl GeneratorRegistry {
+    /// A registry holding all of the built in generators.
+    pub fn new() -> GeneratorRegistry {
+        let mut registry = GeneratorRegistry {
+            factories: Vec::new(),
+        };
+        registry.register("density", DensityGenerator::VERSION, DensityGenerator::create);
+        registry.register("flat", FlatGenerator::VERSION, create_flat);
+        registry.register("heightmap", HeightmapGenerator::VERSION, HeightmapGenerator::create);
+        #[cfg(feature = "lua")]
+        registry.register("lua", LuaGenerator::VERSION, LuaGenerator::create);
+        registry.register("noise", NoiseGenerator::VERSION, create_noise);
+        registry.register("void", VoidGenerator::VERSION, create_void);
+        registry
+    }
+
+    /// Adds a generator, replacing any existing one with the same name. `version` must match the
+    /// version reported by the generators the factory makes.
+    pub fn register(&mut self, name: &'static str, version: u32, factory: GeneratorFactory) {
+        self.factories.retain(|&(n, _, _)| n != name);
+        self.factories.push((name, version, factory));
+    }
+
+    pub fn create(&self, name: &str, config: &GeneratorConfig) -> Option<Box<WorldGenerator>> {
+        self.factories.iter()
+            .find(|&&(n, _, _)| n == name)
+            .map(|&(_, _, factory)| factory(self, config))
+    }
+
+    pub fn names<'a>(&'a self) -> impl Iterator<Item=&'static str> + 'a {
+        self.factories.iter().map(|&(name, _, _)| name)
+    }
+
+    /// The version of the named generator that new chunks would be made with.
+    pub fn current_version(&self, name: &str) -> Option<u32> {
+        self.factories.iter()
+            .find(|&&(n, _, _)| n == name)
+            .map(|&(_, version, _)| version)
+    }
+
+    /// Whether a chunk with this stamp was made by an older version of its generator, or by a
+    /// generator that no longer exists. Chunks from before stamps were recorded count as outdated.
+    pub fn is_outdated(&self, stamp: Option<&GeneratorStamp>) -> bool {
+        match stamp {
+            Some(stamp) => match self.current_version(&stamp.name) {
+                Some(version) => stamp.version < version,
+                None => true,
+            },
+            None => true,
+        }
+    }
+}
+
+fn create_flat(_: &GeneratorRegistry, config: &GeneratorConfig) -> Box<WorldGenerator> {
+    Box::new(FlatGenerator { layers: config.preset.layers.clone() })
+}
+
+fn create_noise(_: &GeneratorRegistry, config: &GeneratorConfig) -> Box<WorldGenerator> {
+    Box::new(NoiseGenerator {
+        sea_level: config.preset.sea_level,
+        erosion: config.preset.erosion.clone().map(Eroder::new),
+    })
+}
+
+fn create_void(_: &GeneratorRegistry, _: &GeneratorConfig) -> Box<WorldGenerator> {
+    Box::new(VoidGenerator)
+}
+
+/// Horizontal layers stacked from the bottom of the world.
+pub struct FlatGenerator {
+    layers: Vec<Layer>,
+}
+
+impl FlatGenerator {
+    pub const VERSION: u32 = 1;
+}
+
+impl WorldGenerator for FlatGenerator {
+    fn name(&self) -> &'static str {
+        "flat"
+    }
+
+    fn version(&self) -> u32 {
+        Self::VERSION
+    }
+
+    fn generate(&self, coord: ChunkCoord, seed: u32) -> Box<Chunk> {
+        let mut chunk = Chunk::new();
+        fill_layers(&mut chunk, &self.layers);
+        chunk
+    }
+}
+
+/// Nothing but air.
+pub struct VoidGenerator;
+
+impl VoidGenerator {
+    pub const VERSION: u32 = 1;
+}
+
+impl WorldGenerator for VoidGenerator {
+    fn name(&self) -> &'static str {
+        "void"
+    }
+
+    fn version(&self) -> u32 {
+        Self::VERSION
+    }
+
+    fn generate(&self, coord: ChunkCoord, seed: u32) -> Box<Chunk> {
+        Chunk::new()
+    }
+}
@@ -0,0 +1,140 @@
+use block::BlockType;
+use chunk::{ CHUNK_SIDE_LENGTH, WORLD_HEIGHT };
+use chunk_generator::TerrainGenerator;
+use chunk_store::StoreKind;
+use math::*;
+
+/// How far from the origin, in chunks, safe ground is searched for when a world is created.
+const SPAWN_SEARCH_RADIUS: i32 = 6;
+
+/// Where players appear when there's no safe ground near the origin, like in a void world.
+const FALLBACK_SPAWN: Coord = Coord { x: 0, y: 45, z: 0 };
+
+/// The game mode the world was created with. It's only recorded and shown for now, play is
+/// the same in both.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GameMode {
+    Creative,
+    Survival,
+}
+
+impl GameMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            GameMode::Creative => "creative",
+            GameMode::Survival => "survival",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<GameMode> {
+        match name {
+            "creative" => Some(GameMode::Creative),
+            "survival" => Some(GameMode::Survival),
+            _ => None,
+        }
+    }
+}
+
+/// Everything about a world that isn't part of a chunk or the player.
+#[derive(Clone, PartialEq, Debug)]
+pub struct WorldMeta {
+    pub seed: u32,
+    /// The world generator the world was created with.
+    pub generator: String,
+    /// The block new players stand in. Only `None` until it's been chosen.
+    pub spawn: Option<Coord>,
+    /// How many ticks the world has run for.
+    pub time: u64,
+    pub game_mode: GameMode,
+    /// Where the world's chunks are kept.
+    pub storage: StoreKind,
+}
+
+/// Searches outwards from the origin for a column whose top block is ground that can be stood
+/// on, generating chunks as it goes. Returns the block above that ground.
+pub fn find_spawn(terrain: &TerrainGenerator) -> Coord {
+    let mut coords = Vec::new();
+    for x in -SPAWN_SEARCH_RADIUS..SPAWN_SEARCH_RADIUS + 1 {
+        for z in -SPAWN_SEARCH_RADIUS..SPAWN_SEARCH_RADIUS + 1 {
+            coords.push(ChunkCoord::new(x, z));
+        }
+    }
+    coords.sort_by_key(|coord| (coord.x * coord.x + coord.z * coord.z, coord.x, coord.z));
+
+    for coord in coords {
+        let (chunk, _) = terrain.generate_decorated(coord);
+        // Start from the middle of the chunk, so the spawn isn't right on an edge.
+        let mut columns = Vec::new();
+        for x in 0..CHUNK_SIDE_LENGTH as i32 {
+            for z in 0..CHUNK_SIDE_LENGTH as i32 {
+                columns.push((x, z));
+            }
+        }
+        let middle = CHUNK_SIDE_LENGTH as i32 / 2;
+        columns.sort_by_key(|&(x, z)| ((x - middle).abs() + (z - middle).abs(), x, z));
+
+        for (x, z) in columns {
+            let top = (0..WORLD_HEIGHT as i32 - 2).rev().find(|&y| !chunk.get(Coord::new(x, y, z)).is_air());
+            if let Some(y) = top {
+                if is_safe_ground(chunk.get(Coord::new(x, y, z)).ty) {
+                    return Coord::new(
+                        coord.x * CHUNK_SIDE_LENGTH as i32 + x,
+                        y + 1,
+                        coord.z * CHUNK_SIDE_LENGTH as i32 + z,
+                    );
+                }
+            }
+        }
+    }
+    warn!("Found no safe ground within {} chunks of the origin to spawn on.", SPAWN_SEARCH_RADIUS);
+    FALLBACK_SPAWN
+}
+
+/// Solid ground that's not in the sea or the treetops.
+fn is_safe_ground(ty: BlockType) -> bool {
+    use block::BlockType::*;
+    match ty {
+        Dirt | Grass | Stone | Sand | Gravel | Sandstone | Cobblestone => true,
+        Air | Wood | Log | Bedrock | GoldOre | IronOre | CoalOre | Leaf | Sponge | Water => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ores::OreConfig;
+    use preset::{ Layer, WorldPreset };
+
+    #[test]
+    fn spawn_is_on_the_ground() {
+        let preset = WorldPreset {
+            terrain: "flat".to_string(),
+            layers: vec![
+                Layer { block: BlockType::Bedrock, thickness: 1 },
+                Layer { block: BlockType::Grass, thickness: 20 },
+            ],
+            caves: false,
+            ores: false,
+            decorations: false,
+            structures: false,
+            sea_level: None,
+            ..WorldPreset::default()
+        };
+        let terrain = TerrainGenerator::new(3, preset, OreConfig::default(), Vec::new());
+        let spawn = find_spawn(&terrain);
+        assert_eq!(spawn.y, 21);
+        assert!(spawn.x.abs() < CHUNK_SIDE_LENGTH as i32 && spawn.z.abs() < CHUNK_SIDE_LENGTH as i32);
+    }
+
+    #[test]
+    fn void_worlds_fall_back() {
+        let preset = WorldPreset {
+            terrain: "void".to_string(),
+            structures: false,
+            sea_level: None,
+            ..WorldPreset::default()
+        };
+        let terrain = TerrainGenerator::new(3, preset, OreConfig::default(), Vec::new());
+        assert_eq!(find_spawn(&terrain), FALLBACK_SPAWN);
+    }
+}
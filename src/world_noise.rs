@@ -0,0 +1,60 @@
+use noise::{ NoiseModule, Perlin, Seedable };
+
+use random::derive_seed;
+
+/// Several octaves of Perlin noise summed together. Every octave gets its own seed derived from
+/// the world seed and `salt`, so two fields built from the same world seed don't line up.
+pub struct OctaveNoise {
+    octaves: Vec<Perlin>,
+    frequency: f64,
+    persistence: f64,
+    normaliser: f64,
+}
+
+impl OctaveNoise {
+    pub fn new(seed: u32, salt: u32, octave_count: usize, frequency: f64, persistence: f64) -> OctaveNoise {
+        let octaves = (0..octave_count)
+            .map(|i| Perlin::new().set_seed(derive_seed(seed, salt.wrapping_add(i as u32 * 7919))))
+            .collect();
+
+        let mut normaliser = 0.0;
+        let mut amplitude = 1.0;
+        for _ in 0..octave_count {
+            normaliser += amplitude;
+            amplitude *= persistence;
+        }
+
+        OctaveNoise {
+            octaves,
+            frequency,
+            persistence,
+            normaliser,
+        }
+    }
+
+    /// Samples the 2D field, the result is roughly in the range `-1.0..1.0`.
+    pub fn get2(&self, x: f64, z: f64) -> f64 {
+        let mut total = 0.0;
+        let mut frequency = self.frequency;
+        let mut amplitude = 1.0;
+        for octave in self.octaves.iter() {
+            total += octave.get([x * frequency, z * frequency]) * amplitude;
+            frequency *= 2.0;
+            amplitude *= self.persistence;
+        }
+        total / self.normaliser
+    }
+
+    /// Samples the 3D field, the result is roughly in the range `-1.0..1.0`.
+    pub fn get3(&self, x: f64, y: f64, z: f64) -> f64 {
+        let mut total = 0.0;
+        let mut frequency = self.frequency;
+        let mut amplitude = 1.0;
+        for octave in self.octaves.iter() {
+            total += octave.get([x * frequency, y * frequency, z * frequency]) * amplitude;
+            frequency *= 2.0;
+            amplitude *= self.persistence;
+        }
+        total / self.normaliser
+    }
+}